@@ -0,0 +1,85 @@
+#!/bin/sh
+# Runs a server and two clients, each client behind its own NAT, and checks that the clients
+# open a direct path to each other. Needs root, iproute2 and iptables.
+#
+#   client1 (10.1.0.2) -- nat1 (10.1.0.1 | 192.0.2.11) --+
+#                                                         +-- wan bridge -- server (192.0.2.1)
+#   client2 (10.2.0.2) -- nat2 (10.2.0.1 | 192.0.2.12) --+
+set -eu
+
+BIN=${BIN:-./target/debug/tunnel-cli}
+KEY=hole-punch-test
+LOGS=$(mktemp -d)
+PIDS=""
+
+cleanup() {
+    for pid in $PIDS; do kill "$pid" 2>/dev/null || true; done
+    for ns in hp-wan hp-server hp-nat1 hp-nat2 hp-client1 hp-client2; do
+        ip netns del "$ns" 2>/dev/null || true
+    done
+}
+trap cleanup EXIT
+
+run() {
+    ns=$1
+    shift
+    ip netns exec "$ns" "$@"
+}
+
+for ns in hp-wan hp-server hp-nat1 hp-nat2 hp-client1 hp-client2; do
+    ip netns add "$ns"
+    run "$ns" ip link set lo up
+done
+run hp-wan ip link add br0 type bridge
+run hp-wan ip link set br0 up
+
+# Connects two namespaces with a veth pair and gives the first end an address
+link() {
+    ns=$1 dev=$2 addr=$3 peer_ns=$4 peer_dev=$5
+    ip link add "$dev" netns "$ns" type veth peer name "$peer_dev" netns "$peer_ns"
+    run "$ns" ip addr add "$addr" dev "$dev"
+    run "$ns" ip link set "$dev" up
+    run "$peer_ns" ip link set "$peer_dev" up
+}
+
+link hp-server wan 192.0.2.1/24 hp-wan server
+run hp-wan ip link set server master br0
+for i in 1 2; do
+    link "hp-nat$i" wan "192.0.2.1$i/24" hp-wan "nat$i"
+    run hp-wan ip link set "nat$i" master br0
+    link "hp-client$i" lan "10.$i.0.2/24" "hp-nat$i" "client$i"
+    run "hp-nat$i" ip addr add "10.$i.0.1/24" dev "client$i"
+    run "hp-nat$i" sysctl -qw net.ipv4.ip_forward=1
+    run "hp-nat$i" iptables -t nat -A POSTROUTING -o wan -j MASQUERADE
+    run "hp-client$i" ip route add default via "10.$i.0.1"
+done
+
+# Starts the tunnel in a namespace and gives its device an address
+tunnel() {
+    ns=$1 addr=$2
+    shift 2
+    run "$ns" "$BIN" --name hptun --key "$KEY" --iterations 1000 --peer-to-peer "$@" \
+        >"$LOGS/$ns.log" 2>&1 &
+    PIDS="$PIDS $!"
+    sleep 1
+    run "$ns" ip addr add "$addr/24" dev hptun
+    run "$ns" ip link set hptun up
+}
+
+run hp-server sysctl -qw net.ipv4.ip_forward=1
+tunnel hp-server 10.0.0.1 --port 3456
+tunnel hp-client1 10.0.0.2 --client --address 192.0.2.1:3456 --keepalive 5
+tunnel hp-client2 10.0.0.3 --client --address 192.0.2.1:3456 --keepalive 5
+
+# Both clients must have sent something for the server to know their tunnel addresses
+run hp-client2 ping -c 1 -W 2 10.0.0.1 >/dev/null
+run hp-client1 ping -c 3 -W 2 10.0.0.3 >/dev/null
+sleep 3
+run hp-client1 ping -c 3 -W 2 10.0.0.3 >/dev/null
+
+if grep -q "Direct path is open" "$LOGS/hp-client1.log" "$LOGS/hp-client2.log"; then
+    echo "PASS: the clients talk to each other directly"
+else
+    echo "FAIL: no direct path, logs are in $LOGS"
+    exit 1
+fi
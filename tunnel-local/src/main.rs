@@ -1,13 +1,14 @@
 use std::env;
-use std::os::unix::io::AsRawFd;
 use std::process::Command;
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 
 use libc::{c_int, c_void, sighandler_t, signal, SIGINT};
-use tunnel::net::Net;
-use tunnel::packet;
-use tunnel::select::{select, FdSet};
+use tunnel::net::{Config, Encapsulation, PeerConfig, PeerLimitPolicy, Transport};
+use tunnel::padding::Padding;
+use tunnel::tcp::Proxy;
 use tunnel::tun::TunSocket;
+use tunnel::{cli, crypto};
 
 static RUNNING: AtomicBool = AtomicBool::new(false);
 
@@ -38,50 +39,52 @@ impl Drop for Cleanup {
     }
 }
 
-extern fn handler(_: c_int) {
+extern "C" fn handler(_: c_int) {
     RUNNING.store(false, Ordering::SeqCst);
 }
 
 fn get_handler() ->sighandler_t {
-    handler as extern fn(c_int) as *mut c_void as sighandler_t
+    handler as extern "C" fn(c_int) as *mut c_void as sighandler_t
 }
 pub fn main() {
     let args: Vec<String> = env::args().collect();
-    let (name, remote_addr, local_ip, key, is_client, port, host_port) = parse_args(args);
-    if local_ip == "" {
+    if args.iter().any(|arg| arg == "--genkey") {
+        cli::generate_keys();
+        return;
+    }
+    let (name, local_ip, host_port, config) = parse_args(&args);
+    if local_ip.is_empty() {
         panic!("You must supply a tun dev ip address");
     }
-    if is_client && remote_addr == "" {
+    if config.is_client && config.remote_addr.is_empty() {
         panic!("You must supply a server ip and port number for a client");
     }
-    if key.len() > 32 {
-        panic!("Password length must be less than or equal to 32");
-    }
 
-    let mut net = Net::new(&remote_addr, port, is_client, key).unwrap();
+    let is_client = config.is_client;
+    let net = cli::open_net(&config).unwrap();
     let tunnel = TunSocket::new(&name).unwrap();
     setup_link_dev(&name, &local_ip, host_port, is_client);
-    let local_ip = parse_ip(local_ip);
-    if is_client {
-        client_handshake(&mut net, &local_ip)
-    }
     let _cleanup = Cleanup { is_client, host_port, name };
     unsafe { signal(SIGINT, get_handler()); }
-    run(net, tunnel, is_client);
+    RUNNING.store(true, Ordering::SeqCst);
+    cli::run(net, tunnel, || RUNNING.load(Ordering::Relaxed)).unwrap();
 }
 
-fn parse_args(args: Vec<String>) -> (String, String, String, String, bool, u16, u16) {
+fn parse_args(args: &[String]) -> (String, String, u16, Config) {
     let mut name = String::from("playtun");
-    let mut remote_addr = String::from("");
-    let mut key = String::from("");
     let mut local_ip = String::from("");
-    let mut is_client = false;
-    let mut port = 2000;
     let mut host_port = 8080;
+    let mut config = Config::default();
     let mut i = 1;
     while i < args.len() {
         if args[i] == "--client" || args[i] == "--c" {
-            is_client = true;
+            config.is_client = true;
+            i += 1;
+            continue;
+        }
+
+        if args[i] == "--peer-to-peer" {
+            config.peer_to_peer = true;
             i += 1;
             continue;
         }
@@ -91,15 +94,124 @@ fn parse_args(args: Vec<String>) -> (String, String, String, String, bool, u16,
         }
 
         if (args[i] == "--address" || args[i] == "-a") && i + 1 < args.len() {
-            remote_addr = args[i + 1].clone();
+            config.remote_addr = args[i + 1].clone();
         }
 
         if (args[i] == "--port" || args[i] == "-p") && i + 1 < args.len() {
-            port = args[i + 1].parse().unwrap();
+            config.port = args[i + 1].parse().unwrap();
+        }
+
+        if args[i] == "--transport" && i + 1 < args.len() {
+            match Transport::from_name(&args[i + 1]) {
+                Some(transport) => config.transport = transport,
+                None => panic!("Transport must be udp, tcp or websocket"),
+            }
+        }
+
+        if args[i] == "--websocket-path" && i + 1 < args.len() {
+            config.websocket_path = args[i + 1].clone();
+        }
+
+        if args[i] == "--proxy" && i + 1 < args.len() {
+            match args[i + 1].parse::<Proxy>() {
+                Ok(proxy) => config.proxy = Some(proxy),
+                Err(_) => panic!("Proxies must look like user:password@192.0.2.1:3128"),
+            }
+        }
+
+        if args[i] == "--listen" && i + 1 < args.len() {
+            match args[i + 1].parse() {
+                Ok(addr) => config.listen_addrs.push(addr),
+                Err(_) => panic!("Listen addresses must look like 0.0.0.0:2000 or [::]:2000"),
+            }
         }
 
         if (args[i] == "--key" || args[i] == "-k") && i + 1 < args.len() {
-            key = args[i + 1].clone();
+            config.key = args[i + 1].clone();
+        }
+
+        if args[i] == "--salt" && i + 1 < args.len() {
+            config.salt = args[i + 1].clone();
+        }
+
+        if args[i] == "--iterations" && i + 1 < args.len() {
+            config.iterations = args[i + 1].parse().unwrap();
+        }
+
+        if args[i] == "--private-key" && i + 1 < args.len() {
+            config.private_key = Some(parse_key(&args[i + 1]));
+        }
+
+        if args[i] == "--peer-key" && i + 1 < args.len() {
+            config.peer_key = Some(parse_key(&args[i + 1]));
+        }
+
+        if args[i] == "--allow" && i + 1 < args.len() {
+            config.peers.push(parse_peer(&args[i + 1]));
+        }
+
+        if args[i] == "--rekey-packets" && i + 1 < args.len() {
+            config.rekey_after_packets = args[i + 1].parse().unwrap();
+        }
+
+        if args[i] == "--rekey-seconds" && i + 1 < args.len() {
+            config.rekey_after_time = Duration::from_secs(args[i + 1].parse().unwrap());
+        }
+
+        if args[i] == "--handshake-load" && i + 1 < args.len() {
+            config.handshake_load_threshold = args[i + 1].parse().unwrap();
+        }
+
+        if args[i] == "--handshake-rate" && i + 1 < args.len() {
+            config.handshake_rate_limit = args[i + 1].parse().unwrap();
+        }
+
+        if args[i] == "--idle-timeout" && i + 1 < args.len() {
+            config.idle_timeout = Duration::from_secs(args[i + 1].parse().unwrap());
+        }
+
+        if args[i] == "--max-peers" && i + 1 < args.len() {
+            config.max_peers = args[i + 1].parse().unwrap();
+        }
+
+        if args[i] == "--when-full" && i + 1 < args.len() {
+            match PeerLimitPolicy::from_name(&args[i + 1]) {
+                Some(policy) => config.peer_limit_policy = policy,
+                None => panic!("--when-full must be refuse or evict"),
+            }
+        }
+
+        if args[i] == "--keepalive" && i + 1 < args.len() {
+            config.keepalive_interval = Duration::from_secs(args[i + 1].parse().unwrap());
+        }
+
+        if args[i] == "--cipher" && i + 1 < args.len() {
+            match crypto::CipherSuite::from_name(&args[i + 1]) {
+                Some(suite) => config.cipher_suites = vec![suite],
+                None => panic!("Unknown cipher suite {}", args[i + 1]),
+            }
+        }
+
+        if args[i] == "--encapsulation" && i + 1 < args.len() {
+            match Encapsulation::from_name(&args[i + 1]) {
+                Some(encapsulation) => config.encapsulation = encapsulation,
+                None => panic!("Encapsulation must be header or full"),
+            }
+        }
+
+        if args[i] == "--padding" && i + 1 < args.len() {
+            match args[i + 1].parse::<Padding>() {
+                Ok(padding) => config.padding = padding,
+                Err(_) => panic!("Padding must be none, mtu, multiple:<bytes> or random:<bytes>"),
+            }
+        }
+
+        if args[i] == "--cover-rate" && i + 1 < args.len() {
+            config.cover_rate = args[i + 1].parse().unwrap();
+        }
+
+        if args[i] == "--path-mtu" && i + 1 < args.len() {
+            config.path_mtu = args[i + 1].parse().unwrap();
         }
 
         if (args[i] == "--local" || args[i] == "-l") && i + 1 < args.len() {
@@ -112,7 +224,7 @@ fn parse_args(args: Vec<String>) -> (String, String, String, String, bool, u16,
 
         i += 2;
     }
-    return (name, remote_addr, local_ip, key, is_client, port, host_port);
+    (name, local_ip, host_port, config)
 }
 
 fn setup_link_dev(name: &str, ip_addr: &str, host_port: u16, is_client: bool) {
@@ -135,61 +247,23 @@ fn setup_link_dev(name: &str, ip_addr: &str, host_port: u16, is_client: bool) {
 
 }
 
-fn parse_ip(ip: String) -> Vec<u8> {
-    let mut iter = ip.split('.');
-    let mut ip = vec![];
-    while let Some(c) = iter.next() {
-        ip.push(c.parse::<u8>().unwrap());
-    }
-    ip
-}
-
-fn client_handshake(net: &mut Net, ip: &[u8]) {
-    let hello_packet = packet::create_handshake_packet(&ip[..4].try_into().unwrap());
-    let mut dst: [u8; 4096] = [0; 4096];
-    for i in 0..hello_packet.len() {
-        dst[i] = hello_packet[i];
+/// Parses a peer given as `<public key>=<prefix>,<prefix>...`.
+fn parse_peer(peer: &str) -> PeerConfig {
+    let (key, allowed_ips) = peer.split_once('=').unwrap_or((peer, ""));
+    let allowed_ips = allowed_ips
+        .split(',')
+        .filter(|cidr| !cidr.is_empty())
+        .map(|cidr| cidr.parse().unwrap_or_else(|e| panic!("{e}")))
+        .collect();
+    PeerConfig {
+        public_key: parse_key(key),
+        allowed_ips,
     }
-    let amt = net.send(&mut dst, hello_packet.len());
-    println!("HANDSHAKE: Written {amt} to network");
 }
 
-fn run(mut net: Net, tunnel: TunSocket, is_client: bool) {
-    let mut tun2net = 0;
-    let mut net2tun = 0;
-    RUNNING.store(true, Ordering::SeqCst);
-    while RUNNING.load(Ordering::Relaxed) == true {
-        let mut fdset = FdSet::new();
-        let net_fd = net.as_raw_fd();
-        let tun_fd = tunnel.as_raw_fd();
-        fdset.set(net_fd);
-        fdset.set(tun_fd);
-        let max_fd = net_fd.max(tun_fd);
-        let mut dst: [u8; 4096] = [0; 4096];
-        match select(max_fd + 1, Some(&mut fdset), None, None, None) {
-            Ok(res) => {
-                println!("select result: {res}");
-                if fdset.is_set(net_fd) {
-                    net2tun += 1;
-                    let (buf, amt) = net.recv().unwrap();
-                    println!("NET2TUN {net2tun}: Read {amt} from network");
-                    if is_client || !packet::is_handshake_packet(buf.as_slice()) {
-                        let amt = tunnel.write(buf.as_slice());
-                        println!("NET2TUN {net2tun}: Written {amt} to tunnel");
-                    }
-                }
-
-                if fdset.is_set(tun_fd) {
-                    tun2net += 1;
-                    let amt = tunnel.read(&mut dst).unwrap();
-                    println!("TUN2NET {tun2net}: Read {amt} from tunnel");
-                    let amt = net.send(&mut dst, amt);
-                    println!("TUN2NET {tun2net}: Written {amt} to network");
-                }
-            }
-            Err(err) => {
-                println!("Failed to select {:?}", err);
-            }
-        }
+fn parse_key(key: &str) -> [u8; 32] {
+    match crypto::from_hex(key) {
+        Some(key) => key,
+        None => panic!("Keys must be 64 hex characters"),
     }
 }
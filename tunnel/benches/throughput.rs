@@ -0,0 +1,166 @@
+//! Throughput of packets sealed by a client and opened by its server over loopback, a batch at a
+//! time, with keys prepared once per session and with their schedule rebuilt for every packet,
+//! and how many short packets a second get through one at a time and in batches. Run with
+//! `cargo bench`.
+
+use std::hint::black_box;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use etherparse::PacketBuilder;
+use ring::aead::{LessSafeKey, UnboundKey};
+use tunnel::crypto::{CipherSuite, KEY_LEN};
+use tunnel::handshake::{self, Identity};
+use tunnel::net::{self, Config, Encapsulation, Net};
+use tunnel::pool::{BufferPool, PacketBuf};
+
+/// Length of the IP packets sent, close to what a tun device with the default MTU reads.
+const PACKET_LEN: usize = 1400;
+/// Length of the packets counted a second, where the cost per packet rather than per byte shows.
+const SHORT_PACKET_LEN: usize = 100;
+
+/// Connects a client to a server on loopback and completes their handshake. It's received one
+/// datagram at a time, so that neither peer has GRO on until it first receives a batch.
+fn connect(suite: CipherSuite, encapsulation: Encapsulation) -> (Net, Net) {
+    let private_key = handshake::generate_private_key();
+    // The server binds the port this socket got once it's free again
+    let port = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let server_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+    let config = |is_client| Config {
+        is_client,
+        key: "password".to_owned(),
+        iterations: 1,
+        cipher_suites: vec![suite],
+        encapsulation,
+        ..Default::default()
+    };
+    let mut server = Net::new(&Config {
+        private_key: Some(private_key),
+        listen_addrs: vec![server_addr],
+        ..config(false)
+    })
+    .unwrap();
+    let mut client = Net::new(&Config {
+        remote_addr: server_addr.to_string(),
+        peer_key: Some(*Identity::from_private_key(&private_key).public_key()),
+        ..config(true)
+    })
+    .unwrap();
+    assert!(server.recv().unwrap().0.is_empty());
+    assert!(client.recv().unwrap().0.is_empty());
+    (client, server)
+}
+
+fn ipv4_packet(len: usize) -> Vec<u8> {
+    let builder = PacketBuilder::ipv4([10, 0, 0, 2], [10, 0, 0, 1], 64).udp(1000, 2000);
+    let mut packet = Vec::with_capacity(len);
+    let payload = vec![0xab; len - builder.size(0)];
+    builder.write(&mut packet, &payload).unwrap();
+    packet
+}
+
+/// Builds the key schedules that sealing and then opening a packet built before keys were
+/// prepared once per session.
+fn rebuild_keys(suite: CipherSuite) {
+    for _ in 0..2 {
+        let key = UnboundKey::new(suite.algorithm(), &[7; KEY_LEN]).unwrap();
+        black_box(LessSafeKey::new(key));
+    }
+}
+
+fn sealed_batches(c: &mut Criterion) {
+    let packet = ipv4_packet(PACKET_LEN);
+    let mut group = c.benchmark_group("batch");
+    group.throughput(Throughput::Bytes((net::BATCH_SIZE * PACKET_LEN) as u64));
+    for suite in CipherSuite::ALL {
+        for encapsulation in [Encapsulation::Header, Encapsulation::Full] {
+            for rebuild in [false, true] {
+                let (mut client, mut server) = connect(suite, encapsulation);
+                let mut pool = BufferPool::new(net::BATCH_SIZE, tunnel::pool::CAPACITY);
+                let mut batch: Vec<PacketBuf> = Vec::with_capacity(net::BATCH_SIZE);
+                let keys = match rebuild {
+                    true => "keys per packet",
+                    false => "prepared keys",
+                };
+                let id = BenchmarkId::new(format!("{suite:?}/{encapsulation:?}"), keys);
+                group.bench_function(id, |b| {
+                    b.iter(|| {
+                        for _ in 0..net::BATCH_SIZE {
+                            if rebuild {
+                                rebuild_keys(suite);
+                            }
+                            let mut buf = pool.take();
+                            buf.room_mut()[..packet.len()].copy_from_slice(&packet);
+                            buf.set_len(packet.len());
+                            batch.push(buf);
+                        }
+                        client.send_batch(&mut batch);
+                        pool.give_all(batch.drain(..));
+                        let mut received = 0;
+                        while received < net::BATCH_SIZE {
+                            server
+                                .recv_batch(|result| {
+                                    assert_eq!(result.unwrap().len(), PACKET_LEN);
+                                    received += 1;
+                                })
+                                .unwrap();
+                        }
+                    })
+                });
+            }
+        }
+    }
+    group.finish();
+}
+
+/// Compares sending and receiving a packet per call, as the binaries did before batching, with a
+/// batch of them per call. Each has peers of its own, since a server that received a batch can't
+/// receive one datagram at a time anymore.
+fn packets_per_second(c: &mut Criterion) {
+    let packet = ipv4_packet(SHORT_PACKET_LEN);
+    let mut group = c.benchmark_group("packets");
+    group.throughput(Throughput::Elements(net::BATCH_SIZE as u64));
+    let (mut client, mut server) = connect(CipherSuite::Aes256Gcm, Encapsulation::Header);
+    let mut buf = vec![0; tunnel::pool::CAPACITY];
+    group.bench_function("one at a time", |b| {
+        b.iter(|| {
+            for _ in 0..net::BATCH_SIZE {
+                buf[..packet.len()].copy_from_slice(&packet);
+                client.send(&mut buf, packet.len());
+                while server.recv().unwrap().0.len() != SHORT_PACKET_LEN {}
+            }
+        })
+    });
+    let (mut client, mut server) = connect(CipherSuite::Aes256Gcm, Encapsulation::Header);
+    let mut pool = BufferPool::new(net::BATCH_SIZE, tunnel::pool::CAPACITY);
+    let mut batch: Vec<PacketBuf> = Vec::with_capacity(net::BATCH_SIZE);
+    group.bench_function("batched", |b| {
+        b.iter(|| {
+            for _ in 0..net::BATCH_SIZE {
+                let mut buf = pool.take();
+                buf.room_mut()[..packet.len()].copy_from_slice(&packet);
+                buf.set_len(packet.len());
+                batch.push(buf);
+            }
+            client.send_batch(&mut batch);
+            pool.give_all(batch.drain(..));
+            let mut received = 0;
+            while received < net::BATCH_SIZE {
+                server
+                    .recv_batch(|result| {
+                        assert_eq!(result.unwrap().len(), SHORT_PACKET_LEN);
+                        received += 1;
+                    })
+                    .unwrap();
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, sealed_batches, packets_per_second);
+criterion_main!(benches);
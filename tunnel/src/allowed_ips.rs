@@ -0,0 +1,253 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+use crate::tunerror;
+
+/// An IP prefix such as `10.0.0.0/24` or `fd00::/64`. The address is kept with its host bits
+/// cleared.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    /// Returns the prefix of the given length containing `addr`, or `None` if the length is
+    /// longer than the address.
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Option<Cidr> {
+        let addr = match addr {
+            IpAddr::V4(v4) if prefix_len <= 32 => {
+                Ipv4Addr::from(mask(u32::from(v4).into(), prefix_len, 32) as u32).into()
+            }
+            IpAddr::V6(v6) if prefix_len <= 128 => {
+                Ipv6Addr::from(mask(v6.into(), prefix_len, 128)).into()
+            }
+            _ => return None,
+        };
+        Some(Cidr { addr, prefix_len })
+    }
+
+    /// Returns the prefix holding only this address.
+    pub fn host(addr: IpAddr) -> Cidr {
+        let prefix_len = if addr.is_ipv4() { 32 } else { 128 };
+        Cidr { addr, prefix_len }
+    }
+
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        Cidr::new(addr, self.prefix_len).is_some_and(|cidr| cidr == *self)
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = tunerror::Error;
+
+    /// Parses `address/length`. An address without a length is a single host.
+    fn from_str(s: &str) -> Result<Cidr, tunerror::Error> {
+        let invalid = || tunerror::Error::Message(format!("invalid CIDR prefix {s}"));
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len.parse().map_err(|_| invalid())?)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        match prefix_len {
+            Some(prefix_len) => Cidr::new(addr, prefix_len).ok_or_else(invalid),
+            None => Ok(Cidr::host(addr)),
+        }
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// Clears the bits of a `width` bit address after its first `prefix_len` bits.
+fn mask(bits: u128, prefix_len: u8, width: u8) -> u128 {
+    if prefix_len == 0 {
+        return 0;
+    }
+    bits & (u128::MAX << (width - prefix_len))
+}
+
+/// Maps IP prefixes to values, looked up by longest prefix match. Prefixes are grouped by
+/// length, so a lookup costs one hash lookup per prefix length in use.
+pub struct AllowedIps<T> {
+    v4: BTreeMap<u8, HashMap<u32, T>>,
+    v6: BTreeMap<u8, HashMap<u128, T>>,
+}
+
+impl<T> Default for AllowedIps<T> {
+    fn default() -> Self {
+        AllowedIps::new()
+    }
+}
+
+impl<T> AllowedIps<T> {
+    pub fn new() -> AllowedIps<T> {
+        AllowedIps {
+            v4: BTreeMap::new(),
+            v6: BTreeMap::new(),
+        }
+    }
+
+    /// Maps a prefix to a value, replacing the value it had.
+    pub fn insert(&mut self, cidr: Cidr, value: T) -> Option<T> {
+        match cidr.addr {
+            IpAddr::V4(v4) => self
+                .v4
+                .entry(cidr.prefix_len)
+                .or_default()
+                .insert(v4.into(), value),
+            IpAddr::V6(v6) => self
+                .v6
+                .entry(cidr.prefix_len)
+                .or_default()
+                .insert(v6.into(), value),
+        }
+    }
+
+    /// Returns the value of the longest prefix containing `addr`.
+    pub fn lookup(&self, addr: IpAddr) -> Option<&T> {
+        match addr {
+            IpAddr::V4(v4) => self.v4.iter().rev().find_map(|(prefix_len, prefixes)| {
+                prefixes.get(&(mask(u32::from(v4).into(), *prefix_len, 32) as u32))
+            }),
+            IpAddr::V6(v6) => self.v6.iter().rev().find_map(|(prefix_len, prefixes)| {
+                prefixes.get(&mask(v6.into(), *prefix_len, 128))
+            }),
+        }
+    }
+
+    /// Removes every prefix whose value doesn't satisfy `keep`.
+    pub fn retain(&mut self, mut keep: impl FnMut(&T) -> bool) {
+        for prefixes in self.v4.values_mut() {
+            prefixes.retain(|_, value| keep(value));
+        }
+        for prefixes in self.v6.values_mut() {
+            prefixes.retain(|_, value| keep(value));
+        }
+        self.v4.retain(|_, prefixes| !prefixes.is_empty());
+        self.v6.retain(|_, prefixes| !prefixes.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn prefixes_are_parsed_and_masked() {
+        assert_eq!(cidr("10.1.2.3/8").to_string(), "10.0.0.0/8");
+        assert_eq!(cidr("10.1.2.3").to_string(), "10.1.2.3/32");
+        assert_eq!(cidr("fd00::1/64").to_string(), "fd00::/64");
+        assert_eq!(cidr("fd00::1").to_string(), "fd00::1/128");
+        assert_eq!(cidr("10.1.2.3/0").to_string(), "0.0.0.0/0");
+        assert_eq!(cidr("::1/0").to_string(), "::/0");
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("fd00::/129".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/".parse::<Cidr>().is_err());
+        assert!("not an address/8".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn prefixes_contain_their_addresses() {
+        assert!(cidr("10.0.0.0/8").contains(ip("10.255.255.255")));
+        assert!(!cidr("10.0.0.0/8").contains(ip("11.0.0.0")));
+        assert!(cidr("0.0.0.0/0").contains(ip("192.0.2.1")));
+        assert!(!cidr("0.0.0.0/0").contains(ip("::1")));
+        assert!(cidr("10.0.0.1/32").contains(ip("10.0.0.1")));
+        assert!(!cidr("10.0.0.1/32").contains(ip("10.0.0.2")));
+        assert!(cidr("fd00::/64").contains(ip("fd00::ffff")));
+        assert!(!cidr("fd00::/64").contains(ip("fd00:0:0:1::")));
+    }
+
+    #[test]
+    fn lookups_match_the_longest_prefix() {
+        let mut allowed_ips = AllowedIps::new();
+        allowed_ips.insert(cidr("10.0.0.0/8"), 8);
+        allowed_ips.insert(cidr("10.1.0.0/16"), 16);
+        allowed_ips.insert(cidr("10.1.2.0/24"), 24);
+        assert_eq!(allowed_ips.lookup(ip("10.1.2.3")), Some(&24));
+        assert_eq!(allowed_ips.lookup(ip("10.1.3.3")), Some(&16));
+        assert_eq!(allowed_ips.lookup(ip("10.2.0.1")), Some(&8));
+        assert_eq!(allowed_ips.lookup(ip("11.0.0.1")), None);
+    }
+
+    #[test]
+    fn host_prefixes_only_match_their_address() {
+        let mut allowed_ips = AllowedIps::new();
+        allowed_ips.insert(cidr("10.0.0.0/24"), "network");
+        allowed_ips.insert(Cidr::host(ip("10.0.0.2")), "host");
+        assert_eq!(allowed_ips.lookup(ip("10.0.0.2")), Some(&"host"));
+        assert_eq!(allowed_ips.lookup(ip("10.0.0.3")), Some(&"network"));
+        assert_eq!(allowed_ips.lookup(ip("10.0.0.255")), Some(&"network"));
+        assert_eq!(allowed_ips.lookup(ip("10.0.1.0")), None);
+    }
+
+    #[test]
+    fn default_routes_match_everything_of_their_family() {
+        let mut allowed_ips = AllowedIps::new();
+        allowed_ips.insert(cidr("0.0.0.0/0"), 4);
+        assert_eq!(allowed_ips.lookup(ip("255.255.255.255")), Some(&4));
+        assert_eq!(allowed_ips.lookup(ip("0.0.0.0")), Some(&4));
+        assert_eq!(allowed_ips.lookup(ip("::")), None);
+        allowed_ips.insert(cidr("::/0"), 6);
+        assert_eq!(allowed_ips.lookup(ip("ffff::1")), Some(&6));
+        assert_eq!(allowed_ips.lookup(ip("1.2.3.4")), Some(&4));
+    }
+
+    #[test]
+    fn v4_and_v6_prefixes_are_kept_apart() {
+        let mut allowed_ips = AllowedIps::new();
+        // ::a00:0/104 has the same low bits as 10.0.0.0/8
+        allowed_ips.insert(cidr("::a00:0/104"), 6);
+        assert_eq!(allowed_ips.lookup(ip("10.0.0.1")), None);
+        allowed_ips.insert(cidr("10.0.0.0/8"), 4);
+        assert_eq!(allowed_ips.lookup(ip("10.0.0.1")), Some(&4));
+        assert_eq!(allowed_ips.lookup(ip("::a00:1")), Some(&6));
+        assert_eq!(allowed_ips.lookup(ip("fd00::1")), None);
+    }
+
+    #[test]
+    fn v6_lookups_match_the_longest_prefix() {
+        let mut allowed_ips = AllowedIps::new();
+        allowed_ips.insert(cidr("fd00::/16"), 16);
+        allowed_ips.insert(cidr("fd00:1::/32"), 32);
+        allowed_ips.insert(Cidr::host(ip("fd00:1::1")), 128);
+        assert_eq!(allowed_ips.lookup(ip("fd00:1::1")), Some(&128));
+        assert_eq!(allowed_ips.lookup(ip("fd00:1::2")), Some(&32));
+        assert_eq!(allowed_ips.lookup(ip("fd00:2::1")), Some(&16));
+        assert_eq!(allowed_ips.lookup(ip("fd01::1")), None);
+    }
+
+    #[test]
+    fn inserts_replace_and_retain_removes() {
+        let mut allowed_ips = AllowedIps::new();
+        assert_eq!(allowed_ips.insert(cidr("10.0.0.0/24"), 1), None);
+        assert_eq!(allowed_ips.insert(cidr("10.0.0.7/24"), 2), Some(1));
+        allowed_ips.insert(cidr("10.0.0.1/32"), 3);
+        allowed_ips.insert(cidr("fd00::/64"), 3);
+        allowed_ips.retain(|value| *value != 3);
+        assert_eq!(allowed_ips.lookup(ip("10.0.0.1")), Some(&2));
+        assert_eq!(allowed_ips.lookup(ip("fd00::1")), None);
+    }
+}
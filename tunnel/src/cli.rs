@@ -0,0 +1,129 @@
+use std::io;
+use std::os::unix::io::AsRawFd;
+
+use crate::net::{self, Config, Event, Net};
+use crate::pool::{self, BufferPool, PacketBuf};
+use crate::select::{select, to_timeval, FdSet};
+use crate::tun::TunSocket;
+use crate::tunerror::Error;
+use crate::{crypto, handshake};
+
+/// Sets up the tunnel's side of the network, and prints the public key a server made up so
+/// that clients can be given it.
+pub fn open_net(config: &Config) -> Result<Net, Error> {
+    let net = Net::new(config)?;
+    // Clients need this key to reach a server with a random one
+    if let (None, Some(public_key)) = (config.private_key, net.public_key()) {
+        println!("public key: {}", crypto::to_hex(public_key));
+    }
+    Ok(net)
+}
+
+fn print_event(event: Event) {
+    match event {
+        Event::EndpointChanged {
+            public_key,
+            old: Some(old),
+            new,
+        } => println!(
+            "PEER {}: Moved from {old} to {new}",
+            crypto::to_hex(&public_key)
+        ),
+        Event::EndpointChanged {
+            public_key,
+            old: None,
+            new,
+        } => println!("PEER {}: Connected from {new}", crypto::to_hex(&public_key)),
+    }
+}
+
+/// Prints a new private key and its public key.
+pub fn generate_keys() {
+    let private_key = handshake::generate_private_key();
+    let identity = handshake::Identity::from_private_key(&private_key);
+    println!("private key: {}", crypto::to_hex(&private_key));
+    println!("public key: {}", crypto::to_hex(identity.public_key()));
+}
+
+/// Moves packets between the tun device and the network for as long as `running` says so.
+/// Fails only if the tun device can't be set up for it.
+pub fn run(mut net: Net, tunnel: TunSocket, running: impl Fn() -> bool) -> Result<(), Error> {
+    tunnel.set_nonblocking()?;
+    let mut pool = BufferPool::new(net::BATCH_SIZE, pool::CAPACITY);
+    let mut batch = Vec::with_capacity(net::BATCH_SIZE);
+    // Datagrams from the network that couldn't be opened, such as forged or truncated ones
+    let mut dropped: u64 = 0;
+    while running() {
+        let mut fdset = FdSet::new();
+        let net_fd = net.as_raw_fd();
+        let tun_fd = tunnel.as_raw_fd();
+        fdset.set(net_fd);
+        fdset.set(tun_fd);
+        let max_fd = net_fd.max(tun_fd);
+        let timeout = to_timeval(net.tick_timeout());
+        match select(max_fd + 1, Some(&mut fdset), None, None, Some(&timeout)) {
+            Ok(_) => {
+                if fdset.is_set(net_fd) {
+                    let received = net.recv_batch(|result| match result {
+                        Ok(packet) if !packet.is_empty() => {
+                            tunnel.write(packet);
+                        }
+                        Ok(_) => {}
+                        Err(err) => {
+                            dropped += 1;
+                            println!("NET2TUN: Dropped packet ({dropped} so far): {err}");
+                        }
+                    });
+                    if let Err(err) = received {
+                        println!("NET2TUN: {err}");
+                    }
+                    while let Some(event) = net.next_event() {
+                        print_event(event);
+                    }
+                }
+
+                if fdset.is_set(tun_fd) {
+                    drain_tunnel(&mut net, &tunnel, &mut pool, &mut batch);
+                }
+            }
+            Err(err) => {
+                println!("Failed to select {:?}", err);
+            }
+        }
+        net.tick();
+    }
+    Ok(())
+}
+
+/// Reads packets from the tunnel until it's empty, and sends them a batch at a time. The
+/// buffers they're read into come from the pool and go back to it once sent.
+fn drain_tunnel(
+    net: &mut Net,
+    tunnel: &TunSocket,
+    pool: &mut BufferPool,
+    batch: &mut Vec<PacketBuf>,
+) {
+    loop {
+        while batch.len() < net::BATCH_SIZE {
+            let mut buf = pool.take();
+            match tunnel.read_buf(&mut buf) {
+                Ok(()) => batch.push(buf),
+                Err(Error::IfaceRead(err)) if err.kind() == io::ErrorKind::WouldBlock => {
+                    pool.give(buf);
+                    break;
+                }
+                Err(err) => {
+                    println!("TUN2NET: {err}");
+                    pool.give(buf);
+                    break;
+                }
+            }
+        }
+        let full = batch.len() == net::BATCH_SIZE;
+        net.send_batch(batch);
+        pool.give_all(batch.drain(..));
+        if !full {
+            return;
+        }
+    }
+}
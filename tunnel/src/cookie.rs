@@ -0,0 +1,416 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::crypto::{KEY_LEN, TAG_LEN};
+use crate::handshake;
+use crate::tunerror::Error;
+
+/// Message type of cookie replies. See [`handshake::HANDSHAKE_INITIATION`] for how message
+/// types are numbered.
+pub const COOKIE_REPLY: u8 = 3;
+/// Length of the cookie an initiator echoes back to prove it can receive at its address.
+pub const COOKIE_LEN: usize = 16;
+/// type(1) reserved(3) receiver index(4) nonce(12) cookie(16) tag(16)
+pub const COOKIE_REPLY_LEN: usize = 8 + NONCE_LEN + COOKIE_LEN + TAG_LEN;
+/// How long the secret cookies are made from is kept, and so how long a cookie stays valid.
+const COOKIE_LIFETIME: Duration = Duration::from_secs(120);
+/// Number of initiations a single address may send at once before being rate limited.
+const RATE_LIMIT_BURST: u64 = 5;
+const COOKIE_KEY_LABEL: &[u8] = b"simple-vpn cookie key";
+/// Default number of initiations per second above which cookies are required.
+pub const DEFAULT_LOAD_THRESHOLD: u32 = 100;
+/// Default number of initiations per second accepted from one address under load.
+pub const DEFAULT_RATE_LIMIT: u32 = 20;
+
+/// Returns the key cookie replies are sealed with. Only peers that know the pre-shared key can
+/// read a cookie, and the tag binds the reply to the initiation it answers.
+fn cookie_key(psk: &[u8]) -> LessSafeKey {
+    let key = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, psk), COOKIE_KEY_LABEL);
+    // A SHA-256 output is always a valid ChaCha20-Poly1305 key
+    LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, &key.as_ref()[..KEY_LEN]).unwrap())
+}
+
+/// Computes the second MAC of an initiation, keyed by a cookie from the responder.
+pub fn mac2(cookie: &[u8; COOKIE_LEN], message: &[u8]) -> hmac::Tag {
+    hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, cookie), message)
+}
+
+/// A cookie received from a responder. It's echoed in our initiations until it expires.
+pub struct Cookie {
+    value: [u8; COOKIE_LEN],
+    received: Instant,
+}
+
+impl Cookie {
+    /// Returns the cookie if the responder can still accept it.
+    pub fn value(&self) -> Option<&[u8; COOKIE_LEN]> {
+        (self.received.elapsed() < COOKIE_LIFETIME).then_some(&self.value)
+    }
+}
+
+/// Opens a cookie reply to the initiation whose first MAC is `mac1`.
+pub fn consume_reply(buf: &[u8], psk: &[u8], mac1: &[u8]) -> Result<Cookie, Error> {
+    if buf.len() != COOKIE_REPLY_LEN || buf[0] != COOKIE_REPLY {
+        return Err(Error::Handshake("malformed cookie reply".to_owned()));
+    }
+    let nonce = Nonce::try_assume_unique_for_key(&buf[8..8 + NONCE_LEN]).unwrap();
+    let mut sealed = buf[8 + NONCE_LEN..].to_vec();
+    let cookie = cookie_key(psk)
+        .open_in_place(nonce, Aad::from(mac1), &mut sealed)
+        .map_err(|_| Error::Handshake("invalid cookie reply".to_owned()))?;
+    Ok(Cookie {
+        value: cookie.try_into().unwrap(),
+        received: Instant::now(),
+    })
+}
+
+/// What to do with an initiation whose first MAC was verified.
+pub enum Admission {
+    /// Go on with the handshake.
+    Accept,
+    /// Send this cookie reply back instead of doing the handshake.
+    CookieReply(Vec<u8>),
+}
+
+/// Token bucket limiting the initiations accepted from one address. Tokens are counted in
+/// nanoseconds: every initiation costs a second divided by the rate.
+struct TokenBucket {
+    tokens: u64,
+    last: Instant,
+}
+
+/// Protects the handshake from floods. While the server receives more initiations than its
+/// load threshold, it only runs handshakes for initiators that echo a cookie tied to their
+/// address, and limits how often each address may start one. Cookies are stateless: they're
+/// a MAC of the address under a secret that changes every two minutes.
+pub struct CookieChecker {
+    key: LessSafeKey,
+    secret: hmac::Key,
+    secret_created: Instant,
+    load_threshold: u32,
+    /// Nanoseconds of tokens an initiation costs, or 0 if initiations aren't rate limited.
+    cost: u64,
+    /// Start of the current second of load accounting.
+    window_start: Instant,
+    /// Initiations received in the current and the previous second.
+    current_count: u32,
+    previous_count: u32,
+    buckets: HashMap<IpAddr, TokenBucket>,
+}
+
+impl CookieChecker {
+    /// `load_threshold` is the number of initiations per second above which cookies are
+    /// required, 0 requiring them all the time. `rate_limit` is the number of initiations per
+    /// second accepted from one address under load, 0 meaning no limit.
+    pub fn new(
+        psk: &[u8],
+        load_threshold: u32,
+        rate_limit: u32,
+        rng: &SystemRandom,
+    ) -> Result<CookieChecker, Error> {
+        let now = Instant::now();
+        Ok(CookieChecker {
+            key: cookie_key(psk),
+            secret: Self::new_secret(rng)?,
+            secret_created: now,
+            load_threshold,
+            cost: match rate_limit {
+                0 => 0,
+                rate => Duration::from_secs(1).as_nanos() as u64 / rate as u64,
+            },
+            window_start: now,
+            current_count: 0,
+            previous_count: 0,
+            buckets: HashMap::new(),
+        })
+    }
+
+    fn new_secret(rng: &SystemRandom) -> Result<hmac::Key, Error> {
+        hmac::Key::generate(hmac::HMAC_SHA256, rng)
+            .map_err(|_| Error::Handshake("could not generate cookie secret".to_owned()))
+    }
+
+    /// Decides whether an initiation from `source` gets a handshake. Initiations from an
+    /// address over its rate limit are refused with an error.
+    pub fn admit(
+        &mut self,
+        message: &[u8],
+        source: SocketAddr,
+        rng: &SystemRandom,
+    ) -> Result<Admission, Error> {
+        let now = Instant::now();
+        if !self.record(now) {
+            return Ok(Admission::Accept);
+        }
+        if now.duration_since(self.secret_created) >= COOKIE_LIFETIME {
+            self.secret = Self::new_secret(rng)?;
+            self.secret_created = now;
+        }
+        let cookie = self.cookie(source);
+        let (signed, mac2) = message.split_at(handshake::INITIATION_LEN - handshake::MAC_LEN);
+        let key = hmac::Key::new(hmac::HMAC_SHA256, &cookie);
+        if hmac::verify(&key, signed, mac2).is_err() {
+            let mac1 = &signed[signed.len() - handshake::MAC_LEN..];
+            return Ok(Admission::CookieReply(
+                self.create_reply(message, &cookie, mac1, rng)?,
+            ));
+        }
+        if !self.take_token(source.ip(), now) {
+            return Err(Error::Handshake(format!("{} is rate limited", source.ip())));
+        }
+        Ok(Admission::Accept)
+    }
+
+    /// Counts an initiation and returns whether we're under load.
+    fn record(&mut self, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.window_start);
+        if elapsed >= Duration::from_secs(1) {
+            self.previous_count = if elapsed < Duration::from_secs(2) {
+                self.current_count
+            } else {
+                0
+            };
+            self.current_count = 0;
+            self.window_start = now;
+            let full = self.cost * RATE_LIMIT_BURST;
+            self.buckets.retain(|_, bucket| {
+                bucket.tokens + (now.duration_since(bucket.last).as_nanos() as u64) < full
+            });
+        }
+        self.current_count = self.current_count.saturating_add(1);
+        self.current_count > self.load_threshold || self.previous_count > self.load_threshold
+    }
+
+    fn cookie(&self, source: SocketAddr) -> [u8; COOKIE_LEN] {
+        let mut context = hmac::Context::with_key(&self.secret);
+        match source.ip() {
+            IpAddr::V4(ip) => context.update(&ip.octets()),
+            IpAddr::V6(ip) => context.update(&ip.octets()),
+        }
+        context.update(&source.port().to_be_bytes());
+        context.sign().as_ref()[..COOKIE_LEN].try_into().unwrap()
+    }
+
+    fn create_reply(
+        &self,
+        message: &[u8],
+        cookie: &[u8; COOKIE_LEN],
+        mac1: &[u8],
+        rng: &SystemRandom,
+    ) -> Result<Vec<u8>, Error> {
+        let mut nonce = [0; NONCE_LEN];
+        rng.fill(&mut nonce)
+            .map_err(|_| Error::Handshake("could not generate nonce".to_owned()))?;
+        let mut sealed = cookie.to_vec();
+        self.key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(mac1),
+                &mut sealed,
+            )
+            .map_err(|_| Error::Handshake("could not seal cookie".to_owned()))?;
+        let mut reply = Vec::with_capacity(COOKIE_REPLY_LEN);
+        reply.extend_from_slice(&[COOKIE_REPLY, 0, 0, 0]);
+        // The receiver index is the initiator's sender index
+        reply.extend_from_slice(&message[4..8]);
+        reply.extend_from_slice(&nonce);
+        reply.extend_from_slice(&sealed);
+        Ok(reply)
+    }
+
+    /// Takes a token from the bucket of an address. Returns false if it's empty.
+    fn take_token(&mut self, ip: IpAddr, now: Instant) -> bool {
+        if self.cost == 0 {
+            return true;
+        }
+        let full = self.cost * RATE_LIMIT_BURST;
+        let bucket = self.buckets.entry(ip).or_insert(TokenBucket {
+            tokens: full,
+            last: now,
+        });
+        let refill = now.duration_since(bucket.last).as_nanos() as u64;
+        bucket.tokens = (bucket.tokens + refill).min(full);
+        bucket.last = now;
+        if bucket.tokens < self.cost {
+            return false;
+        }
+        bucket.tokens -= self.cost;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::CipherSuite;
+    use crate::handshake::Identity;
+
+    const PSK: &[u8] = b"pre-shared key";
+
+    fn source(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    /// Creates an initiation echoing `cookie`, and gives back its first MAC as well.
+    fn initiation(cookie: Option<&[u8; COOKIE_LEN]>) -> (Vec<u8>, Vec<u8>) {
+        let initiator = Identity::from_private_key(&[1; KEY_LEN]);
+        let responder = Identity::from_private_key(&[2; KEY_LEN]);
+        let (message, pending) = handshake::create_initiation(
+            &initiator,
+            responder.public_key(),
+            PSK,
+            7,
+            &[CipherSuite::ChaCha20Poly1305],
+            cookie,
+            &SystemRandom::new(),
+        )
+        .unwrap();
+        (message, pending.mac1().to_vec())
+    }
+
+    /// Has an initiation without a cookie answered, and opens the cookie in the reply.
+    fn cookie_for(checker: &mut CookieChecker, from: SocketAddr) -> Cookie {
+        let (message, mac1) = initiation(None);
+        let Ok(Admission::CookieReply(reply)) = checker.admit(&message, from, &SystemRandom::new())
+        else {
+            panic!("no cookie reply");
+        };
+        consume_reply(&reply, PSK, &mac1).unwrap()
+    }
+
+    fn is_accepted(checker: &mut CookieChecker, message: &[u8], from: SocketAddr) -> bool {
+        match checker.admit(message, from, &SystemRandom::new()).unwrap() {
+            Admission::Accept => true,
+            Admission::CookieReply(_) => false,
+        }
+    }
+
+    #[test]
+    fn cookies_are_only_required_under_load() {
+        let rng = SystemRandom::new();
+        let mut checker = CookieChecker::new(PSK, 3, 0, &rng).unwrap();
+        let from = source("192.0.2.2:40000");
+        let (message, mac1) = initiation(None);
+        for _ in 0..3 {
+            assert!(is_accepted(&mut checker, &message, from));
+        }
+        let Admission::CookieReply(reply) = checker.admit(&message, from, &rng).unwrap() else {
+            panic!("accepted over the load threshold");
+        };
+        assert_eq!(reply.len(), COOKIE_REPLY_LEN);
+        assert_eq!(reply[0], COOKIE_REPLY);
+        // Addressed to the initiator's index
+        assert_eq!(reply[4..8], message[4..8]);
+
+        let cookie = consume_reply(&reply, PSK, &mac1).unwrap();
+        let (message, _) = initiation(cookie.value());
+        assert!(is_accepted(&mut checker, &message, from));
+    }
+
+    #[test]
+    fn replies_only_open_for_the_initiation_they_answer() {
+        let rng = SystemRandom::new();
+        let mut checker = CookieChecker::new(PSK, 0, 0, &rng).unwrap();
+        let (message, mac1) = initiation(None);
+        let Admission::CookieReply(reply) = checker
+            .admit(&message, source("192.0.2.2:40000"), &rng)
+            .unwrap()
+        else {
+            panic!("accepted without a cookie");
+        };
+        let (_, other_mac1) = initiation(None);
+        assert!(consume_reply(&reply, PSK, &other_mac1).is_err());
+        assert!(consume_reply(&reply, b"other key", &mac1).is_err());
+        assert!(consume_reply(&reply[..COOKIE_REPLY_LEN - 1], PSK, &mac1).is_err());
+        assert!(consume_reply(&reply, PSK, &mac1).is_ok());
+    }
+
+    #[test]
+    fn a_wrong_mac2_gets_another_reply() {
+        let mut checker = CookieChecker::new(PSK, 0, 0, &SystemRandom::new()).unwrap();
+        let from = source("192.0.2.2:40000");
+        let cookie = cookie_for(&mut checker, from);
+        let (message, _) = initiation(Some(&[0; COOKIE_LEN]));
+        assert!(!is_accepted(&mut checker, &message, from));
+        // A cookie only stands for the address it was sent to
+        let (message, _) = initiation(cookie.value());
+        assert!(!is_accepted(
+            &mut checker,
+            &message,
+            source("192.0.2.2:40001")
+        ));
+        assert!(!is_accepted(
+            &mut checker,
+            &message,
+            source("192.0.2.3:40000")
+        ));
+        assert!(is_accepted(&mut checker, &message, from));
+    }
+
+    #[test]
+    fn cookies_expire() {
+        let mut checker = CookieChecker::new(PSK, 0, 0, &SystemRandom::new()).unwrap();
+        let from = source("192.0.2.2:40000");
+        let mut cookie = cookie_for(&mut checker, from);
+        assert!(cookie.value().is_some());
+        let value = cookie.value;
+        cookie.received -= COOKIE_LIFETIME;
+        assert!(cookie.value().is_none());
+
+        // The responder forgets the secret it made them from as well
+        let (message, _) = initiation(Some(&value));
+        assert!(is_accepted(&mut checker, &message, from));
+        checker.secret_created -= COOKIE_LIFETIME;
+        assert!(!is_accepted(&mut checker, &message, from));
+    }
+
+    #[test]
+    fn initiations_are_rate_limited_per_address() {
+        let mut checker = CookieChecker::new(PSK, 0, 20, &SystemRandom::new()).unwrap();
+        let from = source("192.0.2.2:40000");
+        let cookie = cookie_for(&mut checker, from);
+        let (message, _) = initiation(cookie.value());
+        for _ in 0..RATE_LIMIT_BURST {
+            assert!(is_accepted(&mut checker, &message, from));
+        }
+        let error = checker
+            .admit(&message, from, &SystemRandom::new())
+            .err()
+            .unwrap();
+        assert!(matches!(error, Error::Handshake(reason) if reason.contains("rate limited")));
+
+        let other = source("192.0.2.3:40000");
+        let cookie = cookie_for(&mut checker, other);
+        let (message, _) = initiation(cookie.value());
+        assert!(is_accepted(&mut checker, &message, other));
+    }
+
+    #[test]
+    fn buckets_refill_at_the_rate() {
+        let mut checker = CookieChecker::new(PSK, 0, 20, &SystemRandom::new()).unwrap();
+        let ip = source("192.0.2.2:40000").ip();
+        let now = Instant::now();
+        for _ in 0..RATE_LIMIT_BURST {
+            assert!(checker.take_token(ip, now));
+        }
+        assert!(!checker.take_token(ip, now));
+        // One initiation every 50ms
+        assert!(!checker.take_token(ip, now + Duration::from_millis(40)));
+        assert!(checker.take_token(ip, now + Duration::from_millis(50)));
+        assert!(!checker.take_token(ip, now + Duration::from_millis(50)));
+        // Waiting longer doesn't save up more than a burst
+        let later = now + Duration::from_secs(10);
+        for _ in 0..RATE_LIMIT_BURST {
+            assert!(checker.take_token(ip, later));
+        }
+        assert!(!checker.take_token(ip, later));
+
+        let mut unlimited = CookieChecker::new(PSK, 0, 0, &SystemRandom::new()).unwrap();
+        assert!((0..100).all(|_| unlimited.take_token(ip, now)));
+    }
+}
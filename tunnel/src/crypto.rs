@@ -0,0 +1,205 @@
+use std::num::NonZeroU32;
+
+use ring::aead::{self, AES_256_GCM, CHACHA20_POLY1305};
+use ring::hkdf;
+use ring::pbkdf2;
+
+/// Salt used when none is configured. Both peers must use the same salt.
+pub const DEFAULT_SALT: &str = "simple-vpn";
+/// PBKDF2 iteration count used when none is configured.
+pub const DEFAULT_ITERATIONS: u32 = 100_000;
+/// Length of the pre-shared key and of static and ephemeral public keys.
+pub const KEY_LEN: usize = 32;
+/// Length of the authentication tag added by every supported cipher suite.
+pub const TAG_LEN: usize = 16;
+
+/// The AEAD algorithms a session can be encrypted with. Both use 256 bit keys and 16 byte tags.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CipherSuite {
+    Aes256Gcm = 1,
+    ChaCha20Poly1305 = 2,
+}
+
+impl CipherSuite {
+    /// Every supported suite, in the order they're preferred by default. AES-GCM is fastest on
+    /// CPUs with AES instructions; peers without them should prefer ChaCha20-Poly1305.
+    pub const ALL: [CipherSuite; 2] = [CipherSuite::Aes256Gcm, CipherSuite::ChaCha20Poly1305];
+
+    pub fn algorithm(self) -> &'static aead::Algorithm {
+        match self {
+            CipherSuite::Aes256Gcm => &AES_256_GCM,
+            CipherSuite::ChaCha20Poly1305 => &CHACHA20_POLY1305,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            CipherSuite::Aes256Gcm => "aes-256-gcm",
+            CipherSuite::ChaCha20Poly1305 => "chacha20-poly1305",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<CipherSuite> {
+        CipherSuite::ALL
+            .into_iter()
+            .find(|suite| suite.name() == name)
+    }
+
+    /// Returns the suite with this identifier, as sent in handshake messages.
+    pub fn from_id(id: u8) -> Option<CipherSuite> {
+        CipherSuite::ALL
+            .into_iter()
+            .find(|suite| *suite as u8 == id)
+    }
+
+    /// Returns a bit set with the bit of every suite in the list.
+    pub fn to_mask(suites: &[CipherSuite]) -> u8 {
+        suites
+            .iter()
+            .fold(0, |mask, suite| mask | 1 << *suite as u8)
+    }
+
+    pub fn in_mask(self, mask: u8) -> bool {
+        mask & 1 << self as u8 != 0
+    }
+}
+
+const INITIATOR_TO_RESPONDER_INFO: &[u8] = b"simple-vpn initiator to responder";
+const RESPONDER_TO_INITIATOR_INFO: &[u8] = b"simple-vpn responder to initiator";
+
+/// The keys used by each side of a session. The initiator of the handshake seals with
+/// `initiator_to_responder` and opens with `responder_to_initiator`; the responder does the
+/// opposite.
+pub struct DirectionalKeys {
+    pub initiator_to_responder: Vec<u8>,
+    pub responder_to_initiator: Vec<u8>,
+}
+
+impl DirectionalKeys {
+    /// Returns the (sending, receiving) keys for a peer.
+    pub fn split(self, is_initiator: bool) -> (Vec<u8>, Vec<u8>) {
+        if is_initiator {
+            (self.initiator_to_responder, self.responder_to_initiator)
+        } else {
+            (self.responder_to_initiator, self.initiator_to_responder)
+        }
+    }
+}
+
+/// Output length for HKDF expansion.
+struct KeyLen(usize);
+
+impl hkdf::KeyType for KeyLen {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+/// Stretches a password into a pre-shared key with PBKDF2-HMAC-SHA256. The pre-shared key is
+/// mixed into every handshake, so only peers that know the password can complete one.
+pub fn derive_psk(password: &str, salt: &[u8], iterations: NonZeroU32) -> [u8; KEY_LEN] {
+    let mut psk = [0; KEY_LEN];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        iterations,
+        salt,
+        password.as_bytes(),
+        &mut psk,
+    );
+    psk
+}
+
+/// Derives the keys of a session from the chaining key the handshake ended with, which took in
+/// the pre-shared key and every Diffie-Hellman result, and a hash of the handshake messages.
+/// Each direction gets its own key.
+pub fn derive_session_keys(
+    suite: CipherSuite,
+    chaining_key: &[u8],
+    transcript: &[u8],
+) -> DirectionalKeys {
+    let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, chaining_key).extract(&[]);
+    let len = suite.algorithm().key_len();
+    DirectionalKeys {
+        initiator_to_responder: expand(&prk, &[INITIATOR_TO_RESPONDER_INFO, transcript], len),
+        responder_to_initiator: expand(&prk, &[RESPONDER_TO_INITIATOR_INFO, transcript], len),
+    }
+}
+
+fn expand(prk: &hkdf::Prk, info: &[&[u8]], len: usize) -> Vec<u8> {
+    let mut key = vec![0; len];
+    // The output length is far below HKDF's limit of 255 hash lengths, so this can't fail
+    prk.expand(info, KeyLen(len))
+        .unwrap()
+        .fill(&mut key)
+        .unwrap();
+    key
+}
+
+/// Encodes a key as lowercase hex so it can be printed and passed on the command line.
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Decodes a hex encoded key. Returns `None` if the string isn't `KEY_LEN` bytes of hex.
+pub fn from_hex(hex: &str) -> Option<[u8; KEY_LEN]> {
+    if hex.len() != KEY_LEN * 2 || !hex.is_ascii() {
+        return None;
+    }
+    let mut key = [0; KEY_LEN];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_psk_depends_on_the_password_and_the_salt() {
+        let psk = |password, salt: &str| {
+            derive_psk(password, salt.as_bytes(), NonZeroU32::new(1).unwrap())
+        };
+        assert_eq!(psk("password", "salt"), psk("password", "salt"));
+        assert_ne!(psk("password", "salt"), psk("passw0rd", "salt"));
+        assert_ne!(psk("password", "salt"), psk("password", "pepper"));
+    }
+
+    #[test]
+    fn each_direction_has_its_own_key() {
+        let suite = CipherSuite::Aes256Gcm;
+        let keys = || derive_session_keys(suite, &[1; KEY_LEN], b"transcript");
+        let (initiator_send, initiator_recv) = keys().split(true);
+        let (responder_send, responder_recv) = keys().split(false);
+        assert_eq!(initiator_send.len(), AES_256_GCM.key_len());
+        assert_ne!(initiator_send, initiator_recv);
+        // What one side seals with, the other opens with
+        assert_eq!(initiator_send, responder_recv);
+        assert_eq!(initiator_recv, responder_send);
+        // Another handshake gets other keys
+        let other = derive_session_keys(suite, &[1; KEY_LEN], b"other transcript");
+        assert_ne!(other.initiator_to_responder, initiator_send);
+    }
+
+    #[test]
+    fn suites_go_through_their_names_and_ids() {
+        for suite in CipherSuite::ALL {
+            assert_eq!(CipherSuite::from_name(suite.name()), Some(suite));
+            assert_eq!(CipherSuite::from_id(suite as u8), Some(suite));
+            assert!(suite.in_mask(CipherSuite::to_mask(&[suite])));
+        }
+        assert_eq!(CipherSuite::from_name("aes-128-gcm"), None);
+        assert!(
+            !CipherSuite::ChaCha20Poly1305.in_mask(CipherSuite::to_mask(&[CipherSuite::Aes256Gcm]))
+        );
+    }
+
+    #[test]
+    fn keys_go_through_hex() {
+        let key = [0xab; KEY_LEN];
+        assert_eq!(from_hex(&to_hex(&key)), Some(key));
+        assert_eq!(from_hex("ab"), None);
+        assert_eq!(from_hex(&"zz".repeat(KEY_LEN)), None);
+    }
+}
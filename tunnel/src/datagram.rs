@@ -0,0 +1,407 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::mem::MaybeUninit;
+use std::net::SocketAddr;
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+use libc::{fcntl, F_GETFL, F_SETFL, O_NONBLOCK};
+use socket2::{SockAddr, Socket};
+
+use crate::mmsg::{self, Received, Transmit};
+use crate::pool::PacketBuf;
+use crate::tunerror::Error;
+
+/// Carries the datagrams [`crate::net::Net`] seals and opens. It sits below the handshake,
+/// encryption and peer tracking, which work the same whatever carries their datagrams. A UDP
+/// socket carries them over the network, and a [`MemorySocket`] within the process, so a
+/// tunnel can run without the network or root.
+pub trait DatagramSocket: AsRawFd {
+    /// Sends a datagram to `addr`, or to the address the socket is connected to if it's `None`.
+    fn send_to(&self, buf: &[u8], addr: Option<&SockAddr>) -> io::Result<usize>;
+
+    /// Waits for a datagram and receives it, and returns its length and where it came from.
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SockAddr)>;
+
+    fn local_addr(&self) -> io::Result<SockAddr>;
+
+    /// Sends messages, and returns how many were sent, which may be fewer than given. Fails
+    /// only if the first one couldn't be sent. Sockets that don't do GSO are never given a
+    /// segment length.
+    fn send_batch(&self, transmits: &[Transmit]) -> io::Result<usize> {
+        for (i, transmit) in transmits.iter().enumerate() {
+            let contents: Vec<u8> = transmit
+                .contents
+                .iter()
+                .flat_map(|s| s.iter())
+                .copied()
+                .collect();
+            if let Err(e) = self.send_to(&contents, transmit.addr) {
+                return if i == 0 { Err(e) } else { Ok(i) };
+            }
+        }
+        Ok(transmits.len())
+    }
+
+    /// Receives the messages waiting, up to one per buffer, without waiting if none is. They
+    /// fill the first buffers in order and are described in `received`. Returns how many there
+    /// are.
+    fn recv_batch(&self, bufs: &mut [PacketBuf], received: &mut [Received]) -> io::Result<usize>;
+
+    /// Returns whether the socket can split a message into datagrams of equal length (GSO).
+    fn supports_gso(&self) -> bool {
+        false
+    }
+
+    /// Asks the socket to put received datagrams together (GRO), and returns whether it will.
+    fn enable_gro(&self) -> bool {
+        false
+    }
+}
+
+impl DatagramSocket for Socket {
+    fn send_to(&self, buf: &[u8], addr: Option<&SockAddr>) -> io::Result<usize> {
+        match addr {
+            Some(addr) => Socket::send_to(self, buf, addr),
+            None => Socket::send(self, buf),
+        }
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SockAddr)> {
+        let buf = unsafe { &mut *(buf as *mut [u8] as *mut [MaybeUninit<u8>]) };
+        Socket::recv_from(self, buf)
+    }
+
+    fn local_addr(&self) -> io::Result<SockAddr> {
+        Socket::local_addr(self)
+    }
+
+    fn send_batch(&self, transmits: &[Transmit]) -> io::Result<usize> {
+        mmsg::send(self, transmits)
+    }
+
+    fn recv_batch(&self, bufs: &mut [PacketBuf], received: &mut [Received]) -> io::Result<usize> {
+        mmsg::recv(self, bufs, received)
+    }
+
+    fn supports_gso(&self) -> bool {
+        mmsg::supports_gso(self)
+    }
+
+    fn enable_gro(&self) -> bool {
+        mmsg::enable_gro(self)
+    }
+}
+
+/// Datagrams waiting for a [`MemorySocket`], with where they came from, and the write end of
+/// the pipe that wakes it up.
+struct Inbox {
+    datagrams: Sender<(SocketAddr, Vec<u8>)>,
+    notify: File,
+}
+
+/// A network within the process, which carries datagrams between the [`MemorySocket`]s bound
+/// to it. Datagrams sent to an address nobody is bound to are lost, as they are on a real
+/// network. Clones are the same network.
+#[derive(Clone, Default)]
+pub struct MemoryNetwork {
+    inboxes: Arc<Mutex<HashMap<SocketAddr, Inbox>>>,
+}
+
+impl MemoryNetwork {
+    pub fn new() -> MemoryNetwork {
+        MemoryNetwork::default()
+    }
+
+    /// Makes a socket bound to `addr`, which must not be taken by another one already.
+    pub fn bind(&self, addr: SocketAddr) -> Result<MemorySocket, Error> {
+        let mut inboxes = self.inboxes.lock().unwrap();
+        if inboxes.contains_key(&addr) {
+            return Err(Error::IoError(io::ErrorKind::AddrInUse.into()));
+        }
+        let (ready, notify) = pipe()?;
+        let (sender, receiver) = mpsc::channel();
+        inboxes.insert(
+            addr,
+            Inbox {
+                datagrams: sender,
+                notify,
+            },
+        );
+        Ok(MemorySocket {
+            addr,
+            peer_addr: None,
+            network: self.clone(),
+            datagrams: receiver,
+            ready,
+        })
+    }
+}
+
+/// A socket on a [`MemoryNetwork`], which behaves like a UDP socket: receiving waits for a
+/// datagram, and datagrams are lost rather than queued without end when too many are waiting.
+/// Its descriptor is the read end of a pipe holding a byte for every datagram waiting, so it
+/// can be waited on like a socket.
+pub struct MemorySocket {
+    addr: SocketAddr,
+    /// Where datagrams go by default, and the only address they're taken from, once connected.
+    peer_addr: Option<SocketAddr>,
+    network: MemoryNetwork,
+    datagrams: Receiver<(SocketAddr, Vec<u8>)>,
+    ready: File,
+}
+
+impl MemorySocket {
+    /// Sends datagrams to `addr` when no address is given, and takes them only from there.
+    pub fn connect(&mut self, addr: SocketAddr) {
+        self.peer_addr = Some(addr);
+    }
+
+    /// Receives a datagram if one is waiting, and fails with `WouldBlock` otherwise.
+    fn try_recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SockAddr)> {
+        loop {
+            (&self.ready).read_exact(&mut [0])?;
+            // The sender wrote the byte first, but still holds the network until the datagram
+            // follows it
+            let (from, datagram) = self
+                .datagrams
+                .recv()
+                .map_err(|_| io::ErrorKind::BrokenPipe)?;
+            if self.peer_addr.is_some_and(|peer_addr| peer_addr != from) {
+                continue;
+            }
+            let len = datagram.len().min(buf.len());
+            buf[..len].copy_from_slice(&datagram[..len]);
+            return Ok((len, from.into()));
+        }
+    }
+}
+
+impl Drop for MemorySocket {
+    fn drop(&mut self) {
+        self.network.inboxes.lock().unwrap().remove(&self.addr);
+    }
+}
+
+/// Makes a pipe whose ends don't block, and returns both of them.
+fn pipe() -> Result<(File, File), Error> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+        return Err(Error::IoError(io::Error::last_os_error()));
+    }
+    let (read, write) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+    for fd in fds {
+        let flags = unsafe { fcntl(fd, F_GETFL) };
+        if flags < 0 || unsafe { fcntl(fd, F_SETFL, flags | O_NONBLOCK) } < 0 {
+            return Err(Error::FCntl(io::Error::last_os_error()));
+        }
+    }
+    Ok((read, write))
+}
+
+/// Waits until a descriptor has something to read.
+fn wait_readable(fd: RawFd) -> io::Result<()> {
+    let mut pollfd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    loop {
+        if unsafe { libc::poll(&mut pollfd, 1, -1) } >= 0 {
+            return Ok(());
+        }
+        let error = io::Error::last_os_error();
+        if error.kind() != io::ErrorKind::Interrupted {
+            return Err(error);
+        }
+    }
+}
+
+impl DatagramSocket for MemorySocket {
+    /// Sends a datagram. A connected socket fails with `ConnectionRefused` if nobody is bound
+    /// to its peer's address, as it would once the ICMP error came back.
+    fn send_to(&self, buf: &[u8], addr: Option<&SockAddr>) -> io::Result<usize> {
+        let to = match addr {
+            Some(addr) => addr.as_socket().ok_or(io::ErrorKind::InvalidInput)?,
+            None => self.peer_addr.ok_or(io::ErrorKind::NotConnected)?,
+        };
+        let inboxes = self.network.inboxes.lock().unwrap();
+        let Some(inbox) = inboxes.get(&to) else {
+            return match addr {
+                Some(_) => Ok(buf.len()),
+                None => Err(io::ErrorKind::ConnectionRefused.into()),
+            };
+        };
+        // A full pipe means the receiver is behind, so the datagram is dropped like UDP would
+        match (&inbox.notify).write(&[0]) {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(buf.len()),
+            Err(e) => return Err(e),
+        }
+        // The receiver holds its end of the channel for as long as it's in the network
+        inbox.datagrams.send((self.addr, buf.to_vec())).unwrap();
+        Ok(buf.len())
+    }
+
+    /// Waits for a datagram and receives it. A datagram longer than `buf` is cut short, as UDP
+    /// does.
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SockAddr)> {
+        loop {
+            wait_readable(self.ready.as_raw_fd())?;
+            match self.try_recv_from(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                result => return result,
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SockAddr> {
+        Ok(self.addr.into())
+    }
+
+    fn recv_batch(&self, bufs: &mut [PacketBuf], received: &mut [Received]) -> io::Result<usize> {
+        let mut count = 0;
+        for (buf, received) in bufs.iter_mut().zip(received.iter_mut()) {
+            match self.try_recv_from(buf.as_mut()) {
+                Ok((len, addr)) => *received = Received::new(len, addr),
+                Err(e) if count == 0 => return Err(e),
+                Err(_) => break,
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+impl AsRawFd for MemorySocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.ready.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::IoSlice;
+    use std::net::Ipv4Addr;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::select::{select, to_timeval, FdSet};
+
+    fn is_readable(socket: &MemorySocket) -> bool {
+        let mut fdset = FdSet::new();
+        fdset.set(socket.as_raw_fd());
+        let timeout = to_timeval(Duration::ZERO);
+        select(
+            socket.as_raw_fd() + 1,
+            Some(&mut fdset),
+            None,
+            None,
+            Some(&timeout),
+        )
+        .unwrap();
+        fdset.is_set(socket.as_raw_fd())
+    }
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from((Ipv4Addr::LOCALHOST, port))
+    }
+
+    #[test]
+    fn datagrams_reach_the_socket_bound_to_their_address_in_order() {
+        let network = MemoryNetwork::new();
+        let a = network.bind(addr(1)).unwrap();
+        let mut b = network.bind(addr(2)).unwrap();
+        let c = network.bind(addr(3)).unwrap();
+        assert!(network.bind(addr(1)).is_err());
+        assert!(!is_readable(&b));
+        let slices = [
+            IoSlice::new(b"fir"),
+            IoSlice::new(b"st"),
+            IoSlice::new(b"second"),
+        ];
+        let b_addr = SockAddr::from(addr(2));
+        let transmits = [
+            Transmit {
+                contents: &slices[..2],
+                addr: Some(&b_addr),
+                segment_len: None,
+            },
+            Transmit {
+                contents: &slices[2..],
+                addr: Some(&b_addr),
+                segment_len: None,
+            },
+        ];
+        assert_eq!(a.send_batch(&transmits).unwrap(), 2);
+        // Nobody is at other addresses
+        a.send_to(b"lost", Some(&addr(4).into())).unwrap();
+        assert!(is_readable(&b) && !is_readable(&a) && !is_readable(&c));
+        let mut bufs = [
+            PacketBuf::with_capacity(4),
+            PacketBuf::new(),
+            PacketBuf::new(),
+        ];
+        let mut received = [Received::default(); 3];
+        assert_eq!(b.recv_batch(&mut bufs, &mut received).unwrap(), 2);
+        // What doesn't fit is cut off
+        assert_eq!(&bufs[0].as_mut()[..received[0].len], b"firs");
+        assert_eq!(&bufs[1].as_mut()[..received[1].len], b"second");
+        assert_eq!(received[1].addr(), addr(1).into());
+        assert!(!is_readable(&b));
+        let result = b.recv_batch(&mut bufs, &mut received);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        // Once connected, only the peer is heard from
+        b.connect(addr(1));
+        c.send_to(b"other", Some(&b_addr)).unwrap();
+        a.send_to(b"peer", Some(&b_addr)).unwrap();
+        let mut buf = [0; 10];
+        assert_eq!(b.recv_from(&mut buf).unwrap(), (4, addr(1).into()));
+        assert_eq!(&buf[..4], b"peer");
+        // Nobody is there once the peer is gone
+        drop(a);
+        let result = b.send_to(b"gone", None);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::ConnectionRefused);
+        assert!(network.bind(addr(1)).is_ok());
+    }
+
+    #[test]
+    fn receiving_waits_for_a_datagram() {
+        let network = MemoryNetwork::new();
+        let a = network.bind(addr(1)).unwrap();
+        let b = network.bind(addr(2)).unwrap();
+        let receiver = thread::spawn(move || {
+            let mut buf = [0; 10];
+            let (len, _) = b.recv_from(&mut buf).unwrap();
+            buf[..len].to_vec()
+        });
+        thread::sleep(Duration::from_millis(50));
+        a.send_to(b"late", Some(&addr(2).into())).unwrap();
+        assert_eq!(receiver.join().unwrap(), b"late");
+    }
+
+    #[test]
+    fn datagrams_nobody_reads_are_lost_instead_of_blocking_the_sender() {
+        let network = MemoryNetwork::new();
+        let a = network.bind(addr(1)).unwrap();
+        let b = network.bind(addr(2)).unwrap();
+        // Far more than a pipe holds
+        let sent = 200_000;
+        for _ in 0..sent {
+            a.send_to(b"x", Some(&addr(2).into())).unwrap();
+        }
+        let mut bufs: Vec<_> = (0..1024).map(|_| PacketBuf::with_capacity(1)).collect();
+        let mut received = vec![Received::default(); 1024];
+        let mut waiting = 0;
+        while let Ok(count) = b.recv_batch(&mut bufs, &mut received) {
+            waiting += count;
+        }
+        assert!(0 < waiting && waiting < sent);
+        // Room again once they're read
+        a.send_to(b"x", Some(&addr(2).into())).unwrap();
+        assert!(is_readable(&b));
+    }
+}
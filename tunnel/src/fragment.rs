@@ -0,0 +1,237 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use socket2::SockAddr;
+
+use crate::tunerror::Error;
+
+/// Message type of fragments of a datagram too long for the path between peers. See
+/// [`crate::handshake::HANDSHAKE_INITIATION`] for how message types are numbered. A fragment is
+/// the type, its index, the number of fragments, a reserved byte and the datagram's id, followed
+/// by its piece of the datagram. Fragments aren't sealed themselves: the datagram they make up
+/// is authenticated once it's put back together.
+pub const FRAGMENT: u8 = 7;
+pub const HEADER_LEN: usize = 8;
+/// Number of fragments a datagram may be split into.
+pub const MAX_FRAGMENTS: usize = 8;
+/// Incomplete datagrams are dropped once their first fragment is this old.
+pub const TIMEOUT: Duration = Duration::from_secs(2);
+/// Number of bytes held by incomplete datagrams. The oldest are dropped to stay under it.
+pub const MAX_HELD_BYTES: usize = 1 << 20;
+/// Number of incomplete datagrams. The oldest are dropped to stay under it.
+pub const MAX_INCOMPLETE: usize = 256;
+
+/// Splits a datagram into fragments of at most `max_len` bytes, headers included, which must be
+/// more than [`HEADER_LEN`]. Returns `None` if it takes more than [`MAX_FRAGMENTS`].
+pub fn split(datagram: &[u8], id: u32, max_len: usize) -> Option<Vec<Vec<u8>>> {
+    let pieces: Vec<&[u8]> = datagram.chunks(max_len - HEADER_LEN).collect();
+    if pieces.len() > MAX_FRAGMENTS {
+        return None;
+    }
+    let fragments = pieces
+        .iter()
+        .enumerate()
+        .map(|(index, piece)| {
+            let mut fragment = vec![FRAGMENT, index as u8, pieces.len() as u8, 0];
+            fragment.extend_from_slice(&id.to_le_bytes());
+            fragment.extend_from_slice(piece);
+            fragment
+        })
+        .collect();
+    Some(fragments)
+}
+
+/// A datagram some fragments of arrived.
+struct Incomplete {
+    pieces: Vec<Option<Vec<u8>>>,
+    held: usize,
+    started: Instant,
+}
+
+/// Puts datagrams back together from their fragments, by sender and id. Anyone can send
+/// fragments, so what's held is limited in time, size and number.
+#[derive(Default)]
+pub struct Reassembler {
+    incomplete: HashMap<(SockAddr, u32), Incomplete>,
+    held: usize,
+}
+
+impl Reassembler {
+    pub fn new() -> Reassembler {
+        Reassembler::default()
+    }
+
+    /// Adds a fragment received from `from`, and returns the whole datagram if that was its
+    /// last missing fragment. Datagrams longer than `max_len` are refused.
+    pub fn add(
+        &mut self,
+        from: &SockAddr,
+        fragment: &[u8],
+        max_len: usize,
+        now: Instant,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let malformed = || Error::Message("malformed fragment".to_owned());
+        if fragment.len() <= HEADER_LEN || fragment[0] != FRAGMENT {
+            return Err(malformed());
+        }
+        let (index, count) = (fragment[1] as usize, fragment[2] as usize);
+        if count > MAX_FRAGMENTS || index >= count {
+            return Err(malformed());
+        }
+        let id = u32::from_le_bytes(fragment[4..HEADER_LEN].try_into().unwrap());
+        let piece = &fragment[HEADER_LEN..];
+        let key = (from.clone(), id);
+        let is_new = match self.incomplete.get(&key) {
+            Some(incomplete) => {
+                if incomplete.pieces.len() != count || incomplete.held + piece.len() > max_len {
+                    self.remove(&key);
+                    return Err(malformed());
+                }
+                // A fragment sent again changes nothing
+                if incomplete.pieces[index].is_some() {
+                    return Ok(None);
+                }
+                false
+            }
+            None if piece.len() > max_len => return Err(malformed()),
+            None => true,
+        };
+        self.drop_oldest(
+            MAX_INCOMPLETE - is_new as usize,
+            MAX_HELD_BYTES - piece.len(),
+        );
+        let incomplete = self
+            .incomplete
+            .entry(key.clone())
+            .or_insert_with(|| Incomplete {
+                pieces: vec![None; count],
+                held: 0,
+                started: now,
+            });
+        incomplete.pieces[index] = Some(piece.to_vec());
+        incomplete.held += piece.len();
+        self.held += piece.len();
+        if incomplete.pieces.iter().any(Option::is_none) {
+            return Ok(None);
+        }
+        let incomplete = self.remove(&key).unwrap();
+        Ok(Some(
+            incomplete.pieces.into_iter().flatten().flatten().collect(),
+        ))
+    }
+
+    /// Drops the datagrams whose first fragment arrived longer than [`TIMEOUT`] ago.
+    pub fn expire(&mut self, now: Instant) {
+        let held = &mut self.held;
+        self.incomplete.retain(|_, incomplete| {
+            let keep = now.duration_since(incomplete.started) < TIMEOUT;
+            if !keep {
+                *held -= incomplete.held;
+            }
+            keep
+        });
+    }
+
+    /// Drops the oldest datagrams until at most `max_count` of them hold at most `max_bytes`.
+    fn drop_oldest(&mut self, max_count: usize, max_bytes: usize) {
+        while self.incomplete.len() > max_count || self.held > max_bytes {
+            let oldest = self
+                .incomplete
+                .iter()
+                .min_by_key(|(_, incomplete)| incomplete.started)
+                .map(|(key, _)| key.clone());
+            let Some(oldest) = oldest else {
+                return;
+            };
+            self.remove(&oldest);
+        }
+    }
+
+    fn remove(&mut self, key: &(SockAddr, u32)) -> Option<Incomplete> {
+        let incomplete = self.incomplete.remove(key)?;
+        self.held -= incomplete.held;
+        Some(incomplete)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use super::*;
+
+    fn addr(port: u16) -> SockAddr {
+        SocketAddr::from((Ipv4Addr::LOCALHOST, port)).into()
+    }
+
+    #[test]
+    fn fragments_in_any_order_make_the_datagram_again() {
+        let datagram: Vec<u8> = (0..=255).cycle().take(1000).collect();
+        let mut fragments = split(&datagram, 7, 300).unwrap();
+        assert_eq!(fragments.len(), 4);
+        assert!(fragments.iter().all(|fragment| fragment.len() <= 300));
+        fragments.reverse();
+        let mut reassembler = Reassembler::new();
+        let now = Instant::now();
+        for fragment in &fragments[..3] {
+            assert_eq!(
+                reassembler.add(&addr(1), fragment, 4096, now).unwrap(),
+                None
+            );
+        }
+        // Another sender's fragments don't count
+        let last = &fragments[3];
+        assert_eq!(reassembler.add(&addr(2), last, 4096, now).unwrap(), None);
+        let whole = reassembler.add(&addr(1), last, 4096, now).unwrap();
+        assert_eq!(whole, Some(datagram));
+        assert_eq!(reassembler.held, fragments[3].len() - HEADER_LEN);
+    }
+
+    #[test]
+    fn datagrams_needing_too_many_fragments_are_not_split() {
+        assert!(split(&[0; 100], 0, HEADER_LEN + 12).is_none());
+        assert_eq!(split(&[0; 96], 0, HEADER_LEN + 12).unwrap().len(), 8);
+    }
+
+    #[test]
+    fn malformed_and_oversized_fragments_are_refused() {
+        let mut reassembler = Reassembler::new();
+        let now = Instant::now();
+        for fragment in [
+            vec![FRAGMENT, 0, 1, 0, 0, 0, 0, 0],
+            vec![FRAGMENT, 2, 2, 0, 0, 0, 0, 0, 1],
+            vec![FRAGMENT, 0, 9, 0, 0, 0, 0, 0, 1],
+        ] {
+            assert!(reassembler.add(&addr(1), &fragment, 4096, now).is_err());
+        }
+        assert_eq!(reassembler.incomplete.len(), 0);
+        let fragments = split(&[1; 100], 1, 60).unwrap();
+        // Fragments that disagree on their count or make too long a datagram drop what arrived
+        let mut recounted = fragments[1].clone();
+        recounted[2] = 3;
+        for (fragment, max_len) in [(&recounted, 4096), (&fragments[1], 80)] {
+            let first = reassembler.add(&addr(1), &fragments[0], 4096, now);
+            assert!(first.unwrap().is_none());
+            assert!(reassembler.add(&addr(1), fragment, max_len, now).is_err());
+            assert_eq!(reassembler.held, 0);
+            assert_eq!(reassembler.incomplete.len(), 0);
+        }
+    }
+
+    #[test]
+    fn incomplete_datagrams_expire_and_make_room_for_new_ones() {
+        let mut reassembler = Reassembler::new();
+        let start = Instant::now();
+        for id in 0..MAX_INCOMPLETE as u32 + 1 {
+            let fragments = split(&[0; 100], id, 60).unwrap();
+            let now = start + Duration::from_millis(id as u64);
+            reassembler.add(&addr(1), &fragments[0], 4096, now).unwrap();
+        }
+        assert_eq!(reassembler.incomplete.len(), MAX_INCOMPLETE);
+        assert!(!reassembler.incomplete.contains_key(&(addr(1), 0)));
+        reassembler.expire(start + TIMEOUT + Duration::from_millis(100));
+        assert_eq!(reassembler.incomplete.len(), MAX_INCOMPLETE - 100);
+        reassembler.expire(start + TIMEOUT * 2);
+        assert_eq!(reassembler.held, 0);
+    }
+}
@@ -0,0 +1,602 @@
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
+use ring::digest::{self, SHA256};
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::cookie::{self, COOKIE_LEN};
+use crate::crypto::{self, CipherSuite, KEY_LEN, TAG_LEN};
+use crate::tunerror::Error;
+
+/// Message types. They share the first byte of a datagram with the IP version nibble of
+/// cleartext data packets, so they must never have 4 or 6 in their upper four bits.
+pub const HANDSHAKE_INITIATION: u8 = 1;
+pub const HANDSHAKE_RESPONSE: u8 = 2;
+
+const TIMESTAMP_LEN: usize = 12;
+pub const MAC_LEN: usize = 32;
+const MAC_LABEL: &[u8] = b"simple-vpn handshake mac";
+/// Hashed into the first chaining key, so that keys from this handshake can't be mistaken for
+/// keys from any other protocol.
+const PROTOCOL_NAME: &[u8] = b"Noise_IKpsk0_25519_ChaChaPoly_SHA256 simple-vpn";
+
+/// type(1) offered suites(1) preferred suite(1) reserved(1) sender index(4) ephemeral(32)
+/// encrypted static(32 + 16) encrypted timestamp(12 + 16) mac(32) cookie mac(32)
+pub const INITIATION_LEN: usize =
+    8 + KEY_LEN + (KEY_LEN + TAG_LEN) + (TIMESTAMP_LEN + TAG_LEN) + MAC_LEN * 2;
+/// type(1) chosen suite(1) reserved(2) sender index(4) receiver index(4) ephemeral(32)
+/// encrypted nothing(16) mac(32)
+pub const RESPONSE_LEN: usize = 12 + KEY_LEN + TAG_LEN + MAC_LEN;
+
+/// A TAI64N style timestamp: big endian seconds followed by nanoseconds, so later timestamps
+/// compare greater byte by byte.
+pub type Timestamp = [u8; TIMESTAMP_LEN];
+
+/// The long-term X25519 key pair a peer is known by. The handshake only completes with a peer
+/// that owns the private key of the public key it's known by.
+pub struct Identity {
+    private_key: StaticSecret,
+    public_key: [u8; KEY_LEN],
+}
+
+impl Identity {
+    pub fn from_private_key(private_key: &[u8; KEY_LEN]) -> Identity {
+        let private_key = StaticSecret::from(*private_key);
+        let public_key = PublicKey::from(&private_key).to_bytes();
+        Identity {
+            private_key,
+            public_key,
+        }
+    }
+
+    pub fn public_key(&self) -> &[u8; KEY_LEN] {
+        &self.public_key
+    }
+}
+
+/// Generates a new private key for an [`Identity`].
+pub fn generate_private_key() -> [u8; KEY_LEN] {
+    let mut private_key = [0; KEY_LEN];
+    SystemRandom::new().fill(&mut private_key).unwrap();
+    private_key
+}
+
+/// The keys and indexes of a session produced by a completed handshake.
+pub struct SessionKeys {
+    /// Index the other peer puts on packets sent to us.
+    pub local_index: u32,
+    /// Index we put on packets sent to the other peer.
+    pub remote_index: u32,
+    pub send_key: Vec<u8>,
+    pub recv_key: Vec<u8>,
+    pub suite: CipherSuite,
+}
+
+/// What both sides of a handshake build up as its messages go back and forth, as in the Noise
+/// framework. The chaining key takes in the pre-shared key and the result of every
+/// Diffie-Hellman, the hash takes in every byte of the messages, and the key encrypts the next
+/// part of a message with the hash as associated data.
+#[derive(Clone)]
+struct SymmetricState {
+    chaining_key: [u8; KEY_LEN],
+    hash: [u8; KEY_LEN],
+    key: [u8; KEY_LEN],
+}
+
+impl SymmetricState {
+    /// Starts a handshake with the responder whose static public key is `responder`, which the
+    /// initiator must know in advance.
+    fn new(responder: &[u8; KEY_LEN], psk: &[u8]) -> SymmetricState {
+        let chaining_key = hash(&[PROTOCOL_NAME]);
+        let mut state = SymmetricState {
+            chaining_key,
+            hash: chaining_key,
+            key: [0; KEY_LEN],
+        };
+        state.mix_hash(responder);
+        state.mix_key(psk);
+        state
+    }
+
+    fn mix_hash(&mut self, data: &[u8]) {
+        self.hash = hash(&[&self.hash, data]);
+    }
+
+    /// Mixes secret input into the chaining key and derives the next key from it.
+    fn mix_key(&mut self, input: &[u8]) {
+        let [chaining_key, key] = kdf(&self.chaining_key, input);
+        self.chaining_key = chaining_key;
+        self.key = key;
+    }
+
+    /// Mixes in an ephemeral public key, which is sent in the clear.
+    fn mix_ephemeral(&mut self, public_key: &[u8; KEY_LEN]) {
+        self.mix_hash(public_key);
+        self.mix_key(public_key);
+    }
+
+    /// Mixes in the Diffie-Hellman of a private and a public key. Fails if the public key has a
+    /// small order, since anyone would know the shared secret.
+    fn mix_dh(
+        &mut self,
+        private_key: &StaticSecret,
+        public_key: &[u8; KEY_LEN],
+    ) -> Result<(), Error> {
+        let shared_secret = private_key.diffie_hellman(&PublicKey::from(*public_key));
+        if !shared_secret.was_contributory() {
+            return Err(Error::Handshake("invalid public key".to_owned()));
+        }
+        self.mix_key(shared_secret.as_bytes());
+        Ok(())
+    }
+
+    /// Appends `payload` encrypted to `message`, and mixes in the ciphertext. Every key only
+    /// ever encrypts once, so the nonce is always 0.
+    fn encrypt_and_hash(&mut self, payload: &[u8], message: &mut Vec<u8>) {
+        let start = message.len();
+        message.extend_from_slice(payload);
+        // Sealing only fails for payloads far longer than these
+        let tag = self
+            .aead_key()
+            .seal_in_place_separate_tag(first_nonce(), Aad::from(self.hash), &mut message[start..])
+            .unwrap();
+        message.extend_from_slice(tag.as_ref());
+        self.mix_hash(&message[start..]);
+    }
+
+    /// Decrypts what [`SymmetricState::encrypt_and_hash`] encrypted, and mixes in the
+    /// ciphertext. Fails unless both sides mixed in the same keys and messages so far.
+    fn decrypt_and_hash(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut payload = ciphertext.to_vec();
+        let len = self
+            .aead_key()
+            .open_in_place(first_nonce(), Aad::from(self.hash), &mut payload)
+            .map_err(|_| Error::Handshake("could not decrypt handshake".to_owned()))?
+            .len();
+        payload.truncate(len);
+        self.mix_hash(ciphertext);
+        Ok(payload)
+    }
+
+    fn aead_key(&self) -> LessSafeKey {
+        LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, &self.key).unwrap())
+    }
+
+    /// Derives the keys of the session from the state at the end of the handshake.
+    fn split(&self, suite: CipherSuite) -> crypto::DirectionalKeys {
+        crypto::derive_session_keys(suite, &self.chaining_key, &self.hash)
+    }
+}
+
+fn first_nonce() -> Nonce {
+    Nonce::assume_unique_for_key([0; 12])
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; KEY_LEN] {
+    let mut context = digest::Context::new(&SHA256);
+    for part in parts {
+        context.update(part);
+    }
+    context.finish().as_ref().try_into().unwrap()
+}
+
+/// HKDF with the chaining key as salt, giving the next chaining key and a key.
+fn kdf(chaining_key: &[u8], input: &[u8]) -> [[u8; KEY_LEN]; 2] {
+    let prk = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, chaining_key), input);
+    let prk = hmac::Key::new(hmac::HMAC_SHA256, prk.as_ref());
+    let first = hmac::sign(&prk, &[1]);
+    let second = hmac::sign(&prk, &[first.as_ref(), &[2]].concat());
+    [
+        first.as_ref().try_into().unwrap(),
+        second.as_ref().try_into().unwrap(),
+    ]
+}
+
+/// What the initiator keeps between sending an initiation and receiving the response.
+pub struct PendingHandshake {
+    ephemeral: StaticSecret,
+    state: SymmetricState,
+    pub local_index: u32,
+    /// The pre-shared key MAC of the initiation.
+    mac1: [u8; MAC_LEN],
+    /// Bit set of the cipher suites offered in the initiation.
+    offered: u8,
+    pub sent_at: Instant,
+}
+
+impl PendingHandshake {
+    /// Returns the pre-shared key MAC of the initiation, which cookie replies are bound to.
+    pub fn mac1(&self) -> &[u8] {
+        &self.mac1
+    }
+}
+
+/// A received initiation whose pre-shared key MAC was verified and whose encrypted static key
+/// and timestamp were opened.
+pub struct Initiation {
+    pub sender_index: u32,
+    pub static_key: [u8; KEY_LEN],
+    pub timestamp: Timestamp,
+    ephemeral: [u8; KEY_LEN],
+    /// Bit set of the cipher suites the initiator supports.
+    offered: u8,
+    /// The suite the initiator would like to use.
+    preferred: u8,
+    state: SymmetricState,
+}
+
+impl Initiation {
+    /// Picks the suite for the session: the initiator's preferred suite if we allow it, else
+    /// the first of ours that the initiator offered.
+    fn choose_suite(&self, suites: &[CipherSuite]) -> Option<CipherSuite> {
+        suites
+            .iter()
+            .find(|suite| **suite as u8 == self.preferred)
+            .or_else(|| suites.iter().find(|suite| suite.in_mask(self.offered)))
+            .copied()
+    }
+}
+
+/// A received response whose pre-shared key MAC was verified. Whether it comes from the
+/// responder we expect is only known once [`consume_response`] opens it.
+pub struct Response {
+    pub sender_index: u32,
+    pub receiver_index: u32,
+    ephemeral: [u8; KEY_LEN],
+    suite: u8,
+    message: Vec<u8>,
+}
+
+fn now() -> Timestamp {
+    let elapsed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let mut timestamp = [0; TIMESTAMP_LEN];
+    timestamp[..8].copy_from_slice(&elapsed.as_secs().to_be_bytes());
+    timestamp[8..].copy_from_slice(&elapsed.subsec_nanos().to_be_bytes());
+    timestamp
+}
+
+fn mac_key(psk: &[u8]) -> hmac::Key {
+    let key = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, psk), MAC_LABEL);
+    hmac::Key::new(hmac::HMAC_SHA256, key.as_ref())
+}
+
+/// Appends a MAC keyed by the pre-shared key, which lets the receiver drop messages from peers
+/// that don't know it before doing any public key operation.
+fn append_mac(message: &mut Vec<u8>, psk: &[u8]) {
+    let tag = hmac::sign(&mac_key(psk), message);
+    message.extend_from_slice(tag.as_ref());
+}
+
+fn verify_mac(message: &[u8], psk: &[u8]) -> Result<(), Error> {
+    let (body, mac) = message.split_at(message.len() - MAC_LEN);
+    hmac::verify(&mac_key(psk), body, mac).map_err(|_| Error::Handshake("invalid mac".to_owned()))
+}
+
+fn generate_ephemeral(rng: &SystemRandom) -> Result<(StaticSecret, [u8; KEY_LEN]), Error> {
+    let mut private_key = [0; KEY_LEN];
+    rng.fill(&mut private_key)
+        .map_err(|_| Error::Handshake("could not generate ephemeral key".to_owned()))?;
+    let ephemeral = StaticSecret::from(private_key);
+    let public_key = PublicKey::from(&ephemeral).to_bytes();
+    Ok((ephemeral, public_key))
+}
+
+fn read_u32(buf: &[u8]) -> u32 {
+    u32::from_le_bytes(buf[..4].try_into().unwrap())
+}
+
+/// Builds the first handshake message, to the responder whose static public key is
+/// `responder`. The handshake follows Noise IK: the initiator sends a fresh ephemeral key, then
+/// its static key encrypted with a key only the responder can also derive, and a timestamp so
+/// that a captured initiation can't be replayed, encrypted with a key that also needs both
+/// static keys. It also offers the cipher suites it supports, the first one being its
+/// preference. The last MAC is keyed by the responder's cookie if we have one, and is zero
+/// otherwise.
+pub fn create_initiation(
+    identity: &Identity,
+    responder: &[u8; KEY_LEN],
+    psk: &[u8],
+    local_index: u32,
+    suites: &[CipherSuite],
+    cookie: Option<&[u8; COOKIE_LEN]>,
+    rng: &SystemRandom,
+) -> Result<(Vec<u8>, PendingHandshake), Error> {
+    let (ephemeral, ephemeral_public) = generate_ephemeral(rng)?;
+    let offered = CipherSuite::to_mask(suites);
+    let preferred = suites[0] as u8;
+    let mut state = SymmetricState::new(responder, psk);
+    let mut message = Vec::with_capacity(INITIATION_LEN);
+    message.extend_from_slice(&[HANDSHAKE_INITIATION, offered, preferred, 0]);
+    message.extend_from_slice(&local_index.to_le_bytes());
+    state.mix_hash(&message);
+    message.extend_from_slice(&ephemeral_public);
+    state.mix_ephemeral(&ephemeral_public);
+    state.mix_dh(&ephemeral, responder)?;
+    state.encrypt_and_hash(identity.public_key(), &mut message);
+    state.mix_dh(&identity.private_key, responder)?;
+    state.encrypt_and_hash(&now(), &mut message);
+    append_mac(&mut message, psk);
+    let mac1 = message[message.len() - MAC_LEN..].try_into().unwrap();
+    match cookie {
+        Some(cookie) => message.extend_from_slice(cookie::mac2(cookie, &message).as_ref()),
+        None => message.extend_from_slice(&[0; MAC_LEN]),
+    }
+    let pending = PendingHandshake {
+        ephemeral,
+        state,
+        local_index,
+        mac1,
+        offered,
+        sent_at: Instant::now(),
+    };
+    Ok((message, pending))
+}
+
+/// An initiation whose pre-shared key MAC was checked, and that can be opened.
+pub struct CheckedInitiation<'a>(&'a [u8]);
+
+/// Checks an initiation's pre-shared key MAC. This is cheap enough to do for every initiation,
+/// before deciding whether to do the expensive work of the handshake.
+pub fn check_initiation_mac<'a>(buf: &'a [u8], psk: &[u8]) -> Result<CheckedInitiation<'a>, Error> {
+    if buf.len() != INITIATION_LEN || buf[0] != HANDSHAKE_INITIATION {
+        return Err(Error::Handshake("malformed initiation".to_owned()));
+    }
+    verify_mac(&buf[..INITIATION_LEN - MAC_LEN], psk)?;
+    Ok(CheckedInitiation(buf))
+}
+
+/// Checks an initiation's pre-shared key MAC and opens it like [`open_initiation`].
+pub fn parse_initiation(identity: &Identity, buf: &[u8], psk: &[u8]) -> Result<Initiation, Error> {
+    open_initiation(identity, &check_initiation_mac(buf, psk)?, psk)
+}
+
+/// Opens the initiator's static key and timestamp of an initiation whose MAC was checked, with
+/// our static key. The caller decides whether the static key is one it takes initiations from.
+pub fn open_initiation(
+    identity: &Identity,
+    initiation: &CheckedInitiation,
+    psk: &[u8],
+) -> Result<Initiation, Error> {
+    let buf = initiation.0;
+    let mut state = SymmetricState::new(identity.public_key(), psk);
+    state.mix_hash(&buf[..8]);
+    let ephemeral: [u8; KEY_LEN] = buf[8..40].try_into().unwrap();
+    state.mix_ephemeral(&ephemeral);
+    state.mix_dh(&identity.private_key, &ephemeral)?;
+    let static_key: [u8; KEY_LEN] = state.decrypt_and_hash(&buf[40..88])?.try_into().unwrap();
+    state.mix_dh(&identity.private_key, &static_key)?;
+    let timestamp = state.decrypt_and_hash(&buf[88..116])?.try_into().unwrap();
+    Ok(Initiation {
+        sender_index: read_u32(&buf[4..]),
+        static_key,
+        timestamp,
+        ephemeral,
+        offered: buf[1],
+        preferred: buf[2],
+        state,
+    })
+}
+
+/// Builds the response to an opened initiation and derives the responder's session keys.
+/// `suites` are the cipher suites we allow, in order of preference. The response carries a
+/// fresh ephemeral key and encrypts nothing, with a key that needs both ephemeral keys and the
+/// initiator's static key, so opening it proves to the initiator that we own our static key.
+pub fn create_response(
+    psk: &[u8],
+    initiation: &Initiation,
+    local_index: u32,
+    suites: &[CipherSuite],
+    rng: &SystemRandom,
+) -> Result<(Vec<u8>, SessionKeys), Error> {
+    let Some(suite) = initiation.choose_suite(suites) else {
+        return Err(Error::NoCommonCipherSuite);
+    };
+    let (ephemeral, ephemeral_public) = generate_ephemeral(rng)?;
+    let mut state = initiation.state.clone();
+    let mut message = Vec::with_capacity(RESPONSE_LEN);
+    message.extend_from_slice(&[HANDSHAKE_RESPONSE, suite as u8, 0, 0]);
+    message.extend_from_slice(&local_index.to_le_bytes());
+    message.extend_from_slice(&initiation.sender_index.to_le_bytes());
+    state.mix_hash(&message);
+    message.extend_from_slice(&ephemeral_public);
+    state.mix_ephemeral(&ephemeral_public);
+    state.mix_dh(&ephemeral, &initiation.ephemeral)?;
+    state.mix_dh(&ephemeral, &initiation.static_key)?;
+    state.encrypt_and_hash(&[], &mut message);
+    append_mac(&mut message, psk);
+    let (send_key, recv_key) = state.split(suite).split(false);
+    let session = SessionKeys {
+        local_index,
+        remote_index: initiation.sender_index,
+        send_key,
+        recv_key,
+        suite,
+    };
+    Ok((message, session))
+}
+
+/// Checks a response's pre-shared key MAC. The caller uses the receiver index to find the
+/// matching [`PendingHandshake`].
+pub fn parse_response(buf: &[u8], psk: &[u8]) -> Result<Response, Error> {
+    if buf.len() != RESPONSE_LEN || buf[0] != HANDSHAKE_RESPONSE {
+        return Err(Error::Handshake("malformed response".to_owned()));
+    }
+    verify_mac(buf, psk)?;
+    Ok(Response {
+        sender_index: read_u32(&buf[4..]),
+        receiver_index: read_u32(&buf[8..]),
+        ephemeral: buf[12..44].try_into().unwrap(),
+        suite: buf[1],
+        message: buf.to_vec(),
+    })
+}
+
+/// Opens the response, which only the responder the initiation was for could have built, and
+/// derives the initiator's session keys with the cipher suite the responder chose.
+pub fn consume_response(
+    identity: &Identity,
+    pending: PendingHandshake,
+    response: &Response,
+) -> Result<SessionKeys, Error> {
+    let suite = CipherSuite::from_id(response.suite)
+        .filter(|suite| suite.in_mask(pending.offered))
+        .ok_or_else(|| Error::Handshake("responder chose a suite we didn't offer".to_owned()))?;
+    let mut state = pending.state;
+    state.mix_hash(&response.message[..12]);
+    state.mix_ephemeral(&response.ephemeral);
+    state.mix_dh(&pending.ephemeral, &response.ephemeral)?;
+    state.mix_dh(&identity.private_key, &response.ephemeral)?;
+    state.decrypt_and_hash(&response.message[44..44 + TAG_LEN])?;
+    let (send_key, recv_key) = state.split(suite).split(true);
+    Ok(SessionKeys {
+        local_index: pending.local_index,
+        remote_index: response.sender_index,
+        send_key,
+        recv_key,
+        suite,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PSK: &[u8] = b"pre-shared key";
+
+    fn identities() -> (Identity, Identity) {
+        (
+            Identity::from_private_key(&[1; KEY_LEN]),
+            Identity::from_private_key(&[2; KEY_LEN]),
+        )
+    }
+
+    #[test]
+    fn both_sides_derive_the_same_keys() {
+        let (initiator, responder) = identities();
+        let rng = SystemRandom::new();
+        let suites = [CipherSuite::ChaCha20Poly1305];
+        let (message, pending) = create_initiation(
+            &initiator,
+            responder.public_key(),
+            PSK,
+            1,
+            &suites,
+            None,
+            &rng,
+        )
+        .unwrap();
+        let initiation = parse_initiation(&responder, &message, PSK).unwrap();
+        assert_eq!(&initiation.static_key, initiator.public_key());
+        let (message, responder_keys) =
+            create_response(PSK, &initiation, 2, &suites, &rng).unwrap();
+        let response = parse_response(&message, PSK).unwrap();
+        let initiator_keys = consume_response(&initiator, pending, &response).unwrap();
+        assert_eq!(initiator_keys.send_key, responder_keys.recv_key);
+        assert_eq!(initiator_keys.recv_key, responder_keys.send_key);
+        assert_ne!(initiator_keys.send_key, initiator_keys.recv_key);
+    }
+
+    #[test]
+    fn static_keys_are_not_sent_in_the_clear() {
+        let (initiator, responder) = identities();
+        let rng = SystemRandom::new();
+        let suites = [CipherSuite::ChaCha20Poly1305];
+        let (message, _) = create_initiation(
+            &initiator,
+            responder.public_key(),
+            PSK,
+            1,
+            &suites,
+            None,
+            &rng,
+        )
+        .unwrap();
+        let initiation = parse_initiation(&responder, &message, PSK).unwrap();
+        let (response, _) = create_response(PSK, &initiation, 2, &suites, &rng).unwrap();
+        for key in [initiator.public_key(), responder.public_key()] {
+            assert!(!message.windows(KEY_LEN).any(|window| window == key));
+            assert!(!response.windows(KEY_LEN).any(|window| window == key));
+        }
+    }
+
+    #[test]
+    fn only_the_responder_can_open_the_initiation() {
+        let (initiator, responder) = identities();
+        let other = Identity::from_private_key(&[3; KEY_LEN]);
+        let rng = SystemRandom::new();
+        let suites = [CipherSuite::ChaCha20Poly1305];
+        let (message, _) = create_initiation(
+            &initiator,
+            responder.public_key(),
+            PSK,
+            1,
+            &suites,
+            None,
+            &rng,
+        )
+        .unwrap();
+        assert!(parse_initiation(&other, &message, PSK).is_err());
+    }
+
+    #[test]
+    fn low_order_keys_are_rejected() {
+        let (initiator, _) = identities();
+        let rng = SystemRandom::new();
+        let suites = [CipherSuite::ChaCha20Poly1305];
+        let result = create_initiation(&initiator, &[0; KEY_LEN], PSK, 1, &suites, None, &rng);
+        assert!(matches!(result, Err(Error::Handshake(_))));
+    }
+
+    /// Runs a handshake between peers allowing these suites, in order of preference, and
+    /// returns the suite each side ended up with.
+    fn negotiate(
+        initiator_suites: &[CipherSuite],
+        responder_suites: &[CipherSuite],
+    ) -> Result<(CipherSuite, CipherSuite), Error> {
+        let (initiator, responder) = identities();
+        let rng = SystemRandom::new();
+        let (message, pending) = create_initiation(
+            &initiator,
+            responder.public_key(),
+            PSK,
+            1,
+            initiator_suites,
+            None,
+            &rng,
+        )?;
+        let initiation = parse_initiation(&responder, &message, PSK)?;
+        let (message, responder_keys) =
+            create_response(PSK, &initiation, 2, responder_suites, &rng)?;
+        let response = parse_response(&message, PSK)?;
+        let initiator_keys = consume_response(&initiator, pending, &response)?;
+        Ok((initiator_keys.suite, responder_keys.suite))
+    }
+
+    #[test]
+    fn the_initiators_preferred_suite_is_used_if_the_responder_allows_it() {
+        let (aes, chacha) = (CipherSuite::Aes256Gcm, CipherSuite::ChaCha20Poly1305);
+        assert_eq!(
+            negotiate(&[aes, chacha], &[chacha, aes]).unwrap(),
+            (aes, aes)
+        );
+        assert_eq!(
+            negotiate(&[chacha, aes], &[aes, chacha]).unwrap(),
+            (chacha, chacha)
+        );
+        // Otherwise the responder picks the suite it prefers among the others offered
+        assert_eq!(
+            negotiate(&[aes, chacha], &[chacha]).unwrap(),
+            (chacha, chacha)
+        );
+    }
+
+    #[test]
+    fn handshakes_fail_without_a_common_suite() {
+        let result = negotiate(&[CipherSuite::Aes256Gcm], &[CipherSuite::ChaCha20Poly1305]);
+        assert!(matches!(result, Err(Error::NoCommonCipherSuite)));
+    }
+}
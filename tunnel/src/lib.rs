@@ -1,5 +1,19 @@
+pub mod allowed_ips;
+pub mod cli;
+pub mod cookie;
+pub mod crypto;
+pub mod datagram;
+pub mod fragment;
+pub mod handshake;
+pub mod mmsg;
 pub mod net;
 pub mod packet;
+pub mod padding;
+pub mod pool;
+pub mod rendezvous;
+pub mod replay;
 pub mod select;
+pub mod tcp;
 pub mod tun;
 pub mod tunerror;
+pub mod websocket;
@@ -0,0 +1,263 @@
+use std::io::{self, IoSlice};
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+
+use libc::{c_int, c_void};
+use socket2::{SockAddr, Socket};
+
+/// Number of datagrams a kernel splits one UDP GSO buffer into at most.
+pub const MAX_SEGMENTS: usize = 64;
+/// Longest UDP payload over both IPv4 and IPv6, which is the longest GSO buffer, and the longest
+/// buffer GRO can put received datagrams together into.
+pub const MAX_SEGMENTED_LEN: usize = u16::MAX as usize - 48;
+/// Room for one control message holding an `int`, kept aligned like a `cmsghdr`.
+type Control = [u64; 4];
+
+/// Number of messages sent or received with one system call at most.
+pub const MAX_MESSAGES: usize = 64;
+
+/// Datagrams to send with one message.
+#[derive(Clone, Copy, Default)]
+pub struct Transmit<'a> {
+    /// What to send, in pieces the kernel puts together.
+    pub contents: &'a [IoSlice<'a>],
+    /// Where to send them. `None` for a connected socket.
+    pub addr: Option<&'a SockAddr>,
+    /// With UDP GSO, the kernel splits `contents` into datagrams this long, the last one
+    /// possibly shorter. `None` sends `contents` as one datagram.
+    pub segment_len: Option<usize>,
+}
+
+impl Transmit<'_> {
+    pub fn len(&self) -> usize {
+        self.contents.iter().map(|slice| slice.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// A message [`recv`] received.
+#[derive(Clone, Copy)]
+pub struct Received {
+    pub len: usize,
+    addr: libc::sockaddr_storage,
+    addr_len: libc::socklen_t,
+    /// With UDP GRO, the length of the datagrams the message is made of, the last one possibly
+    /// shorter.
+    pub segment_len: Option<usize>,
+}
+
+impl Received {
+    /// Describes a message of `len` bytes from `addr`, received without GRO.
+    pub fn new(len: usize, addr: SockAddr) -> Received {
+        Received {
+            len,
+            addr_len: addr.len(),
+            addr: addr.as_storage(),
+            segment_len: None,
+        }
+    }
+
+    /// The sender of the message.
+    pub fn addr(&self) -> SockAddr {
+        unsafe { SockAddr::new(self.addr, self.addr_len) }
+    }
+}
+
+impl Default for Received {
+    fn default() -> Received {
+        Received {
+            len: 0,
+            addr: unsafe { mem::zeroed() },
+            addr_len: 0,
+            segment_len: None,
+        }
+    }
+}
+
+/// Returns whether the kernel can split a buffer into several datagrams with UDP GSO.
+pub fn supports_gso(socket: &Socket) -> bool {
+    let mut value: c_int = 0;
+    let mut len = mem::size_of::<c_int>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_UDP,
+            libc::UDP_SEGMENT,
+            (&mut value as *mut c_int).cast(),
+            &mut len,
+        )
+    };
+    result == 0
+}
+
+/// Asks the kernel to put datagrams received on the socket together with UDP GRO. Returns
+/// whether it will, which needs receive buffers of [`MAX_SEGMENTED_LEN`].
+pub fn enable_gro(socket: &Socket) -> bool {
+    let enable: c_int = 1;
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_UDP,
+            libc::UDP_GRO,
+            (&enable as *const c_int).cast(),
+            mem::size_of::<c_int>() as libc::socklen_t,
+        )
+    };
+    result == 0
+}
+
+/// Receives up to one message per buffer, and at most [`MAX_MESSAGES`], with a single
+/// `recvmmsg` call, without waiting if none is waiting. Describes the messages, which fill the
+/// first buffers in order, in `received`, and returns how many there are.
+pub fn recv<B: AsMut<[u8]>>(
+    socket: &Socket,
+    bufs: &mut [B],
+    received: &mut [Received],
+) -> io::Result<usize> {
+    let count = bufs.len().min(received.len()).min(MAX_MESSAGES);
+    // The header has private padding fields on some targets
+    let mut headers: [libc::mmsghdr; MAX_MESSAGES] = unsafe { mem::zeroed() };
+    let mut iovecs: [libc::iovec; MAX_MESSAGES] = unsafe { mem::zeroed() };
+    let mut controls = [Control::default(); MAX_MESSAGES];
+    for (i, buf) in bufs[..count].iter_mut().enumerate() {
+        let buf = buf.as_mut();
+        iovecs[i] = libc::iovec {
+            iov_base: buf.as_mut_ptr().cast(),
+            iov_len: buf.len(),
+        };
+        let header = &mut headers[i].msg_hdr;
+        header.msg_name = (&mut received[i].addr as *mut libc::sockaddr_storage).cast();
+        header.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as _;
+        header.msg_iov = &mut iovecs[i];
+        header.msg_iovlen = 1;
+        header.msg_control = controls[i].as_mut_ptr().cast();
+        header.msg_controllen = mem::size_of::<Control>() as _;
+    }
+    let count = unsafe {
+        libc::recvmmsg(
+            socket.as_raw_fd(),
+            headers.as_mut_ptr(),
+            count as _,
+            libc::MSG_DONTWAIT as _,
+            ptr::null_mut(),
+        )
+    };
+    if count < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let count = count as usize;
+    for (header, received) in headers[..count].iter().zip(received.iter_mut()) {
+        received.len = header.msg_len as usize;
+        received.addr_len = header.msg_hdr.msg_namelen;
+        received.segment_len = unsafe { gro_segment_len(&header.msg_hdr) };
+    }
+    Ok(count)
+}
+
+/// Finds the segment length UDP GRO left in the control messages of a received message.
+unsafe fn gro_segment_len(header: &libc::msghdr) -> Option<usize> {
+    let mut cmsg = libc::CMSG_FIRSTHDR(header);
+    while !cmsg.is_null() {
+        if (*cmsg).cmsg_level == libc::SOL_UDP && (*cmsg).cmsg_type == libc::UDP_GRO {
+            let segment_len = ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const c_int);
+            return Some(segment_len as usize);
+        }
+        cmsg = libc::CMSG_NXTHDR(header, cmsg);
+    }
+    None
+}
+
+/// Sends messages, at most [`MAX_MESSAGES`], with a single `sendmmsg` call. Returns how many
+/// were sent, which may be fewer than given. Fails only if the first one couldn't be sent.
+pub fn send(socket: &Socket, transmits: &[Transmit]) -> io::Result<usize> {
+    let count = transmits.len().min(MAX_MESSAGES);
+    let mut headers: [libc::mmsghdr; MAX_MESSAGES] = unsafe { mem::zeroed() };
+    let mut controls = [Control::default(); MAX_MESSAGES];
+    for (i, transmit) in transmits[..count].iter().enumerate() {
+        let header = &mut headers[i].msg_hdr;
+        if let Some(addr) = transmit.addr {
+            header.msg_name = addr.as_ptr().cast_mut().cast();
+            header.msg_namelen = addr.len();
+        }
+        // `IoSlice` is laid out like an `iovec`
+        header.msg_iov = transmit.contents.as_ptr().cast_mut().cast();
+        header.msg_iovlen = transmit.contents.len() as _;
+        if let Some(segment_len) = transmit.segment_len {
+            unsafe { set_segment_len(header, &mut controls[i], segment_len) };
+        }
+    }
+    let count = unsafe { libc::sendmmsg(socket.as_raw_fd(), headers.as_mut_ptr(), count as _, 0) };
+    if count < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(count as usize)
+}
+
+/// Adds the control message asking UDP GSO to split a message into datagrams of `segment_len`.
+unsafe fn set_segment_len(header: &mut libc::msghdr, control: &mut Control, segment_len: usize) {
+    header.msg_control = control.as_mut_ptr().cast::<c_void>();
+    header.msg_controllen = libc::CMSG_SPACE(mem::size_of::<u16>() as _) as _;
+    let cmsg = libc::CMSG_FIRSTHDR(header);
+    (*cmsg).cmsg_level = libc::SOL_UDP;
+    (*cmsg).cmsg_type = libc::UDP_SEGMENT;
+    (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<u16>() as _) as _;
+    ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut u16, segment_len as u16);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use socket2::{Domain, Protocol, Type};
+
+    use super::*;
+
+    fn bound_socket() -> (Socket, SockAddr) {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)).unwrap();
+        socket
+            .bind(&SocketAddr::from((Ipv4Addr::LOCALHOST, 0)).into())
+            .unwrap();
+        let addr = socket.local_addr().unwrap();
+        (socket, addr)
+    }
+
+    #[test]
+    fn gso_buffers_arrive_as_datagrams_or_together_with_gro() {
+        let (sender, _) = bound_socket();
+        if !supports_gso(&sender) {
+            return;
+        }
+        let contents: Vec<u8> = (0..250).map(|i| i as u8).collect();
+        let (plain, plain_addr) = bound_socket();
+        let (gro, gro_addr) = bound_socket();
+        assert!(enable_gro(&gro));
+        for addr in [&plain_addr, &gro_addr] {
+            let transmit = Transmit {
+                contents: &[
+                    IoSlice::new(&contents[..120]),
+                    IoSlice::new(&contents[120..]),
+                ],
+                addr: Some(addr),
+                segment_len: Some(100),
+            };
+            assert_eq!(send(&sender, &[transmit]).unwrap(), 1);
+        }
+        let mut bufs = vec![[0; 1000]; 4];
+        let mut received = [Received::default(); 4];
+        assert_eq!(recv(&plain, &mut bufs, &mut received).unwrap(), 3);
+        let lens: Vec<usize> = received[..3].iter().map(|received| received.len).collect();
+        assert_eq!(lens, [100, 100, 50]);
+        assert!(received[..3]
+            .iter()
+            .all(|received| received.segment_len.is_none()));
+        assert_eq!(received[0].addr(), sender.local_addr().unwrap());
+        assert_eq!(bufs[2][..50], contents[200..]);
+        assert_eq!(recv(&gro, &mut bufs, &mut received).unwrap(), 1);
+        assert_eq!((received[0].len, received[0].segment_len), (250, Some(100)));
+        assert_eq!(bufs[0][..250], contents);
+    }
+}
@@ -1,250 +1,3826 @@
-use std::collections::HashMap;
-use std::mem::MaybeUninit;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::array;
+use std::collections::{HashMap, VecDeque};
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::num::NonZeroU32;
+use std::ops::Range;
+use std::time::{Duration, Instant};
 use std::vec;
 use std::{
-    io,
-    os::fd::{AsRawFd, RawFd},
+    io::{self, IoSlice},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
 };
 
-use etherparse::{Ipv4HeaderSlice, Ipv6HeaderSlice};
 use ring::aead::Aad;
-use ring::aead::BoundKey;
+use ring::aead::LessSafeKey;
 use ring::aead::Nonce;
-use ring::aead::NonceSequence;
-use ring::aead::OpeningKey;
-use ring::aead::SealingKey;
 use ring::aead::UnboundKey;
-use ring::aead::AES_256_GCM;
 use ring::aead::NONCE_LEN;
 use ring::error::Unspecified;
+use ring::rand::{SecureRandom, SystemRandom};
 use socket2::{Domain, Protocol, SockAddr, Socket, Type};
 
+use crate::allowed_ips::{AllowedIps, Cidr};
+use crate::cookie::{self, Admission, Cookie, CookieChecker};
+use crate::crypto::{self, CipherSuite, KEY_LEN, TAG_LEN};
+use crate::datagram::DatagramSocket;
+use crate::fragment::{self, Reassembler};
+use crate::handshake::{self, Identity, PendingHandshake, SessionKeys, Timestamp};
+use crate::mmsg;
 use crate::packet;
+use crate::padding::{self, Padding};
+use crate::pool::{BufferPool, PacketBuf};
+use crate::rendezvous::{self, Message, RENDEZVOUS};
+use crate::replay::{self, ReplayWindow};
+use crate::tcp::{Connection, Proxy, StreamConfig};
 use crate::tunerror;
+const IPV4_HEADER_LEN: usize = 20;
 const IPV6_HEADER_LEN: usize = 40;
+/// Room taken in the path MTU by the IP and UDP headers of our datagrams, counting the longer
+/// IPv6 header.
+const UDP_OVERHEAD: usize = IPV6_HEADER_LEN + 8;
+/// Smallest path MTU every IPv4 host must handle.
+const MIN_PATH_MTU: usize = 576;
+/// Size of the buffer datagrams are received into. Packets that would make a longer datagram
+/// once sealed are dropped, and datagrams put back together from fragments can't be longer.
+pub(crate) const MAX_DATAGRAM_LEN: usize = 4096;
+/// Size of the receiver's session index carried at the end of every sealed data packet.
+const INDEX_LEN: usize = 4;
+/// Size of the send counter carried at the end of every sealed data packet.
+const COUNTER_LEN: usize = 8;
+/// Message type of data packets sealed whole, behind a header made of the type, 3 reserved
+/// bytes, the receiver's session index and the counter. The type shares the first byte with the
+/// IP version of cleartext header packets and handshake messages.
+const TRANSPORT_DATA: u8 = 4;
+/// Message type of keepalives, which are sealed like fully encapsulated data packets but carry
+/// nothing. They keep NAT mappings between idle peers open. Without encryption a keepalive is
+/// just this byte.
+const KEEPALIVE: u8 = 5;
+/// First byte of what cover packets seal. They're sent as fully encapsulated data packets, so
+/// they can't be told apart from them on the network, but hold zeros instead of an IP packet.
+const COVER: u8 = 0;
+/// Size of the header in front of fully encapsulated data packets.
+const DATA_HEADER_LEN: usize = 4 + INDEX_LEN + COUNTER_LEN;
+/// Length of the associated data an IPv6 header gives, the longest.
+const MAX_AAD_LEN: usize = 1 + 3 + 32;
+/// How long an initiator waits for a response before sending a new initiation.
+const REKEY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Number of packets kept for a peer while its handshake is in progress.
+const MAX_QUEUED_PACKETS: usize = 32;
+/// Number of static keys whose newest initiation is remembered after their peer is forgotten.
+const MAX_REMEMBERED_INITIATORS: usize = 1 << 16;
+/// Number of events kept until they're taken with [`Net::next_event`]. Older ones are dropped.
+const MAX_QUEUED_EVENTS: usize = 64;
+/// A session never seals more packets than this, so its counters can't wrap around.
+const REJECT_AFTER_PACKETS: u64 = u64::MAX - replay::WINDOW_SIZE - 1;
+/// Default number of packets after which a session is replaced.
+pub const DEFAULT_REKEY_AFTER_PACKETS: u64 = 1 << 60;
+/// Default age after which a session is replaced.
+pub const DEFAULT_REKEY_AFTER_TIME: Duration = Duration::from_secs(120);
+/// Default time after which a silent peer is forgotten.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+/// Default number of peers a server keeps track of.
+pub const DEFAULT_MAX_PEERS: usize = 1024;
+/// Default MTU of the path between peers, that of Ethernet.
+pub const DEFAULT_PATH_MTU: usize = 1500;
+/// How often [`Net::tick`] should be called.
+pub const TICK_INTERVAL: Duration = Duration::from_secs(1);
+/// Number of datagrams [`Net::recv_batch`] and [`Net::send_batch`] move with one system call.
+pub const BATCH_SIZE: usize = 32;
+/// Token of a TCP client's connection to its server, which takes the place of a UDP client's
+/// only socket in the server's endpoint.
+const SERVER_CONNECTION: usize = 0;
+/// How long a TCP client first waits before connecting to its server again. The wait doubles
+/// after every connection lost without a frame received, up to [`MAX_RECONNECT_DELAY`].
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(32);
 
-struct CounterNonceSequence(u32);
+/// Builds the nonce for a single packet from its counter. The counter fills the last 8 bytes of
+/// the 12 byte nonce, so no two packets sealed under a key share a nonce.
+fn counter_nonce(counter: u64) -> Nonce {
+    let mut nonce_bytes = [0; NONCE_LEN];
+    nonce_bytes[NONCE_LEN - COUNTER_LEN..].copy_from_slice(&counter.to_be_bytes());
+    Nonce::assume_unique_for_key(nonce_bytes)
+}
+
+/// Keys and counters agreed on by a completed handshake.
+struct Session {
+    /// Index the peer puts on the packets it sends us.
+    local_index: u32,
+    /// Index we put on the packets we send the peer.
+    remote_index: u32,
+    /// Keys prepared for the session's cipher suite once, instead of for every packet.
+    send_key: LessSafeKey,
+    recv_key: LessSafeKey,
+    /// Counter of the next packet sealed with `send_key`, from which its nonce is built. It
+    /// only ever increases. It starts at 0 in every session, which never repeats a nonce under a
+    /// key: session keys come from the fresh ephemeral keys of a handshake, so no two sessions,
+    /// of the same peer or of different ones, share a key.
+    send_counter: u64,
+    /// Counters already received from this peer.
+    replay_window: ReplayWindow,
+    created: Instant,
+    /// Whether we sent the initiation of the handshake that created this session. Only that
+    /// side replaces the session when it gets old, so both sides don't start handshakes at once.
+    is_initiator: bool,
+}
+
+impl Session {
+    fn new(keys: SessionKeys, is_initiator: bool) -> Session {
+        Session {
+            local_index: keys.local_index,
+            remote_index: keys.remote_index,
+            send_key: Self::prepare_key(keys.suite, &keys.send_key),
+            recv_key: Self::prepare_key(keys.suite, &keys.recv_key),
+            send_counter: 0,
+            replay_window: ReplayWindow::new(),
+            created: Instant::now(),
+            is_initiator,
+        }
+    }
+
+    fn prepare_key(suite: CipherSuite, key: &[u8]) -> LessSafeKey {
+        let key = UnboundKey::new(suite.algorithm(), key).expect("session keys fit their suite");
+        LessSafeKey::new(key)
+    }
+
+    fn next_counter(&mut self) -> u64 {
+        let counter = self.send_counter;
+        self.send_counter += 1;
+        counter
+    }
+}
+
+/// When sessions get replaced by a new handshake.
+#[derive(Clone, Copy)]
+struct RekeyPolicy {
+    after_packets: u64,
+    after_time: Duration,
+}
+
+impl RekeyPolicy {
+    /// Returns whether the initiator of a session should replace it. `recv_counter` is the
+    /// counter of the last packet received on it, so that an initiator that only receives still
+    /// rekeys when the other side sends a lot.
+    fn needs_rekey(&self, session: &Session, recv_counter: u64) -> bool {
+        session.is_initiator
+            && (session.send_counter >= self.after_packets
+                || recv_counter >= self.after_packets
+                || session.created.elapsed() >= self.after_time)
+    }
+
+    /// Returns whether a session is too old to be used at all. It's given half of the rekey
+    /// time again to be replaced. Either side starts a handshake when it has packets to send and
+    /// its session expired.
+    fn is_expired(&self, session: &Session) -> bool {
+        session.send_counter >= REJECT_AFTER_PACKETS
+            || session.created.elapsed() >= self.after_time + self.after_time / 2
+    }
+}
+
+type PeerId = u32;
+
+/// Where a peer is reached: its address and the socket its packets arrive on. Replies go out
+/// through the same socket, so they come from the address the peer sent to. With TCP, `socket`
+/// is the token of the peer's connection.
+#[derive(Clone)]
+struct Endpoint {
+    addr: SockAddr,
+    socket: usize,
+}
+
+/// State kept for every remote peer we exchange packets with.
+#[derive(Default)]
+struct Peer {
+    /// The peer's static public key. For a client's server this is the expected key, if one
+    /// was configured.
+    public_key: Option<[u8; KEY_LEN]>,
+    endpoint: Option<Endpoint>,
+    /// The session packets are sent with.
+    session: Option<Session>,
+    /// The session replaced by the last handshake. It's only used to open packets that were
+    /// already on their way when the keys changed.
+    previous_session: Option<Session>,
+    /// Our initiation waiting for the peer's response.
+    handshake: Option<PendingHandshake>,
+    /// Cookie the peer sent us while under load, echoed in our next initiations.
+    cookie: Option<Cookie>,
+    /// When the last authenticated packet was received from this peer.
+    last_seen: Option<Instant>,
+    /// When we last sent a packet to this peer.
+    last_sent: Option<Instant>,
+    /// Initiations sent to punch a hole to this peer while no session with it exists. Only used
+    /// by clients, for peers the server introduced them to.
+    punch_attempts: Option<u32>,
+    /// Whether the peer comes from the configuration rather than from a received packet.
+    /// Configured peers are reset instead of removed when they go idle.
+    configured: bool,
+    /// Packets waiting for the handshake to complete.
+    queue: VecDeque<Vec<u8>>,
+}
+
+impl Peer {
+    /// Returns the current or previous session with this local index.
+    fn session_mut(&mut self, index: u32) -> Option<&mut Session> {
+        [&mut self.session, &mut self.previous_session]
+            .into_iter()
+            .flatten()
+            .find(|session| session.local_index == index)
+    }
+
+    /// Returns whether an initiation may be sent, which is when none is waiting for a response
+    /// or the last one timed out.
+    fn can_initiate(&self) -> bool {
+        match &self.handshake {
+            Some(pending) => pending.sent_at.elapsed() >= REKEY_TIMEOUT,
+            None => true,
+        }
+    }
+}
+
+/// A packet sealed for a peer and not sent yet.
+struct Sealed {
+    /// Where the datagram is in the buffer the packet was sealed in.
+    datagram: Range<usize>,
+    endpoint: Endpoint,
+    peer_id: PeerId,
+    needs_rekey: bool,
+    /// The source and destination of a packet relayed by the server, to ask for a direct path.
+    relayed: Option<(IpAddr, IpAddr)>,
+}
+
+/// A UDP datagram held while batching.
+enum Datagram {
+    /// Sealed in place in the buffer at this index of those given to [`Net::send_batch`].
+    Sealed(usize),
+    Copied(Vec<u8>),
+}
+
+impl Datagram {
+    fn contents<'a>(&'a self, packets: &'a [PacketBuf]) -> &'a [u8] {
+        match self {
+            Datagram::Sealed(i) => packets[*i].packet(),
+            Datagram::Copied(datagram) => datagram,
+        }
+    }
+}
+
+/// How data packets are laid out on the network.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Encapsulation {
+    /// The inner IP header stays in cleartext and only its payload is encrypted.
+    #[default]
+    Header,
+    /// The whole inner packet is encrypted behind a small header, so the virtual addresses,
+    /// protocol and length of the inner packet aren't visible on the network.
+    Full,
+}
+
+impl Encapsulation {
+    pub fn from_name(name: &str) -> Option<Encapsulation> {
+        match name {
+            "header" => Some(Encapsulation::Header),
+            "full" => Some(Encapsulation::Full),
+            _ => None,
+        }
+    }
+}
+
+/// How packets travel between peers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Transport {
+    /// Every packet is a UDP datagram.
+    #[default]
+    Udp,
+    /// Packets are sent over a TCP connection, each behind its length, for networks that drop
+    /// UDP. A client connects again when its connection is lost.
+    Tcp,
+    /// Packets are sent over a TCP connection as binary WebSocket messages, so they pass
+    /// through HTTP proxies and web servers that forward WebSocket upgrades.
+    WebSocket,
+}
+
+impl Transport {
+    pub fn from_name(name: &str) -> Option<Transport> {
+        match name {
+            "udp" => Some(Transport::Udp),
+            "tcp" => Some(Transport::Tcp),
+            "websocket" => Some(Transport::WebSocket),
+            _ => None,
+        }
+    }
+}
+
+/// Something that happened to a peer, reported by [`Net::next_event`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// An authenticated packet arrived from a new address of the peer, and packets for it are
+    /// now sent there. `old` is `None` the first time the peer is heard from.
+    EndpointChanged {
+        public_key: [u8; KEY_LEN],
+        old: Option<SocketAddr>,
+        new: SocketAddr,
+    },
+}
+
+/// What a server does when a new peer shows up while it already has `max_peers` peers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PeerLimitPolicy {
+    /// The new peer is turned away.
+    #[default]
+    Refuse,
+    /// The peer heard from least recently is forgotten to make room. Configured peers are never
+    /// evicted.
+    EvictIdlest,
+}
+
+impl PeerLimitPolicy {
+    pub fn from_name(name: &str) -> Option<PeerLimitPolicy> {
+        match name {
+            "refuse" => Some(PeerLimitPolicy::Refuse),
+            "evict" => Some(PeerLimitPolicy::EvictIdlest),
+            _ => None,
+        }
+    }
+}
+
+/// A client known to the server in advance.
+#[derive(Clone, Debug)]
+pub struct PeerConfig {
+    pub public_key: [u8; KEY_LEN],
+    /// Prefixes the client may use as the source of its packets. Packets read from the tunnel
+    /// are sent to the client with the longest prefix containing their destination.
+    pub allowed_ips: Vec<Cidr>,
+}
+
+/// Options used to set up a [`Net`].
+pub struct Config {
+    /// Address and port of the server. Only used by clients.
+    pub remote_addr: String,
+    /// Port the server listens on.
+    pub port: u16,
+    /// Addresses the server listens on. If it's empty, the server listens on `port` on every
+    /// IPv6 and IPv4 address.
+    pub listen_addrs: Vec<SocketAddr>,
+    pub is_client: bool,
+    /// Protocol packets are sent over. Clients and their server must use the same one.
+    pub transport: Transport,
+    /// Path of the WebSocket upgrade requests clients send and the server accepts. Only used
+    /// with [`Transport::WebSocket`].
+    pub websocket_path: String,
+    /// HTTP proxy a client connects to its server through. Needs the TCP or WebSocket
+    /// transport.
+    pub proxy: Option<Proxy>,
+    /// Password the pre-shared key is derived from. Handshakes only succeed between peers with
+    /// the same password.
+    pub key: String,
+    /// Salt for the password key derivation. Both peers must use the same salt.
+    pub salt: String,
+    /// PBKDF2 iteration count for the password key derivation.
+    pub iterations: u32,
+    /// Private key identifying this peer. A random one is used if it isn't set.
+    pub private_key: Option<[u8; KEY_LEN]>,
+    /// Public key of the server. The client encrypts its handshake to it, so only the server
+    /// that owns its private key can answer. Clients need it when packets are encrypted.
+    pub peer_key: Option<[u8; KEY_LEN]>,
+    /// The clients allowed to connect. Any client that knows the password may connect if it's
+    /// empty, and the addresses it uses are learned from its packets, each belonging to the
+    /// first client that used it until that client expires. Only used by servers.
+    pub peers: Vec<PeerConfig>,
+    /// Number of packets sent or received on a session before it's replaced by a new handshake.
+    pub rekey_after_packets: u64,
+    /// Age of a session after which it's replaced by a new handshake.
+    pub rekey_after_time: Duration,
+    /// Number of handshake initiations per second above which initiators must echo a cookie
+    /// before a handshake is done for them. 0 always requires a cookie.
+    pub handshake_load_threshold: u32,
+    /// Number of handshake initiations per second accepted from one address while under load.
+    /// 0 means no limit.
+    pub handshake_rate_limit: u32,
+    /// How long a peer may go without sending an authenticated packet before the server forgets
+    /// its sessions and address. Zero keeps peers forever.
+    pub idle_timeout: Duration,
+    /// Maximum number of peers a server keeps track of, configured ones included.
+    pub max_peers: usize,
+    pub peer_limit_policy: PeerLimitPolicy,
+    /// A keepalive is sent to a peer nothing was sent to for this long. Zero sends none.
+    pub keepalive_interval: Duration,
+    /// Clients ask the server to introduce them to the clients they send packets to, and send
+    /// them packets directly once a path through their NATs is open. A server only introduces
+    /// clients to each other if it's set. Needs encryption and UDP.
+    pub peer_to_peer: bool,
+    /// Cipher suites this peer accepts, in order of preference.
+    pub cipher_suites: Vec<CipherSuite>,
+    /// Layout of the data packets we send. Packets in either layout are accepted.
+    pub encapsulation: Encapsulation,
+    /// How the data packets we send are padded. Needs encryption and full encapsulation.
+    /// Padded packets are accepted whatever this is.
+    pub padding: Padding,
+    /// Number of cover packets sent to every peer with a session each second. Zero sends none.
+    /// Needs encryption and full encapsulation.
+    pub cover_rate: u32,
+    /// MTU of the path between peers. UDP datagrams that wouldn't fit are split into fragments,
+    /// which the other peer puts back together.
+    pub path_mtu: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            remote_addr: String::new(),
+            port: 2000,
+            listen_addrs: vec![],
+            is_client: false,
+            transport: Transport::Udp,
+            websocket_path: "/".to_owned(),
+            proxy: None,
+            key: String::new(),
+            salt: crypto::DEFAULT_SALT.to_owned(),
+            iterations: crypto::DEFAULT_ITERATIONS,
+            private_key: None,
+            peer_key: None,
+            peers: vec![],
+            rekey_after_packets: DEFAULT_REKEY_AFTER_PACKETS,
+            rekey_after_time: DEFAULT_REKEY_AFTER_TIME,
+            handshake_load_threshold: cookie::DEFAULT_LOAD_THRESHOLD,
+            handshake_rate_limit: cookie::DEFAULT_RATE_LIMIT,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            max_peers: DEFAULT_MAX_PEERS,
+            peer_limit_policy: PeerLimitPolicy::Refuse,
+            keepalive_interval: Duration::ZERO,
+            peer_to_peer: false,
+            cipher_suites: CipherSuite::ALL.to_vec(),
+            encapsulation: Encapsulation::Header,
+            padding: Padding::None,
+            cover_rate: 0,
+            path_mtu: DEFAULT_PATH_MTU,
+        }
+    }
+}
+
+impl Config {
+    /// Packets are only encrypted if a password or a private key is set.
+    fn is_encrypted(&self) -> bool {
+        !self.key.is_empty() || self.private_key.is_some()
+    }
+}
 
-impl NonceSequence for CounterNonceSequence {
-    fn advance(&mut self) -> Result<Nonce, Unspecified> {
-        let mut nonce_bytes = vec![0; NONCE_LEN];
+/// Timestamp of the newest initiation accepted from every static key, kept after the peer is
+/// forgotten so that a recorded initiation can't start a session again later. When too many
+/// keys are remembered the oldest are forgotten, and initiations from unknown keys must then be
+/// newer than any of those were.
+#[derive(Default)]
+struct Initiations {
+    newest: HashMap<[u8; KEY_LEN], Timestamp>,
+    forgotten_up_to: Timestamp,
+}
+
+impl Initiations {
+    fn is_replayed(&self, static_key: &[u8; KEY_LEN], timestamp: &Timestamp) -> bool {
+        let newest = self.newest.get(static_key).unwrap_or(&self.forgotten_up_to);
+        timestamp <= newest
+    }
 
-        let bytes = self.0.to_be_bytes();
-        nonce_bytes[8..].copy_from_slice(&bytes);
-        self.0 += 1;
-        Nonce::try_assume_unique_for_key(&nonce_bytes)
+    fn accept(&mut self, static_key: [u8; KEY_LEN], timestamp: Timestamp) {
+        if self.newest.len() >= MAX_REMEMBERED_INITIATORS && !self.newest.contains_key(&static_key)
+        {
+            let (oldest, timestamp) = self
+                .newest
+                .iter()
+                .min_by_key(|(_, timestamp)| **timestamp)
+                .map(|(key, timestamp)| (*key, *timestamp))
+                .unwrap();
+            self.newest.remove(&oldest);
+            self.forgotten_up_to = self.forgotten_up_to.max(timestamp);
+        }
+        self.newest.insert(static_key, timestamp);
     }
 }
 
 pub struct Net {
-    fd: RawFd,
-    pub socket: Socket,
-    ip_map: Option<HashMap<IpAddr, SockAddr>>,
-    key: Vec<u8>,
+    /// The client's socket, or the sockets of the server's listen addresses. Only used with UDP.
+    sockets: Vec<Box<dyn DatagramSocket>>,
+    /// The server's listeners with TCP. A TCP client has none.
+    listeners: Vec<Socket>,
+    /// Epoll instance watching every socket and connection, when there's more than one socket
+    /// or the transport is TCP.
+    epoll: Option<OwnedFd>,
+    is_client: bool,
+    transport: Transport,
+    /// How packets are carried over TCP connections.
+    stream: StreamConfig,
+    /// TCP connections by their epoll token. A server's connections come after its listeners.
+    connections: HashMap<usize, Connection>,
+    next_token: usize,
+    /// When a TCP client that lost its connection connects again, and how long it waits after
+    /// the next loss.
+    reconnect_at: Option<Instant>,
+    reconnect_delay: Duration,
+    /// The peer a client was started with.
+    server: Option<PeerId>,
+    peer_to_peer: bool,
+    /// Addresses a client asked the server to introduce it to, and when.
+    requested: HashMap<IpAddr, Instant>,
+    /// Maps virtual IP prefixes to the peer that owns them. A client only has the prefixes of
+    /// the peers it talks to directly.
+    allowed_ips: AllowedIps<PeerId>,
+    /// Whether only the configured peers may connect, from their allowed prefixes. Otherwise
+    /// any peer may connect and the addresses it uses are learned from its packets.
+    static_peers: bool,
+    peers: HashMap<PeerId, Peer>,
+    next_peer_id: PeerId,
+    /// Peers by static public key.
+    peer_ids: HashMap<[u8; KEY_LEN], PeerId>,
+    /// Peers by endpoint. Only used when packets aren't encrypted.
+    endpoints: HashMap<SockAddr, PeerId>,
+    /// Peers by the local index of their session or pending handshake.
+    indexes: HashMap<u32, PeerId>,
+    /// Our static key pair. `None` if packets aren't encrypted.
+    identity: Option<Identity>,
+    initiations: Initiations,
+    psk: [u8; KEY_LEN],
+    rekey: RekeyPolicy,
+    idle_timeout: Duration,
+    max_peers: usize,
+    peer_limit_policy: PeerLimitPolicy,
+    keepalive_interval: Duration,
+    /// When [`Net::tick`] last did its work.
+    last_tick: Instant,
+    cookies: CookieChecker,
+    cipher_suites: Vec<CipherSuite>,
+    encapsulation: Encapsulation,
+    padding: Padding,
+    cover_rate: u32,
+    /// When the next cover packets are due.
+    next_cover: Instant,
+    /// Longest UDP datagram sent whole. Longer ones are split into fragments.
+    max_datagram_len: usize,
+    /// Longest a fully encapsulated packet is padded to, so its datagram still goes whole.
+    max_padded_len: usize,
+    reassembler: Reassembler,
+    next_fragment_id: u32,
+    /// Buffers [`Net::recv_batch`] receives into, allocated on its first call.
+    recv_bufs: BufferPool,
+    /// Whether the kernel splits buffers into UDP datagrams of equal length for us (GSO).
+    gso: bool,
+    /// Whether the kernel puts received UDP datagrams together (GRO). Turned on, where it can
+    /// be, by the first call to [`Net::recv_batch`].
+    gro: Option<bool>,
+    /// Whether UDP datagrams are held in `outgoing` until [`Net::send_batch`] writes them all.
+    batching: bool,
+    outgoing: Vec<(Datagram, Endpoint)>,
+    rng: SystemRandom,
+    /// Number of packets dropped because they were replayed or fell behind the replay window.
+    replayed_packets: u64,
+    events: VecDeque<Event>,
 }
 
 impl AsRawFd for Net {
+    /// Returns a descriptor that's readable when a packet can be received.
     fn as_raw_fd(&self) -> RawFd {
-        self.fd
+        match &self.epoll {
+            Some(epoll) => epoll.as_raw_fd(),
+            None => self.sockets[0].as_raw_fd(),
+        }
+    }
+}
+
+/// Returns the time between cover packets sent `cover_rate` times a second.
+fn cover_interval(cover_rate: u32) -> Duration {
+    Duration::from_secs(1) / cover_rate.max(1)
+}
+
+/// Converts a peer address to a `SocketAddr`, showing IPv4 peers of a dual-stack socket as
+/// plain IPv4 addresses.
+fn socket_addr(addr: &SockAddr) -> Option<SocketAddr> {
+    let addr = addr.as_socket()?;
+    Some(SocketAddr::new(addr.ip().to_canonical(), addr.port()))
+}
+
+fn udp_socket(addr: &SocketAddr) -> Result<Socket, tunerror::Error> {
+    let socket = Socket::new(Domain::for_address(*addr), Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    Ok(socket)
+}
+
+/// Creates a TCP socket that accepts connections without blocking.
+fn tcp_listener(addr: &SocketAddr) -> Result<Socket, tunerror::Error> {
+    let socket = Socket::new(
+        Domain::for_address(*addr),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+/// Creates the server's sockets, or its listeners with TCP. Without listen addresses, a single
+/// IPv6 socket accepting IPv4 too is used, or an IPv4 socket if the host has no IPv6.
+fn bind_sockets(config: &Config) -> Result<Vec<Socket>, tunerror::Error> {
+    let bind = |addr: SocketAddr, only_v6: bool| {
+        let socket = match config.transport {
+            Transport::Udp => udp_socket(&addr)?,
+            Transport::Tcp | Transport::WebSocket => tcp_listener(&addr)?,
+        };
+        if addr.is_ipv6() {
+            socket.set_only_v6(only_v6)?;
+        }
+        socket
+            .bind(&addr.into())
+            .map_err(|e| tunerror::Error::Bind(format!("{addr}: {e}")))?;
+        if config.transport != Transport::Udp {
+            socket.listen(128)?;
+        }
+        Ok(socket)
+    };
+    if !config.listen_addrs.is_empty() {
+        return config
+            .listen_addrs
+            .iter()
+            .map(|addr| bind(*addr, true))
+            .collect();
+    }
+    let dual_stack = SocketAddr::from((Ipv6Addr::UNSPECIFIED, config.port));
+    bind(dual_stack, false)
+        .or_else(|_| {
+            bind(
+                SocketAddr::from((Ipv4Addr::UNSPECIFIED, config.port)),
+                false,
+            )
+        })
+        .map(|socket| vec![socket])
+}
+
+/// Creates an epoll instance reporting which of the sockets are readable.
+fn watch_sockets(sockets: &[RawFd]) -> Result<OwnedFd, tunerror::Error> {
+    let fd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+    if fd < 0 {
+        return Err(tunerror::Error::EventQueue(io::Error::last_os_error()));
+    }
+    let epoll = unsafe { OwnedFd::from_raw_fd(fd) };
+    for (i, socket) in sockets.iter().enumerate() {
+        watch(&epoll, libc::EPOLL_CTL_ADD, *socket, i, false)?;
+    }
+    Ok(epoll)
+}
+
+/// Adds a descriptor to an epoll instance under `token`, or changes what's reported for it,
+/// depending on `op`. It's always reported when readable, and also when writable if `writes`.
+fn watch(
+    epoll: &OwnedFd,
+    op: libc::c_int,
+    fd: RawFd,
+    token: usize,
+    writes: bool,
+) -> Result<(), tunerror::Error> {
+    let mut events = libc::EPOLLIN;
+    if writes {
+        events |= libc::EPOLLOUT;
+    }
+    let mut event = libc::epoll_event {
+        events: events as u32,
+        u64: token as u64,
+    };
+    if unsafe { libc::epoll_ctl(epoll.as_raw_fd(), op, fd, &mut event) } < 0 {
+        return Err(tunerror::Error::EventQueue(io::Error::last_os_error()));
     }
+    Ok(())
+}
+
+/// Returns where the run of datagrams starting at `start` that a single GSO message can carry
+/// ends: they go to the same address, and are all as long as the first except the last, which
+/// may be shorter.
+fn gso_run_end(slices: &[IoSlice], addrs: &[&SockAddr], start: usize) -> usize {
+    let first = slices[start].len();
+    let mut len = first;
+    let mut end = start + 1;
+    while end < slices.len() && end - start < mmsg::MAX_SEGMENTS && slices[end - 1].len() == first {
+        if addrs[end] != addrs[start]
+            || slices[end].len() > first
+            || len + slices[end].len() > mmsg::MAX_SEGMENTED_LEN
+        {
+            break;
+        }
+        len += slices[end].len();
+        end += 1;
+    }
+    end
+}
+
+/// Parses the address of a client's server.
+fn remote_addr(config: &Config) -> Result<SocketAddr, tunerror::Error> {
+    config
+        .remote_addr
+        .parse()
+        .map_err(|_| tunerror::Error::Connect(config.remote_addr.clone()))
 }
 
 impl Net {
-    pub fn new(
-        remote_addr: &str,
-        port: u16,
-        is_client: bool,
-        key: String,
-    ) -> Result<Net, io::Error> {
-        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
-        let net: Net;
-        socket.set_reuse_address(true)?;
-        let mut key_bytes;
-        if key.len() > 0 {
-            key_bytes = vec![0; AES_256_GCM.key_len()];
-            for (i, b) in key.bytes().enumerate() {
-                key_bytes[i] = b;
+    pub fn new(config: &Config) -> Result<Net, tunerror::Error> {
+        let mut sockets: Vec<Box<dyn DatagramSocket>> = vec![];
+        let mut listeners = vec![];
+        match (config.is_client, config.transport) {
+            (true, Transport::Udp) => {
+                let address = remote_addr(config)?;
+                let socket = udp_socket(&address)?;
+                // Packets from other clients must get through too when talking to them directly
+                if !config.peer_to_peer {
+                    socket.connect(&address.into())?;
+                }
+                sockets.push(Box::new(socket));
             }
-        } else {
-            key_bytes = vec![];
+            // The connection is opened once epoll can watch it
+            (true, Transport::Tcp | Transport::WebSocket) => {}
+            (false, Transport::Udp) => {
+                for socket in bind_sockets(config)? {
+                    sockets.push(Box::new(socket));
+                }
+            }
+            (false, Transport::Tcp | Transport::WebSocket) => listeners = bind_sockets(config)?,
         }
+        Net::from_parts(config, sockets, listeners)
+    }
 
-        if is_client {
-            let address: SocketAddr = remote_addr.parse().unwrap();
-            let address = address.into();
-            socket.connect(&address)?;
-            net = Net {
-                fd: socket.as_raw_fd(),
-                socket: socket,
-                ip_map: None,
-                key: key_bytes,
-            };
+    /// Sets up a UDP tunnel over datagram sockets made elsewhere, such as the sockets of a
+    /// [`MemoryNetwork`], instead of binding its own. A client needs one, connected to its
+    /// server if it isn't peer to peer; a server needs one or more.
+    ///
+    /// [`MemoryNetwork`]: crate::datagram::MemoryNetwork
+    pub fn with_sockets(
+        config: &Config,
+        sockets: Vec<Box<dyn DatagramSocket>>,
+    ) -> Result<Net, tunerror::Error> {
+        if config.transport != Transport::Udp || sockets.is_empty() {
+            return Err(tunerror::Error::Message(
+                "datagram sockets need the UDP transport and at least one socket".to_owned(),
+            ));
+        }
+        Net::from_parts(config, sockets, vec![])
+    }
+
+    fn from_parts(
+        config: &Config,
+        sockets: Vec<Box<dyn DatagramSocket>>,
+        listeners: Vec<Socket>,
+    ) -> Result<Net, tunerror::Error> {
+        let remote_addr = match config.is_client {
+            true => Some(remote_addr(config)?),
+            false => None,
+        };
+        let epoll = match (config.transport, sockets.len()) {
+            (Transport::Udp, 1) => None,
+            _ => {
+                let fds: Vec<RawFd> = sockets
+                    .iter()
+                    .map(|socket| socket.as_raw_fd())
+                    .chain(listeners.iter().map(Socket::as_raw_fd))
+                    .collect();
+                Some(watch_sockets(&fds)?)
+            }
+        };
+        let gso = config.transport == Transport::Udp && sockets.iter().all(|s| s.supports_gso());
+        let rng = SystemRandom::new();
+        let mut psk = [0; KEY_LEN];
+        let mut identity = None;
+        if !config.peers.is_empty() && !config.is_encrypted() {
+            return Err(tunerror::Error::Message(
+                "peers can only be configured when packets are encrypted".to_owned(),
+            ));
+        }
+        if config.peer_to_peer && !config.is_encrypted() {
+            return Err(tunerror::Error::Message(
+                "peer to peer needs packets to be encrypted".to_owned(),
+            ));
+        }
+        if config.peer_to_peer && config.transport != Transport::Udp {
+            return Err(tunerror::Error::Message(
+                "peer to peer needs the UDP transport".to_owned(),
+            ));
+        }
+        if config.proxy.is_some() && config.transport == Transport::Udp {
+            return Err(tunerror::Error::Message(
+                "proxies need the TCP or WebSocket transport".to_owned(),
+            ));
+        }
+        if config.path_mtu < MIN_PATH_MTU {
+            return Err(tunerror::Error::Message(format!(
+                "path MTU must be at least {MIN_PATH_MTU}"
+            )));
+        }
+        // Longer datagrams wouldn't fit in the buffers the other peer receives into
+        let max_datagram_len = (config.path_mtu - UDP_OVERHEAD).min(MAX_DATAGRAM_LEN);
+        let is_full = config.is_encrypted() && config.encapsulation == Encapsulation::Full;
+        if (config.padding != Padding::None || config.cover_rate > 0) && !is_full {
+            return Err(tunerror::Error::Message(
+                "padding and cover traffic need encryption and full encapsulation".to_owned(),
+            ));
+        }
+        if config.is_encrypted() {
+            if !config.key.is_empty() {
+                let Some(iterations) = NonZeroU32::new(config.iterations) else {
+                    return Err(tunerror::Error::Message(
+                        "iteration count must be greater than zero".to_owned(),
+                    ));
+                };
+                psk = crypto::derive_psk(&config.key, config.salt.as_bytes(), iterations);
+            }
+            let private_key = config
+                .private_key
+                .unwrap_or_else(handshake::generate_private_key);
+            identity = Some(Identity::from_private_key(&private_key));
+            if config.cipher_suites.is_empty() {
+                return Err(tunerror::Error::Message(
+                    "at least one cipher suite must be allowed".to_owned(),
+                ));
+            }
+            if config.is_client && config.peer_key.is_none() {
+                return Err(tunerror::Error::Message(
+                    "a client needs the server's public key".to_owned(),
+                ));
+            }
+        }
+
+        let mut net = Net {
+            next_token: sockets.len() + listeners.len(),
+            sockets,
+            listeners,
+            epoll,
+            is_client: config.is_client,
+            transport: config.transport,
+            stream: StreamConfig {
+                websocket_path: (config.transport == Transport::WebSocket)
+                    .then(|| config.websocket_path.clone()),
+                proxy: config.proxy.clone(),
+            },
+            connections: HashMap::new(),
+            reconnect_at: None,
+            reconnect_delay: MIN_RECONNECT_DELAY,
+            server: None,
+            peer_to_peer: config.peer_to_peer,
+            requested: HashMap::new(),
+            allowed_ips: AllowedIps::new(),
+            static_peers: !config.peers.is_empty(),
+            peers: HashMap::new(),
+            next_peer_id: 0,
+            peer_ids: HashMap::new(),
+            endpoints: HashMap::new(),
+            indexes: HashMap::new(),
+            identity,
+            initiations: Initiations::default(),
+            psk,
+            cookies: CookieChecker::new(
+                &psk,
+                config.handshake_load_threshold,
+                config.handshake_rate_limit,
+                &rng,
+            )?,
+            rekey: RekeyPolicy {
+                after_packets: config.rekey_after_packets,
+                after_time: config.rekey_after_time,
+            },
+            idle_timeout: config.idle_timeout,
+            max_peers: config.max_peers,
+            peer_limit_policy: config.peer_limit_policy,
+            keepalive_interval: config.keepalive_interval,
+            last_tick: Instant::now(),
+            cipher_suites: config.cipher_suites.clone(),
+            encapsulation: config.encapsulation,
+            padding: config.padding,
+            cover_rate: config.cover_rate,
+            next_cover: Instant::now() + cover_interval(config.cover_rate),
+            max_datagram_len,
+            max_padded_len: max_datagram_len - DATA_HEADER_LEN - TAG_LEN,
+            reassembler: Reassembler::new(),
+            next_fragment_id: 0,
+            recv_bufs: BufferPool::new(0, MAX_DATAGRAM_LEN),
+            gso,
+            gro: None,
+            batching: false,
+            outgoing: vec![],
+            rng,
+            replayed_packets: 0,
+            events: VecDeque::new(),
+        };
+        if let Some(address) = remote_addr {
+            if net.transport != Transport::Udp {
+                let connection = Connection::connect(&address, &net.stream)?;
+                net.add_connection(SERVER_CONNECTION, connection)?;
+            }
+            let peer_id = net.add_peer(Peer {
+                public_key: config.peer_key,
+                endpoint: Some(Endpoint {
+                    addr: address.into(),
+                    socket: 0,
+                }),
+                ..Default::default()
+            });
+            net.server = Some(peer_id);
+            if net.identity.is_some() {
+                net.initiate_handshake(peer_id)?;
+            }
         } else {
-            let bind_addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port).into();
-            socket.bind(&bind_addr)?;
-            let map: HashMap<IpAddr, SockAddr> = HashMap::new();
-            net = Net {
-                fd: socket.as_raw_fd(),
-                socket: socket,
-                ip_map: Some(map),
-                key: key_bytes,
-            };
+            for peer_config in &config.peers {
+                let peer_id = net.add_peer(Peer {
+                    public_key: Some(peer_config.public_key),
+                    configured: true,
+                    ..Default::default()
+                });
+                for cidr in &peer_config.allowed_ips {
+                    net.allowed_ips.insert(*cidr, peer_id);
+                }
+            }
         }
         Ok(net)
     }
 
-    /// Sends an IP packet to a UDP endpoint.
-    pub fn send(&self, buf: &mut [u8], size: usize) -> usize {
-        let version = buf[0] >> 4;
-        if version != 4 && version != 6 {
-            return 0;
+    /// Returns the number of received packets dropped by the replay protection.
+    pub fn replayed_packets(&self) -> u64 {
+        self.replayed_packets
+    }
+
+    /// Takes the oldest event that wasn't taken yet.
+    pub fn next_event(&mut self) -> Option<Event> {
+        self.events.pop_front()
+    }
+
+    /// Returns this peer's static public key, or `None` if packets aren't encrypted.
+    pub fn public_key(&self) -> Option<&[u8; KEY_LEN]> {
+        self.identity.as_ref().map(|identity| identity.public_key())
+    }
+
+    fn add_peer(&mut self, peer: Peer) -> PeerId {
+        let peer_id = self.next_peer_id;
+        self.next_peer_id += 1;
+        if let Some(public_key) = peer.public_key {
+            self.peer_ids.insert(public_key, peer_id);
         }
-        let mut new_size = size;
-        if self.key.len() > 0 {
-            new_size = self
-                .encrypt(buf, size, version)
-                .expect("Encryption process had an error");
+        if let Some(endpoint) = &peer.endpoint {
+            if self.identity.is_none() {
+                self.endpoints.insert(endpoint.addr.clone(), peer_id);
+            }
         }
-        let buf = &buf[..new_size];
-        if self.ip_map.is_none() {
-            let _ = self.socket.send(buf).unwrap();
-        } else {
-            let destination_ip;
-            if version == 4 {
-                let slice = Ipv4HeaderSlice::from_slice(&buf);
-                if slice.is_err() {
-                    println!("{:?}", slice.err().unwrap());
-                    return 0;
-                }
-                destination_ip = IpAddr::V4(slice.unwrap().destination_addr());
-            } else {
-                let slice = Ipv6HeaderSlice::from_slice(&buf);
-                if slice.is_err() {
-                    println!("{:?}", slice.err().unwrap());
-                    return 0;
+        self.peers.insert(peer_id, peer);
+        peer_id
+    }
+
+    /// Adds a peer that sent us a packet, making room for it first if the server is full.
+    fn add_new_peer(&mut self, peer: Peer) -> Result<PeerId, tunerror::Error> {
+        if self.peers.len() >= self.max_peers {
+            let idlest = self
+                .peers
+                .iter()
+                .filter(|(_, peer)| !peer.configured)
+                .min_by_key(|(_, peer)| peer.last_seen)
+                .map(|(peer_id, _)| *peer_id);
+            match (self.peer_limit_policy, idlest) {
+                (PeerLimitPolicy::EvictIdlest, Some(idlest)) => {
+                    println!("PEER {idlest}: Evicted to make room");
+                    self.remove_peer(idlest);
                 }
-                destination_ip = IpAddr::V6(slice.unwrap().destination_addr());
+                _ => return Err(tunerror::Error::TooManyPeers),
+            }
+        }
+        Ok(self.add_peer(peer))
+    }
+
+    /// Forgets a peer along with its sessions and routes.
+    fn remove_peer(&mut self, peer_id: PeerId) {
+        let Some(peer) = self.peers.remove(&peer_id) else {
+            return;
+        };
+        if let Some(public_key) = peer.public_key {
+            self.peer_ids.remove(&public_key);
+        }
+        if let Some(endpoint) = peer.endpoint {
+            self.endpoints.remove(&endpoint.addr);
+        }
+        self.indexes.retain(|_, id| *id != peer_id);
+        self.allowed_ips.retain(|id| *id != peer_id);
+    }
+
+    /// Drops the sessions and address of a configured peer that went idle. Its key and allowed
+    /// prefixes stay, so it can connect again.
+    fn reset_peer(&mut self, peer_id: PeerId) {
+        let peer = self.peers.get_mut(&peer_id).unwrap();
+        peer.endpoint = None;
+        peer.session = None;
+        peer.previous_session = None;
+        peer.handshake = None;
+        peer.cookie = None;
+        peer.queue.clear();
+        peer.last_seen = None;
+        self.indexes.retain(|_, id| *id != peer_id);
+    }
+
+    /// Does the periodic work: keepalives are sent to peers that were sent nothing for the
+    /// keepalive interval, cover packets are sent to peers with a session, holes are punched to
+    /// the peers the server introduced us to, and peers that haven't sent an authenticated packet
+    /// for longer than the idle timeout are forgotten, so the peer table doesn't grow forever and
+    /// packets aren't routed to stale addresses. A client never forgets its server. With TCP, a
+    /// client connects again once it waited long enough after losing its connection, and a
+    /// server closes connections nothing arrived on for the idle timeout. Call it once
+    /// [`Net::tick_timeout`] passed; calling it more often is cheap.
+    pub fn tick(&mut self) {
+        let now = Instant::now();
+        if self.cover_rate > 0 && now >= self.next_cover {
+            self.send_cover(now);
+        }
+        if now.duration_since(self.last_tick) < TICK_INTERVAL {
+            return;
+        }
+        self.last_tick = now;
+        if self.reconnect_at.is_some_and(|at| now >= at) {
+            self.reconnect();
+        }
+        if !self.keepalive_interval.is_zero() {
+            self.send_keepalives(now);
+        }
+        if self.peer_to_peer && self.is_client {
+            self.continue_punching();
+            self.requested
+                .retain(|_, asked| now.duration_since(*asked) < rendezvous::REQUEST_INTERVAL);
+        }
+        self.reassembler.expire(now);
+        if !self.idle_timeout.is_zero() {
+            self.expire_idle_peers(now);
+            if !self.is_client {
+                self.close_idle_connections(now);
+            }
+        }
+    }
+
+    fn send_keepalives(&mut self, now: Instant) {
+        let due: Vec<PeerId> = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| {
+                peer.endpoint.is_some()
+                    && peer.last_sent.is_none_or(|last_sent| {
+                        now.duration_since(last_sent) >= self.keepalive_interval
+                    })
+            })
+            .map(|(peer_id, _)| *peer_id)
+            .collect();
+        for peer_id in due {
+            if let Err(e) = self.send_keepalive(peer_id) {
+                println!("KEEPALIVE: {e}");
             }
-            let client_ip = self.ip_map.as_ref().unwrap().get(&destination_ip);
-            if client_ip.is_some() {
-                let _ = self.socket.send_to(buf, client_ip.unwrap()).unwrap();
+        }
+    }
+
+    /// Sends a keepalive to a peer. A peer without a usable session gets a handshake instead,
+    /// so an idle client gets its session back before the server has something to send it.
+    fn send_keepalive(&mut self, peer_id: PeerId) -> Result<(), tunerror::Error> {
+        if self.identity.is_some() {
+            return self.send_message(peer_id, KEEPALIVE, &[]);
+        }
+        let peer = self.peers.get_mut(&peer_id).unwrap();
+        peer.last_sent = Some(Instant::now());
+        let endpoint = peer.endpoint.clone().unwrap();
+        self.send_to_endpoint(&[KEEPALIVE], &endpoint)?;
+        Ok(())
+    }
+
+    /// Returns how long to wait before calling [`Net::tick`] again. It's at most
+    /// [`TICK_INTERVAL`], and shorter while cover packets are due sooner.
+    pub fn tick_timeout(&self) -> Duration {
+        let mut at = self.last_tick + TICK_INTERVAL;
+        if self.cover_rate > 0 {
+            at = at.min(self.next_cover);
+        }
+        at.saturating_duration_since(Instant::now())
+    }
+
+    /// Sends a cover packet to every peer with a session, so an observer can't tell when the
+    /// tunnel carries packets. Each is as long as a packet of random length padded with our
+    /// policy. The next ones are due a `cover_rate`th of a second later, so they're spread
+    /// across the second instead of going out in a burst.
+    fn send_cover(&mut self, now: Instant) {
+        let interval = cover_interval(self.cover_rate);
+        self.next_cover += interval;
+        if self.next_cover < now {
+            self.next_cover = now + interval;
+        }
+        let due: Vec<PeerId> = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| peer.endpoint.is_some() && peer.session.is_some())
+            .map(|(peer_id, _)| *peer_id)
+            .collect();
+        for peer_id in due {
+            let len = padding::random_below(self.max_padded_len, &self.rng) + 1;
+            let padded_len = self.padding.padded_len(len, self.max_padded_len, &self.rng);
+            let cover = vec![COVER; padded_len];
+            if let Err(e) = self.send_message(peer_id, TRANSPORT_DATA, &cover) {
+                println!("COVER: {e}");
             }
         }
-        new_size
     }
 
-    /// Encrypts a packet to be sent over the network
-    fn encrypt(&self, buf: &mut [u8], size: usize, version: u8) -> Result<usize, Unspecified> {
-        let header_length = self.configure_header(buf, version, true);
-        let unbound_key = UnboundKey::new(&AES_256_GCM, &self.key)?;
-        let nonce_sequence = CounterNonceSequence(1);
-        let mut sealing_key = SealingKey::new(unbound_key, nonce_sequence);
-        let associated_data = Aad::empty();
+    /// Seals a message for a peer behind the same header as fully encapsulated data packets. A
+    /// peer without a usable session gets a handshake instead, and the message is dropped.
+    fn send_message(
+        &mut self,
+        peer_id: PeerId,
+        message_type: u8,
+        payload: &[u8],
+    ) -> Result<(), tunerror::Error> {
+        let rekey = self.rekey;
+        let peer = self.peers.get_mut(&peer_id).unwrap();
+        let Some(endpoint) = peer.endpoint.clone() else {
+            return Ok(());
+        };
+        let session = peer
+            .session
+            .as_mut()
+            .filter(|session| !rekey.is_expired(session));
+        let Some(session) = session else {
+            self.rekey(peer_id);
+            return Ok(());
+        };
+        let mut buf = vec![0; DATA_HEADER_LEN + payload.len() + TAG_LEN];
+        buf[DATA_HEADER_LEN..DATA_HEADER_LEN + payload.len()].copy_from_slice(payload);
+        let counter = session.next_counter();
+        let needs_rekey = rekey.needs_rekey(session, 0);
+        let size = Self::encapsulate(
+            message_type,
+            &session.send_key,
+            &mut buf,
+            payload.len(),
+            session.remote_index,
+            counter,
+        )
+        .map_err(|_| tunerror::Error::Message("could not seal message".to_owned()))?;
+        peer.last_sent = Some(Instant::now());
+        self.send_to_endpoint(&buf[..size], &endpoint)?;
+        if needs_rekey {
+            self.rekey(peer_id);
+        }
+        Ok(())
+    }
+
+    /// Asks the server to introduce us to the peer owning `destination`, unless we already
+    /// know that peer or asked about the address recently.
+    fn request_introduction(&mut self, source: IpAddr, destination: IpAddr) {
+        let known = self.allowed_ips.lookup(destination).is_some();
+        if known
+            || self.requested.contains_key(&destination)
+            || self.requested.len() >= rendezvous::MAX_REQUESTS
+        {
+            return;
+        }
+        self.requested.insert(destination, Instant::now());
+        let message = Message::Request {
+            source,
+            destination,
+        };
+        if let Err(e) = self.send_message(self.server.unwrap(), RENDEZVOUS, &message.to_bytes()) {
+            println!("RENDEZVOUS: {e}");
+        }
+    }
+
+    fn handle_rendezvous(
+        &mut self,
+        peer_id: PeerId,
+        payload: &[u8],
+    ) -> Result<(), tunerror::Error> {
+        if !self.peer_to_peer {
+            return Err(tunerror::Error::Message(
+                "peer to peer is disabled".to_owned(),
+            ));
+        }
+        match Message::from_bytes(payload)? {
+            Message::Request {
+                source,
+                destination,
+            } if !self.is_client => self.introduce(peer_id, source, destination),
+            Message::Introduction {
+                public_key,
+                endpoint,
+                tunnel_addr,
+            } if Some(peer_id) == self.server => self.punch(public_key, endpoint, tunnel_addr),
+            _ => Err(tunerror::Error::Message(
+                "unexpected rendezvous message".to_owned(),
+            )),
+        }
+    }
 
-        let tag = sealing_key
-            .seal_in_place_separate_tag(associated_data, &mut buf[header_length..size])?;
+    /// Tells a client where the peer owning `destination` can be reached, and that peer where
+    /// the client can be reached, so both start punching at once. A client may only ask to be
+    /// reached at an address it's allowed to send from.
+    fn introduce(
+        &mut self,
+        peer_id: PeerId,
+        source: IpAddr,
+        destination: IpAddr,
+    ) -> Result<(), tunerror::Error> {
+        if self.allowed_ips.lookup(source) != Some(&peer_id) {
+            return Err(tunerror::Error::SourceNotAllowed(source));
+        }
+        let Some(&other_id) = self.allowed_ips.lookup(destination) else {
+            return Ok(());
+        };
+        if other_id == peer_id {
+            return Ok(());
+        }
+        let introduction = |peer: &Peer, tunnel_addr| {
+            Some(Message::Introduction {
+                public_key: peer.public_key?,
+                endpoint: socket_addr(&peer.endpoint.as_ref()?.addr)?,
+                tunnel_addr,
+            })
+        };
+        let to_peer = introduction(&self.peers[&other_id], destination);
+        let to_other = introduction(&self.peers[&peer_id], source);
+        let (Some(to_peer), Some(to_other)) = (to_peer, to_other) else {
+            return Ok(());
+        };
+        self.send_message(peer_id, RENDEZVOUS, &to_peer.to_bytes())?;
+        self.send_message(other_id, RENDEZVOUS, &to_other.to_bytes())?;
+        println!("RENDEZVOUS: Introduced {source} to {destination}");
+        Ok(())
+    }
 
-        // Add the tag data to the buffer
-        for i in 0..AES_256_GCM.tag_len() {
-            buf[size + i] = tag.as_ref()[i];
+    /// Starts sending initiations to a peer the server introduced us to. The peer does the
+    /// same, so each NAT sees packets going out to the other peer before its packets come in.
+    fn punch(
+        &mut self,
+        public_key: [u8; KEY_LEN],
+        endpoint: SocketAddr,
+        tunnel_addr: IpAddr,
+    ) -> Result<(), tunerror::Error> {
+        if self.public_key() == Some(&public_key) {
+            return Ok(());
         }
-        Ok(size + AES_256_GCM.tag_len())
+        let peer_id = match self.peer_ids.get(&public_key) {
+            Some(peer_id) => *peer_id,
+            None => self.add_new_peer(Peer {
+                public_key: Some(public_key),
+                ..Default::default()
+            })?,
+        };
+        if Some(peer_id) == self.server {
+            return Ok(());
+        }
+        self.allowed_ips.insert(Cidr::host(tunnel_addr), peer_id);
+        let peer = self.peers.get_mut(&peer_id).unwrap();
+        peer.endpoint = Some(Endpoint {
+            addr: endpoint.into(),
+            socket: 0,
+        });
+        if peer.session.is_some() || peer.punch_attempts.is_some() {
+            return Ok(());
+        }
+        peer.punch_attempts = Some(1);
+        println!("PEER {peer_id}: Punching a hole to {endpoint} for {tunnel_addr}");
+        self.initiate_handshake(peer_id)
     }
 
-    /// Receives a packet from the other peer and decrypts it. Only IPv4 packets can be processed
-    /// TODO: IPv6
-    pub fn recv(&mut self) -> Result<(Vec<u8>, usize), tunerror::Error> {
-        let mut buf = [0; 4096];
-        let recv_buf = unsafe { &mut *(&mut buf[..] as *mut [u8] as *mut [MaybeUninit<u8>]) };
-        let (amount, remote_sock) = self.socket.recv_from(recv_buf).unwrap();
-        let version = buf[0] >> 4;
-        if version != 4 && version != 6 {
-            return Err(tunerror::Error::Message("Invalid packet".to_owned()));
-        }
-        let mut new_size = amount;
-        if self.key.len() > 0 {
-            new_size = self
-                .decrypt(&mut buf, amount, version)
-                .expect("Decryption process had an error");
-        }
-        if self.ip_map.is_some() {
-            if version == 4 {
-                let slice = Ipv4HeaderSlice::from_slice(&buf[..new_size]);
-                match slice {
-                    Ok(header) => {
-                        let source_ip = header.source_addr();
-                        self.ip_map
-                            .as_mut()
-                            .unwrap()
-                            .insert(IpAddr::V4(source_ip), remote_sock);
-                    }
-                    Err(e) => {
-                        println!("{:?}", e);
-                        return Err(tunerror::Error::Message("Invalid packet".to_owned()));
-                    }
+    /// Sends another initiation to every peer we're punching a hole to. A peer that doesn't
+    /// answer after [`rendezvous::PUNCH_ATTEMPTS`] is forgotten, so its packets go through the
+    /// server again.
+    fn continue_punching(&mut self) {
+        let punching: Vec<PeerId> = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| peer.punch_attempts.is_some())
+            .map(|(peer_id, _)| *peer_id)
+            .collect();
+        for peer_id in punching {
+            let peer = self.peers.get_mut(&peer_id).unwrap();
+            let attempts = peer.punch_attempts.unwrap();
+            if peer.session.is_some() {
+                peer.punch_attempts = None;
+                println!("PEER {peer_id}: Direct path is open");
+            } else if attempts < rendezvous::PUNCH_ATTEMPTS {
+                peer.punch_attempts = Some(attempts + 1);
+                if let Err(e) = self.initiate_handshake(peer_id) {
+                    println!("{e}");
                 }
             } else {
-                let slice = Ipv6HeaderSlice::from_slice(&buf[..new_size]);
-                match slice {
-                    Ok(header) => {
-                        let source_ip = header.source_addr();
-                        self.ip_map
-                            .as_mut()
-                            .unwrap()
-                            .insert(IpAddr::V6(source_ip), remote_sock);
-                    }
-                    Err(e) => {
-                        println!("{:?}", e);
-                        return Err(tunerror::Error::Message("Invalid packet".to_owned()));
-                    }
+                println!("PEER {peer_id}: Could not punch a hole, relaying through the server");
+                self.remove_peer(peer_id);
+            }
+        }
+    }
+
+    fn expire_idle_peers(&mut self, now: Instant) {
+        let idle: Vec<PeerId> = self
+            .peers
+            .iter()
+            .filter(|(peer_id, peer)| {
+                Some(**peer_id) != self.server
+                    && peer
+                        .last_seen
+                        .is_some_and(|last_seen| now.duration_since(last_seen) >= self.idle_timeout)
+            })
+            .map(|(peer_id, _)| *peer_id)
+            .collect();
+        for peer_id in idle {
+            println!("PEER {peer_id}: Expired after being idle");
+            if self.peers[&peer_id].configured {
+                self.reset_peer(peer_id);
+            } else {
+                self.remove_peer(peer_id);
+            }
+        }
+    }
+
+    /// Picks a random session index that isn't in use yet.
+    fn new_index(&self) -> u32 {
+        loop {
+            let mut bytes = [0; INDEX_LEN];
+            self.rng.fill(&mut bytes).unwrap();
+            let index = u32::from_le_bytes(bytes);
+            if !self.indexes.contains_key(&index) {
+                return index;
+            }
+        }
+    }
+
+    fn send_to_endpoint(&mut self, buf: &[u8], endpoint: &Endpoint) -> Result<usize, io::Error> {
+        if self.transport != Transport::Udp {
+            let Some(connection) = self.connections.get_mut(&endpoint.socket) else {
+                return Err(io::ErrorKind::NotConnected.into());
+            };
+            let result = connection.send(buf);
+            match &result {
+                // Only this packet was dropped
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => {
+                    println!("CONNECTION {}: {e}", endpoint.socket);
+                    self.close_connection(endpoint.socket);
                 }
+                Ok(_) => self.update_interest(endpoint.socket),
             }
+            return result;
         }
-        let buf_vec = buf[..new_size].to_vec();
-        Ok((buf_vec, amount))
+        if buf.len() > self.max_datagram_len {
+            return self.send_fragments(buf, endpoint);
+        }
+        if self.batching {
+            self.outgoing
+                .push((Datagram::Copied(buf.to_vec()), endpoint.clone()));
+            return Ok(buf.len());
+        }
+        let connected = self.is_client && !self.peer_to_peer;
+        self.sockets[endpoint.socket].send_to(buf, (!connected).then_some(&endpoint.addr))
     }
 
-    /// Decrypts a packet from the network using AES
-    fn decrypt(&self, buf: &mut [u8], size: usize, version: u8) -> Result<usize, Unspecified> {
-        let header_length = self.configure_header(buf, version, false);
-        let unbound_key = UnboundKey::new(&AES_256_GCM, &self.key)?;
-        let nonce_sequence = CounterNonceSequence(1);
-        let mut opening_key = OpeningKey::new(unbound_key, nonce_sequence);
-        let associated_data = Aad::empty();
-        let _ = opening_key.open_in_place(associated_data, &mut buf[header_length..size])?;
-        Ok(size - AES_256_GCM.tag_len())
+    /// Sends a datagram too long for the path MTU as fragments that fit it.
+    fn send_fragments(&mut self, buf: &[u8], endpoint: &Endpoint) -> Result<usize, io::Error> {
+        let id = self.next_fragment_id;
+        self.next_fragment_id = id.wrapping_add(1);
+        let Some(fragments) = fragment::split(buf, id, self.max_datagram_len) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} bytes need too many fragments", buf.len()),
+            ));
+        };
+        let mut sent = 0;
+        for fragment in fragments {
+            sent += self.send_to_endpoint(&fragment, endpoint)?;
+        }
+        Ok(sent)
     }
 
-    /// Sets a new length; the length increases if it's an encryption process, else it decreases.
-    /// The IPv4 header format https://en.wikipedia.org/wiki/IPv4#Header helps us know where
-    /// the needed data is stored for ipv4 packets. The IPv4 header format
-    /// https://en.wikipedia.org/wiki/IPv6_packet#Fixed_header helps us know where. Returns the header length
-    fn configure_header(&self, buf: &mut [u8], version: u8, is_encrypt: bool) -> usize {
-        let mut length;
-        if version == 4 {
-            length = u16::from_be_bytes([buf[2], buf[3]]);
-        } else {
-            length = u16::from_be_bytes([buf[4], buf[5]]);
+    /// Starts watching a new TCP connection under `token`.
+    fn add_connection(
+        &mut self,
+        token: usize,
+        mut connection: Connection,
+    ) -> Result<(), tunerror::Error> {
+        connection.watching_writes = connection.wants_write();
+        watch(
+            self.epoll.as_ref().unwrap(),
+            libc::EPOLL_CTL_ADD,
+            connection.as_raw_fd(),
+            token,
+            connection.watching_writes,
+        )?;
+        self.connections.insert(token, connection);
+        Ok(())
+    }
+
+    /// Has epoll report a connection when it's writable only while it has something to write,
+    /// so pending frames go out as soon as the socket takes them without waking us up for
+    /// nothing otherwise.
+    fn update_interest(&mut self, token: usize) {
+        let Some(connection) = self.connections.get_mut(&token) else {
+            return;
+        };
+        let wants_write = connection.wants_write();
+        if wants_write == connection.watching_writes {
+            return;
         }
-        if is_encrypt {
-            length += AES_256_GCM.tag_len() as u16;
-        } else {
-            length -= AES_256_GCM.tag_len() as u16;
+        let epoll = self.epoll.as_ref().unwrap();
+        match watch(
+            epoll,
+            libc::EPOLL_CTL_MOD,
+            connection.as_raw_fd(),
+            token,
+            wants_write,
+        ) {
+            Ok(()) => connection.watching_writes = wants_write,
+            Err(e) => println!("CONNECTION {token}: {e}"),
         }
-        let bytes = length.to_be_bytes();
-        let mut header_length;
-        if version == 4 {
-            buf[2] = bytes[0];
-            buf[3] = bytes[1];
-            header_length = (buf[0] & 15) as usize;
-            header_length *= 4;
-            packet::set_header_checksum(&mut buf[..header_length]);
+    }
+
+    /// Closes a TCP connection. Closing the socket also removes it from epoll. A client
+    /// connects to its server again later.
+    fn close_connection(&mut self, token: usize) {
+        if self.connections.remove(&token).is_none() {
+            return;
+        }
+        if self.is_client {
+            self.schedule_reconnect();
         } else {
-            buf[4] = bytes[0];
-            buf[5] = bytes[1];
-            header_length = IPV6_HEADER_LEN;
+            println!("CONNECTION {token}: Closed");
         }
-        header_length
+    }
+
+    /// Has [`Net::tick`] connect to the server again after a delay, which doubles every time
+    /// until a frame comes in.
+    fn schedule_reconnect(&mut self) {
+        let delay = self.reconnect_delay;
+        self.reconnect_at = Some(Instant::now() + delay);
+        self.reconnect_delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+        println!("CONNECTION: Connecting to the server again in {delay:?}");
+    }
+
+    /// Opens a new connection to the server in place of the one that was lost. A new handshake
+    /// is started on it, since a server that restarted doesn't know our session anymore.
+    fn reconnect(&mut self) {
+        self.reconnect_at = None;
+        let server = self.server.unwrap();
+        let endpoint = self.peers[&server].endpoint.clone().unwrap();
+        let connected = socket_addr(&endpoint.addr)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))
+            .and_then(|addr| Connection::connect(&addr, &self.stream))
+            .map_err(tunerror::Error::from)
+            .and_then(|connection| self.add_connection(SERVER_CONNECTION, connection));
+        if let Err(e) = connected {
+            println!("CONNECTION: {e}");
+            self.schedule_reconnect();
+            return;
+        }
+        println!("CONNECTION: Connecting to the server");
+        if self.identity.is_some() {
+            if let Err(e) = self.initiate_handshake(server) {
+                println!("{e}");
+            }
+        }
+    }
+
+    /// Accepts a connection on one of the server's listeners. When the server already has
+    /// `max_peers` connections, it makes room the way it does for peers.
+    fn accept(&mut self, listener: usize) -> Result<(), tunerror::Error> {
+        let (socket, addr) = self.listeners[listener].accept()?;
+        if self.connections.len() >= self.max_peers {
+            let idlest = self
+                .connections
+                .iter()
+                .min_by_key(|(_, connection)| connection.last_recv())
+                .map(|(token, _)| *token);
+            match (self.peer_limit_policy, idlest) {
+                (PeerLimitPolicy::EvictIdlest, Some(idlest)) => self.close_connection(idlest),
+                _ => return Err(tunerror::Error::TooManyPeers),
+            }
+        }
+        let token = self.next_token;
+        self.next_token += 1;
+        self.add_connection(token, Connection::accepted(socket, addr, &self.stream)?)?;
+        println!("CONNECTION {token}: Accepted");
+        Ok(())
+    }
+
+    fn close_idle_connections(&mut self, now: Instant) {
+        let idle: Vec<usize> = self
+            .connections
+            .iter()
+            .filter(|(_, connection)| {
+                now.duration_since(connection.last_recv()) >= self.idle_timeout
+            })
+            .map(|(token, _)| *token)
+            .collect();
+        for token in idle {
+            self.close_connection(token);
+        }
+    }
+
+    /// Waits for one of the sockets or connections to be ready and returns its token, which is
+    /// the position of a socket or the key of a connection.
+    fn ready_socket(&self) -> Result<usize, tunerror::Error> {
+        let Some(epoll) = &self.epoll else {
+            return Ok(0);
+        };
+        let mut event = libc::epoll_event { events: 0, u64: 0 };
+        loop {
+            match unsafe { libc::epoll_wait(epoll.as_raw_fd(), &mut event, 1, -1) } {
+                1 => return Ok(event.u64 as usize),
+                _ => {
+                    let error = io::Error::last_os_error();
+                    if error.kind() != io::ErrorKind::Interrupted {
+                        return Err(tunerror::Error::EventQueue(error));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Sends a handshake initiation to a peer, replacing any initiation still in flight.
+    fn initiate_handshake(&mut self, peer_id: PeerId) -> Result<(), tunerror::Error> {
+        let local_index = self.new_index();
+        let identity = self.identity.as_ref().unwrap();
+        let peer = self.peers.get_mut(&peer_id).unwrap();
+        let Some(public_key) = peer.public_key else {
+            return Err(tunerror::Error::Handshake(
+                "the peer's public key isn't known".to_owned(),
+            ));
+        };
+        let (message, pending) = handshake::create_initiation(
+            identity,
+            &public_key,
+            &self.psk,
+            local_index,
+            &self.cipher_suites,
+            peer.cookie.as_ref().and_then(Cookie::value),
+            &self.rng,
+        )?;
+        if let Some(old) = peer.handshake.replace(pending) {
+            self.indexes.remove(&old.local_index);
+        }
+        self.indexes.insert(local_index, peer_id);
+        peer.last_sent = Some(Instant::now());
+        let endpoint = peer.endpoint.clone().unwrap();
+        self.send_to_endpoint(&message, &endpoint)?;
+        println!("HANDSHAKE: Sent initiation {local_index}");
+        Ok(())
+    }
+
+    /// Answers a handshake initiation and starts a new session with its sender.
+    fn handle_initiation(&mut self, buf: &[u8], remote: Endpoint) -> Result<(), tunerror::Error> {
+        let checked = handshake::check_initiation_mac(buf, &self.psk)?;
+        let Some(source) = remote.addr.as_socket() else {
+            return Err(tunerror::Error::Handshake(
+                "initiation from a non IP address".to_owned(),
+            ));
+        };
+        if let Admission::CookieReply(reply) = self.cookies.admit(buf, source, &self.rng)? {
+            self.send_to_endpoint(&reply, &remote)?;
+            println!("HANDSHAKE: Sent cookie to {source}");
+            return Ok(());
+        }
+        let identity = self.identity.as_ref().unwrap();
+        let initiation = handshake::open_initiation(identity, &checked, &self.psk)?;
+        // Checked before a peer is added for the key, so a recorded initiation can neither
+        // start a session nor take a peer's slot
+        if self
+            .initiations
+            .is_replayed(&initiation.static_key, &initiation.timestamp)
+        {
+            return Err(tunerror::Error::Handshake("replayed initiation".to_owned()));
+        }
+        let unknown_peer = || {
+            tunerror::Error::Handshake(format!(
+                "unknown peer {}",
+                crypto::to_hex(&initiation.static_key)
+            ))
+        };
+        // A client only takes initiations from its server and the peers the server introduced it
+        // to, and a server with configured peers only from those peers
+        let peer_id = match self.peer_ids.get(&initiation.static_key) {
+            Some(peer_id) => *peer_id,
+            None if self.is_client || self.static_peers => return Err(unknown_peer()),
+            None => self.add_new_peer(Peer {
+                public_key: Some(initiation.static_key),
+                ..Default::default()
+            })?,
+        };
+
+        let local_index = self.new_index();
+        let (message, keys) = handshake::create_response(
+            &self.psk,
+            &initiation,
+            local_index,
+            &self.cipher_suites,
+            &self.rng,
+        )?;
+        self.send_to_endpoint(&message, &remote)?;
+        self.initiations
+            .accept(initiation.static_key, initiation.timestamp);
+        let peer = self.peers.get_mut(&peer_id).unwrap();
+        peer.last_seen = Some(Instant::now());
+        peer.last_sent = peer.last_seen;
+        self.update_endpoint(peer_id, remote);
+        self.install_session(peer_id, keys, false);
+        println!("HANDSHAKE: Sent response {local_index}");
+        Ok(())
+    }
+
+    /// Sends a peer's packets to the address an authenticated packet of it came from, so a peer
+    /// that changes networks keeps its session. Unauthenticated packets must never get here, or
+    /// anyone could redirect a peer's traffic. A client always talks to the address it was
+    /// given.
+    fn update_endpoint(&mut self, peer_id: PeerId, remote: Endpoint) {
+        let peer = self.peers.get_mut(&peer_id).unwrap();
+        let unchanged = peer.endpoint.as_ref().is_some_and(|endpoint| {
+            endpoint.addr == remote.addr && endpoint.socket == remote.socket
+        });
+        if self.is_client || unchanged {
+            return;
+        }
+        let (Some(public_key), Some(new)) = (peer.public_key, socket_addr(&remote.addr)) else {
+            return;
+        };
+        let old = peer.endpoint.replace(remote);
+        if self.events.len() == MAX_QUEUED_EVENTS {
+            self.events.pop_front();
+        }
+        self.events.push_back(Event::EndpointChanged {
+            public_key,
+            old: old.and_then(|endpoint| socket_addr(&endpoint.addr)),
+            new,
+        });
+    }
+
+    /// Stores the cookie a peer under load sent back for our initiation, and retries the
+    /// handshake with it.
+    fn handle_cookie_reply(&mut self, buf: &[u8]) -> Result<(), tunerror::Error> {
+        let unexpected = || tunerror::Error::Handshake("unexpected cookie reply".to_owned());
+        if buf.len() != cookie::COOKIE_REPLY_LEN {
+            return Err(unexpected());
+        }
+        let receiver_index = u32::from_le_bytes(buf[4..8].try_into().unwrap());
+        let peer_id = *self.indexes.get(&receiver_index).ok_or_else(unexpected)?;
+        let peer = self.peers.get_mut(&peer_id).unwrap();
+        let Some(pending) = peer
+            .handshake
+            .as_ref()
+            .filter(|pending| pending.local_index == receiver_index)
+        else {
+            return Err(unexpected());
+        };
+        peer.cookie = Some(cookie::consume_reply(buf, &self.psk, pending.mac1())?);
+        println!("HANDSHAKE: Received cookie for {receiver_index}");
+        self.initiate_handshake(peer_id)
+    }
+
+    /// Completes the handshake we initiated and sends the packets queued while waiting.
+    fn handle_response(&mut self, buf: &[u8]) -> Result<(), tunerror::Error> {
+        let response = handshake::parse_response(buf, &self.psk)?;
+        let unexpected = || tunerror::Error::Handshake("unexpected response".to_owned());
+        let peer_id = *self
+            .indexes
+            .get(&response.receiver_index)
+            .ok_or_else(unexpected)?;
+        let peer = self.peers.get_mut(&peer_id).unwrap();
+        match &peer.handshake {
+            Some(pending) if pending.local_index == response.receiver_index => {}
+            _ => return Err(unexpected()),
+        }
+        let pending = peer.handshake.take().unwrap();
+        let identity = self.identity.as_ref().unwrap();
+        let keys = match handshake::consume_response(identity, pending, &response) {
+            Ok(keys) => keys,
+            Err(e) => {
+                self.indexes.remove(&response.receiver_index);
+                return Err(e);
+            }
+        };
+        println!("HANDSHAKE: Received response {}", keys.local_index);
+        self.install_session(peer_id, keys, true);
+        self.flush_queue(peer_id);
+        Ok(())
+    }
+
+    /// Makes a new session the one packets are sent with. The replaced session is kept to open
+    /// packets still in flight, and the one before it is dropped.
+    fn install_session(&mut self, peer_id: PeerId, keys: SessionKeys, is_initiator: bool) {
+        let local_index = keys.local_index;
+        let peer = self.peers.get_mut(&peer_id).unwrap();
+        let replaced = peer.session.replace(Session::new(keys, is_initiator));
+        if let Some(old) = std::mem::replace(&mut peer.previous_session, replaced) {
+            self.indexes.remove(&old.local_index);
+        }
+        self.indexes.insert(local_index, peer_id);
+    }
+
+    /// Starts a new handshake with a peer unless one is already in progress.
+    fn rekey(&mut self, peer_id: PeerId) {
+        if self.peers[&peer_id].can_initiate() {
+            if let Err(e) = self.initiate_handshake(peer_id) {
+                println!("{e}");
+            }
+        }
+    }
+
+    /// Sends the packets that were waiting for a peer's session.
+    fn flush_queue(&mut self, peer_id: PeerId) {
+        let queue = std::mem::take(&mut self.peers.get_mut(&peer_id).unwrap().queue);
+        for packet in queue {
+            let mut buf = [0; MAX_DATAGRAM_LEN];
+            buf[..packet.len()].copy_from_slice(&packet);
+            let amt = self.send(&mut buf, packet.len());
+            println!("HANDSHAKE: Written {amt} queued bytes to network");
+        }
+    }
+
+    /// Returns the peer an IP packet read from the tunnel should be sent to. A client sends
+    /// packets through its server unless it has a session with their destination.
+    fn route(&self, buf: &[u8]) -> Option<PeerId> {
+        if self.is_client {
+            let direct = packet::get_destination_addr(buf)
+                .and_then(|destination_ip| self.allowed_ips.lookup(destination_ip))
+                .filter(|peer_id| self.peers[peer_id].session.is_some());
+            return direct.copied().or(self.server);
+        }
+        let destination_ip = packet::get_destination_addr(buf)?;
+        self.allowed_ips.lookup(destination_ip).copied()
+    }
+
+    /// Sends an IP packet to the peer it's routed to. Packets for a peer without a session are
+    /// queued until its handshake completes. `buf` must have room after the packet for what
+    /// sealing adds, and packets that don't fit are dropped. Returns the number of bytes written
+    /// to the network, which is 0 if the packet couldn't be sent.
+    pub fn send(&mut self, buf: &mut [u8], size: usize) -> usize {
+        let Some(sealed) = self.seal(buf, 0..size) else {
+            return 0;
+        };
+        if let Err(e) = self.send_to_endpoint(&buf[sealed.datagram.clone()], &sealed.endpoint) {
+            println!("{e}");
+            return 0;
+        }
+        let sent = sealed.datagram.len();
+        self.after_send(sealed);
+        sent
+    }
+
+    /// Seals an IP packet in place for the peer it's routed to, like [`Net::send`] but without
+    /// sending it. The packet is at `packet` in `buf`, and a fully encapsulated one only moves
+    /// if there's no room in front of it for its header.
+    fn seal(&mut self, buf: &mut [u8], packet: Range<usize>) -> Option<Sealed> {
+        let size = packet.len();
+        let version = buf[packet.clone()].first()? >> 4;
+        if version != 4 && version != 6 {
+            return None;
+        }
+        let full = self.identity.is_some() && self.encapsulation == Encapsulation::Full;
+        let overhead = match (self.identity.is_some(), self.encapsulation) {
+            (false, _) => 0,
+            (true, Encapsulation::Header) => TAG_LEN + INDEX_LEN + COUNTER_LEN,
+            (true, Encapsulation::Full) => DATA_HEADER_LEN + TAG_LEN,
+        };
+        // Where the sealed datagram starts
+        let start = match packet.start.checked_sub(DATA_HEADER_LEN) {
+            _ if !full => packet.start,
+            Some(start) => start,
+            None => 0,
+        };
+        if size + overhead > (buf.len() - start).min(MAX_DATAGRAM_LEN) {
+            println!("Dropped a packet of {size} bytes, too long to seal");
+            return None;
+        }
+        let peer_id = self.route(&buf[packet.clone()])?;
+        // Find out who the packet is for before it's sealed, to ask for a direct path to them
+        let relayed = (self.peer_to_peer && Some(peer_id) == self.server && self.is_client)
+            .then(|| {
+                let source = packet::get_source_addr(&buf[packet.clone()])?;
+                Some((source, packet::get_destination_addr(&buf[packet.clone()])?))
+            })
+            .flatten();
+        let peer = self.peers.get_mut(&peer_id).unwrap();
+        let endpoint = peer.endpoint.clone()?;
+        let mut datagram = packet.clone();
+        let mut needs_rekey = false;
+        if self.identity.is_some() {
+            let rekey = self.rekey;
+            let session = peer
+                .session
+                .as_mut()
+                .filter(|session| !rekey.is_expired(session));
+            let Some(session) = session else {
+                if peer.queue.len() < MAX_QUEUED_PACKETS {
+                    peer.queue.push_back(buf[packet].to_vec());
+                }
+                self.rekey(peer_id);
+                return None;
+            };
+            let counter = session.next_counter();
+            needs_rekey = rekey.needs_rekey(session, 0);
+            let sealed = if full {
+                if start + DATA_HEADER_LEN != packet.start {
+                    buf.copy_within(packet, start + DATA_HEADER_LEN);
+                }
+                let buf = &mut buf[start..];
+                let padded_size = self
+                    .padding
+                    .padded_len(size, self.max_padded_len, &self.rng)
+                    .min(buf.len().min(MAX_DATAGRAM_LEN) - overhead)
+                    .max(size);
+                buf[DATA_HEADER_LEN + size..DATA_HEADER_LEN + padded_size].fill(0);
+                Self::encapsulate(
+                    TRANSPORT_DATA,
+                    &session.send_key,
+                    buf,
+                    padded_size,
+                    session.remote_index,
+                    counter,
+                )
+            } else {
+                Self::encrypt(
+                    &session.send_key,
+                    &mut buf[start..],
+                    size,
+                    version,
+                    session.remote_index,
+                    counter,
+                )
+            };
+            let Ok(sealed_size) = sealed else {
+                println!("Could not seal a packet of {size} bytes");
+                return None;
+            };
+            datagram = start..start + sealed_size;
+        }
+        peer.last_sent = Some(Instant::now());
+        Some(Sealed {
+            datagram,
+            endpoint,
+            peer_id,
+            needs_rekey,
+            relayed,
+        })
+    }
+
+    /// Does what's left once a sealed packet was sent.
+    fn after_send(&mut self, sealed: Sealed) {
+        if sealed.needs_rekey {
+            self.rekey(sealed.peer_id);
+        }
+        if let Some((source, destination)) = sealed.relayed {
+            self.request_introduction(source, destination);
+        }
+    }
+
+    /// Sends IP packets like [`Net::send`], sealing each in place in its buffer, which holds
+    /// the datagram afterwards. The UDP datagrams are written straight from the buffers to each
+    /// socket [`BATCH_SIZE`] at a time. Returns the number of bytes written to the network.
+    pub fn send_batch(&mut self, packets: &mut [PacketBuf]) -> usize {
+        self.batching = true;
+        let mut sent = 0;
+        for (i, buf) in packets.iter_mut().enumerate() {
+            let (data, packet) = buf.parts_mut();
+            let Some(sealed) = self.seal(data, packet) else {
+                continue;
+            };
+            buf.set_packet(sealed.datagram.clone());
+            // Datagrams that go out whole are written from the buffer once they're all sealed
+            let result = if self.transport == Transport::Udp
+                && sealed.datagram.len() <= self.max_datagram_len
+            {
+                self.outgoing
+                    .push((Datagram::Sealed(i), sealed.endpoint.clone()));
+                Ok(())
+            } else {
+                self.send_to_endpoint(buf.packet(), &sealed.endpoint)
+                    .map(drop)
+            };
+            match result {
+                Ok(()) => {
+                    sent += sealed.datagram.len();
+                    self.after_send(sealed);
+                }
+                Err(e) => println!("{e}"),
+            }
+        }
+        self.batching = false;
+        sent.saturating_sub(self.flush_outgoing(packets))
+    }
+
+    /// Writes the datagrams held while batching, those sealed in place in `packets` included.
+    /// Returns the number of bytes that couldn't be written.
+    fn flush_outgoing(&mut self, packets: &[PacketBuf]) -> usize {
+        let mut outgoing = mem::take(&mut self.outgoing);
+        let mut dropped = 0;
+        for token in 0..self.sockets.len() {
+            let mut datagrams = outgoing
+                .iter()
+                .filter(|(_, endpoint)| endpoint.socket == token)
+                .peekable();
+            while let Some(next) = datagrams.peek().copied() {
+                let mut slices = [IoSlice::new(&[]); BATCH_SIZE];
+                let mut addrs = [&next.1.addr; BATCH_SIZE];
+                let mut count = 0;
+                for (datagram, endpoint) in datagrams.by_ref().take(BATCH_SIZE) {
+                    slices[count] = IoSlice::new(datagram.contents(packets));
+                    addrs[count] = &endpoint.addr;
+                    count += 1;
+                }
+                dropped += self.send_datagrams(token, &slices[..count], &addrs[..count]);
+            }
+        }
+        // What was held is dropped, but the room for it is kept for the next batch
+        outgoing.clear();
+        self.outgoing = outgoing;
+        dropped
+    }
+
+    /// Writes up to [`BATCH_SIZE`] datagrams to one socket with a single system call, with GSO
+    /// putting runs of them in one message where it can. Returns the number of bytes that
+    /// couldn't be written.
+    fn send_datagrams(&mut self, token: usize, slices: &[IoSlice], addrs: &[&SockAddr]) -> usize {
+        let connected = self.is_client && !self.peer_to_peer;
+        let mut transmits = [mmsg::Transmit::default(); BATCH_SIZE];
+        // Where each message's datagrams start
+        let mut starts = [0; BATCH_SIZE];
+        let mut count = 0;
+        let mut start = 0;
+        while start < slices.len() {
+            let end = if self.gso {
+                gso_run_end(slices, addrs, start)
+            } else {
+                start + 1
+            };
+            transmits[count] = mmsg::Transmit {
+                contents: &slices[start..end],
+                addr: (!connected).then_some(addrs[start]),
+                segment_len: (end - start > 1).then_some(slices[start].len()),
+            };
+            starts[count] = start;
+            count += 1;
+            start = end;
+        }
+        let mut dropped = 0;
+        let mut next = 0;
+        while next < count {
+            match self.sockets[token].send_batch(&transmits[next..count]) {
+                Ok(sent) => next += sent,
+                // The socket claimed GSO but the device or route can't do it
+                Err(e)
+                    if transmits[next].segment_len.is_some()
+                        && matches!(e.raw_os_error(), Some(libc::EIO | libc::EINVAL)) =>
+                {
+                    println!("GSO: {e}, sending datagrams one at a time from now on");
+                    self.gso = false;
+                    let start = starts[next];
+                    return dropped + self.send_datagrams(token, &slices[start..], &addrs[start..]);
+                }
+                // Only the datagrams that failed are dropped
+                Err(e) => {
+                    println!("{e}");
+                    dropped += transmits[next].len();
+                    next += 1;
+                }
+            }
+        }
+        dropped
+    }
+
+    /// Encrypts a packet to be sent over the network. The receiver's session index and the
+    /// packet's counter are written after the tag so that the receiver can find the session and
+    /// rebuild the nonce the packet was sealed with. The IP header stays readable but is
+    /// authenticated along with the payload.
+    fn encrypt(
+        key: &LessSafeKey,
+        buf: &mut [u8],
+        size: usize,
+        version: u8,
+        index: u32,
+        counter: u64,
+    ) -> Result<usize, Unspecified> {
+        let (aad, aad_len) = Self::header_aad(buf, version);
+        let associated_data = Aad::from(&aad[..aad_len]);
+        let header_length = Self::configure_header(buf, version, true);
+        let tag = key.seal_in_place_separate_tag(
+            counter_nonce(counter),
+            associated_data,
+            &mut buf[header_length..size],
+        )?;
+
+        // Add the tag, the index and the counter to the buffer
+        let mut end = size;
+        for data in [tag.as_ref(), &index.to_le_bytes(), &counter.to_be_bytes()] {
+            buf[end..end + data.len()].copy_from_slice(data);
+            end += data.len();
+        }
+        Ok(end)
+    }
+
+    /// Encrypts a whole packet and puts it behind a header starting with `message_type`. Only
+    /// the length of the packet can be seen on the network. The packet must already be past
+    /// the room for the header, at [`DATA_HEADER_LEN`].
+    fn encapsulate(
+        message_type: u8,
+        key: &LessSafeKey,
+        buf: &mut [u8],
+        size: usize,
+        index: u32,
+        counter: u64,
+    ) -> Result<usize, Unspecified> {
+        let end = DATA_HEADER_LEN + size;
+        if end + TAG_LEN > buf.len() {
+            return Err(Unspecified);
+        }
+        buf[..4].copy_from_slice(&[message_type, 0, 0, 0]);
+        buf[4..4 + INDEX_LEN].copy_from_slice(&index.to_le_bytes());
+        buf[4 + INDEX_LEN..DATA_HEADER_LEN].copy_from_slice(&counter.to_be_bytes());
+
+        let (header, data) = buf.split_at_mut(DATA_HEADER_LEN);
+        let tag = key.seal_in_place_separate_tag(
+            counter_nonce(counter),
+            Aad::from(header),
+            &mut data[..end - DATA_HEADER_LEN],
+        )?;
+        buf[end..end + TAG_LEN].copy_from_slice(tag.as_ref());
+        Ok(end + TAG_LEN)
+    }
+
+    /// Receives a packet from the other peer and decrypts it. Handshake messages and keepalives
+    /// are handled here and give back an empty packet, which must not be written to the tunnel.
+    pub fn recv(&mut self) -> Result<(Vec<u8>, usize), tunerror::Error> {
+        let mut buf = [0; MAX_DATAGRAM_LEN];
+        let Some((amount, remote)) = self.receive(&mut buf)? else {
+            return Ok((vec![], 0));
+        };
+        let mut opened = Ok(vec![]);
+        self.open_datagram(&mut buf[..amount], remote, &mut |result| {
+            opened = result.map(<[u8]>::to_vec);
+        });
+        opened.map(|packet| (packet, amount))
+    }
+
+    /// Receives the datagrams waiting on a ready socket, up to [`BATCH_SIZE`] messages with a
+    /// single system call, and opens each in place like [`Net::recv`]. Hands `each` the packet
+    /// or error of every datagram, in the order they arrived, without copying the packet out of
+    /// the buffer it was received into. With TCP, hands it that of one frame.
+    ///
+    /// The first call turns GRO on where the kernel has it, so that a message may hold many
+    /// datagrams. From then on, datagrams must be received with this rather than [`Net::recv`].
+    pub fn recv_batch(
+        &mut self,
+        mut each: impl FnMut(Result<&[u8], tunerror::Error>),
+    ) -> Result<(), tunerror::Error> {
+        if self.transport != Transport::Udp {
+            let mut buf = [0; MAX_DATAGRAM_LEN];
+            match self.receive(&mut buf) {
+                Ok(Some((amount, remote))) => {
+                    self.open_datagram(&mut buf[..amount], remote, &mut each);
+                }
+                Ok(None) => each(Ok(&[])),
+                Err(e) => each(Err(e)),
+            }
+            return Ok(());
+        }
+        if self.gro.is_none() {
+            let gro = self.sockets.iter().all(|socket| socket.enable_gro());
+            let capacity = if gro {
+                mmsg::MAX_SEGMENTED_LEN
+            } else {
+                MAX_DATAGRAM_LEN
+            };
+            self.recv_bufs = BufferPool::new(BATCH_SIZE, capacity);
+            self.gro = Some(gro);
+        }
+        let token = self.ready_socket()?;
+        let mut bufs: [PacketBuf; BATCH_SIZE] = array::from_fn(|_| self.recv_bufs.take());
+        let mut received = [mmsg::Received::default(); BATCH_SIZE];
+        let result = self.sockets[token].recv_batch(&mut bufs, &mut received);
+        let count = match result {
+            Ok(count) => count,
+            // Another reader took what was waiting
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => 0,
+            Err(e) => {
+                self.recv_bufs.give_all(bufs);
+                return Err(tunerror::Error::Socket(e));
+            }
+        };
+        for (buf, received) in bufs.iter_mut().zip(&received[..count]) {
+            let remote = Endpoint {
+                addr: received.addr(),
+                socket: token,
+            };
+            let segment_len = received.segment_len.unwrap_or(received.len).max(1);
+            let message = &mut buf.as_mut()[..received.len];
+            for datagram in message.chunks_mut(segment_len) {
+                self.open_datagram(datagram, remote.clone(), &mut each);
+            }
+        }
+        self.recv_bufs.give_all(bufs);
+        Ok(())
+    }
+
+    /// Opens a datagram, or the one it completes if it's a fragment, and hands `each` its packet
+    /// or error.
+    fn open_datagram(
+        &mut self,
+        datagram: &mut [u8],
+        remote: Endpoint,
+        each: &mut impl FnMut(Result<&[u8], tunerror::Error>),
+    ) {
+        if datagram.first() != Some(&fragment::FRAGMENT) || self.transport != Transport::Udp {
+            match self.open(datagram, remote) {
+                Ok(packet) => each(Ok(&datagram[packet])),
+                Err(e) => each(Err(e)),
+            }
+            return;
+        }
+        let now = Instant::now();
+        let reassembled = self
+            .reassembler
+            .add(&remote.addr, datagram, MAX_DATAGRAM_LEN, now);
+        match reassembled {
+            Ok(Some(mut whole)) => match self.open(&mut whole, remote) {
+                Ok(packet) => each(Ok(&whole[packet])),
+                Err(e) => each(Err(e)),
+            },
+            Ok(None) => each(Ok(&[])),
+            Err(e) => each(Err(e)),
+        }
+    }
+
+    /// Opens a datagram received from `remote` in place, and returns where its packet is in it.
+    /// Handshake messages and keepalives are handled here and give back an empty packet.
+    fn open(&mut self, buf: &mut [u8], remote: Endpoint) -> Result<Range<usize>, tunerror::Error> {
+        let amount = buf.len();
+        if amount == 0 {
+            return Err(tunerror::Error::ShortPacket(amount));
+        }
+        if self.identity.is_some() {
+            match buf[0] {
+                handshake::HANDSHAKE_INITIATION => {
+                    self.handle_initiation(&buf[..amount], remote)?;
+                    return Ok(0..0);
+                }
+                handshake::HANDSHAKE_RESPONSE => {
+                    self.handle_response(&buf[..amount])?;
+                    return Ok(0..0);
+                }
+                cookie::COOKIE_REPLY => {
+                    self.handle_cookie_reply(&buf[..amount])?;
+                    return Ok(0..0);
+                }
+                _ => {}
+            }
+        }
+        let is_keepalive = buf[0] == KEEPALIVE;
+        let is_rendezvous = self.identity.is_some() && buf[0] == RENDEZVOUS;
+        let is_full =
+            self.identity.is_some() && (buf[0] == TRANSPORT_DATA || is_keepalive || is_rendezvous);
+        let version = buf[0] >> 4;
+        if !is_full && !is_keepalive && version != 4 && version != 6 {
+            return Err(tunerror::Error::InvalidPacket);
+        }
+        let mut packet = 0..amount;
+        let peer_id;
+        if self.identity.is_some() {
+            let (index, counter) = if is_full {
+                if amount < DATA_HEADER_LEN + TAG_LEN {
+                    return Err(tunerror::Error::ShortPacket(amount));
+                }
+                let header = &buf[4..DATA_HEADER_LEN];
+                (
+                    u32::from_le_bytes(header[..INDEX_LEN].try_into().unwrap()),
+                    u64::from_be_bytes(header[INDEX_LEN..].try_into().unwrap()),
+                )
+            } else {
+                let trailer_len = INDEX_LEN + COUNTER_LEN;
+                // The cleartext header is read before the packet is authenticated
+                match Self::ip_header_len(buf, version) {
+                    Some(header_len) if header_len + TAG_LEN + trailer_len <= amount => {}
+                    Some(_) => return Err(tunerror::Error::ShortPacket(amount)),
+                    None => return Err(tunerror::Error::InvalidPacket),
+                }
+                let trailer = &buf[amount - trailer_len..amount];
+                (
+                    u32::from_le_bytes(trailer[..INDEX_LEN].try_into().unwrap()),
+                    u64::from_be_bytes(trailer[INDEX_LEN..].try_into().unwrap()),
+                )
+            };
+            let rekey = self.rekey;
+            let session = self
+                .indexes
+                .get(&index)
+                .and_then(|peer_id| self.peers.get_mut(peer_id))
+                .and_then(|peer| peer.session_mut(index))
+                .filter(|session| !rekey.is_expired(session));
+            let Some(session) = session else {
+                return Err(tunerror::Error::UnknownPeer);
+            };
+            if !session.replay_window.check(counter) {
+                self.replayed_packets += 1;
+                return Err(tunerror::Error::Replay(counter));
+            }
+            packet = if is_full {
+                Self::decapsulate(&session.recv_key, buf, amount, counter)
+            } else {
+                Self::decrypt(&session.recv_key, buf, amount, version, counter).map(|size| 0..size)
+            }
+            .map_err(|_| tunerror::Error::AuthenticationFailed)?;
+            // Another packet with the same counter can't have been accepted since the check above
+            session.replay_window.update(counter);
+            let needs_rekey = rekey.needs_rekey(session, counter);
+            peer_id = self.indexes[&index];
+            self.peers.get_mut(&peer_id).unwrap().last_seen = Some(Instant::now());
+            self.update_endpoint(peer_id, remote);
+            let is_current = self.peers[&peer_id]
+                .session
+                .as_ref()
+                .is_some_and(|session| session.local_index == index);
+            if is_current && needs_rekey {
+                self.rekey(peer_id);
+            }
+        } else {
+            peer_id = match self.endpoints.get(&remote.addr) {
+                Some(peer_id) => *peer_id,
+                None => self.add_new_peer(Peer {
+                    endpoint: Some(remote),
+                    ..Default::default()
+                })?,
+            };
+            self.peers.get_mut(&peer_id).unwrap().last_seen = Some(Instant::now());
+        }
+        if is_keepalive {
+            return Ok(0..0);
+        }
+        if is_rendezvous {
+            self.handle_rendezvous(peer_id, &buf[packet])?;
+            return Ok(0..0);
+        }
+        if is_full {
+            let inner = &buf[packet.clone()];
+            if inner.first().is_none_or(|b| *b == COVER) {
+                return Ok(0..0);
+            }
+            // The IP header of a fully encapsulated packet is only seen once it's decrypted, and
+            // tells where the padding after the packet starts
+            let Some(len) = packet::get_total_len(inner).filter(|len| *len <= inner.len()) else {
+                return Err(tunerror::Error::InvalidPacket);
+            };
+            packet.end = packet.start + len;
+        }
+        // Peers other than a client's server may only send from their allowed prefixes
+        if Some(peer_id) != self.server {
+            let Some(source_ip) = packet::get_source_addr(&buf[packet.clone()]) else {
+                return Err(tunerror::Error::InvalidPacket);
+            };
+            if !self.is_client && !self.static_peers {
+                // A learned address belongs to the first peer that sent from it until that peer
+                // expires, so another client with the password can't take its traffic over
+                match self.allowed_ips.lookup(source_ip) {
+                    Some(owner) if *owner != peer_id => {
+                        return Err(tunerror::Error::SourceNotAllowed(source_ip));
+                    }
+                    Some(_) => {}
+                    None => {
+                        self.allowed_ips.insert(Cidr::host(source_ip), peer_id);
+                    }
+                }
+            } else if self.allowed_ips.lookup(source_ip) != Some(&peer_id) {
+                return Err(tunerror::Error::SourceNotAllowed(source_ip));
+            }
+        }
+        Ok(packet)
+    }
+
+    /// Waits for a datagram, or a whole frame with TCP, and returns its size and where it came
+    /// from. Gives back `None` when what was ready had nothing to pass on, such as a listener
+    /// that accepted a connection or a connection that only got part of a frame.
+    fn receive(&mut self, buf: &mut [u8]) -> Result<Option<(usize, Endpoint)>, tunerror::Error> {
+        let token = self.ready_socket()?;
+        if self.transport == Transport::Udp {
+            let (amount, addr) = self.sockets[token]
+                .recv_from(buf)
+                .map_err(tunerror::Error::Socket)?;
+            return Ok(Some((
+                amount,
+                Endpoint {
+                    addr,
+                    socket: token,
+                },
+            )));
+        }
+        if token < self.listeners.len() {
+            self.accept(token)?;
+            return Ok(None);
+        }
+        let Some(connection) = self.connections.get_mut(&token) else {
+            return Ok(None);
+        };
+        let addr = connection.peer_addr().clone();
+        match connection.flush().and_then(|()| connection.recv(buf)) {
+            Ok(amount) => {
+                self.update_interest(token);
+                if amount.is_some() && self.is_client {
+                    self.reconnect_delay = MIN_RECONNECT_DELAY;
+                }
+                Ok(amount.map(|amount| {
+                    (
+                        amount,
+                        Endpoint {
+                            addr,
+                            socket: token,
+                        },
+                    )
+                }))
+            }
+            Err(e) => {
+                self.close_connection(token);
+                Err(tunerror::Error::ConnectionClosed(e))
+            }
+        }
+    }
+
+    /// Decrypts a packet from the network with the session's cipher suite. The nonce is rebuilt
+    /// from the counter the sender wrote after the tag. Fails if the payload or the fields of
+    /// the IP header covered by [`Net::header_aad`] were changed on the way.
+    fn decrypt(
+        key: &LessSafeKey,
+        buf: &mut [u8],
+        size: usize,
+        version: u8,
+        counter: u64,
+    ) -> Result<usize, Unspecified> {
+        let tag_len = TAG_LEN;
+        let sealed_size = size - INDEX_LEN - COUNTER_LEN;
+        let header_length = Self::configure_header(buf, version, false);
+        if header_length + tag_len > sealed_size {
+            return Err(Unspecified);
+        }
+        let (aad, aad_len) = Self::header_aad(buf, version);
+        let associated_data = Aad::from(&aad[..aad_len]);
+        key.open_in_place(
+            counter_nonce(counter),
+            associated_data,
+            &mut buf[header_length..sealed_size],
+        )?;
+        Ok(sealed_size - tag_len)
+    }
+
+    /// Decrypts a fully encapsulated packet, whose transport data header is authenticated with
+    /// it. Returns where the inner packet is in the buffer.
+    fn decapsulate(
+        key: &LessSafeKey,
+        buf: &mut [u8],
+        size: usize,
+        counter: u64,
+    ) -> Result<Range<usize>, Unspecified> {
+        let (header, data) = buf.split_at_mut(DATA_HEADER_LEN);
+        key.open_in_place(
+            counter_nonce(counter),
+            Aad::from(header),
+            &mut data[..size - DATA_HEADER_LEN],
+        )?;
+        Ok(DATA_HEADER_LEN..size - TAG_LEN)
+    }
+
+    /// Returns the fields of a cleartext IP header that mustn't change between peers: the version,
+    /// the length of the inner packet, the protocol and both addresses. Routing on the server
+    /// relies on the source address, so a packet whose addresses were rewritten fails to decrypt
+    /// instead of being attributed to another client. The length must be the inner packet's,
+    /// i.e. read before the header is configured for encryption or after it's configured for
+    /// decryption.
+    fn header_aad(buf: &[u8], version: u8) -> ([u8; MAX_AAD_LEN], usize) {
+        let fields: [&[u8]; 3] = if version == 4 {
+            [&buf[2..4], &buf[9..10], &buf[12..20]]
+        } else {
+            [&buf[4..7], &buf[8..IPV6_HEADER_LEN], &[]]
+        };
+        let mut aad = [0; MAX_AAD_LEN];
+        aad[0] = version;
+        let mut len = 1;
+        for field in fields {
+            aad[len..len + field.len()].copy_from_slice(field);
+            len += field.len();
+        }
+        (aad, len)
+    }
+
+    /// Returns the length of the IP header at the start of `buf`, or `None` if an IPv4 header
+    /// claims to be shorter than the fixed part of one.
+    fn ip_header_len(buf: &[u8], version: u8) -> Option<usize> {
+        if version == 6 {
+            return Some(IPV6_HEADER_LEN);
+        }
+        let header_len = (buf.first()? & 15) as usize * 4;
+        (header_len >= IPV4_HEADER_LEN).then_some(header_len)
+    }
+
+    /// Sets a new length; the length grows by the size of the tag, index and counter if it's an
+    /// encryption process, else it shrinks by it.
+    /// The IPv4 header format https://en.wikipedia.org/wiki/IPv4#Header helps us know where
+    /// the needed data is stored for ipv4 packets. The IPv4 header format
+    /// https://en.wikipedia.org/wiki/IPv6_packet#Fixed_header helps us know where. Returns the header length
+    fn configure_header(buf: &mut [u8], version: u8, is_encrypt: bool) -> usize {
+        let mut length = if version == 4 {
+            u16::from_be_bytes([buf[2], buf[3]])
+        } else {
+            u16::from_be_bytes([buf[4], buf[5]])
+        };
+        let overhead = (TAG_LEN + INDEX_LEN + COUNTER_LEN) as u16;
+        if is_encrypt {
+            length = length.wrapping_add(overhead);
+        } else {
+            length = length.wrapping_sub(overhead);
+        }
+        let bytes = length.to_be_bytes();
+        let mut header_length;
+        if version == 4 {
+            buf[2] = bytes[0];
+            buf[3] = bytes[1];
+            header_length = (buf[0] & 15) as usize;
+            header_length *= 4;
+            packet::set_header_checksum(&mut buf[..header_length]);
+        } else {
+            buf[4] = bytes[0];
+            buf[5] = bytes[1];
+            header_length = IPV6_HEADER_LEN;
+        }
+        header_length
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{IpAddr, TcpListener, TcpStream};
+    use std::thread;
+
+    use etherparse::PacketBuilder;
+
+    use super::*;
+    use crate::datagram::{MemoryNetwork, MemorySocket};
+
+    const SERVER_IP: [u8; 4] = [10, 0, 0, 1];
+    const CLIENT_IP: [u8; 4] = [10, 0, 0, 2];
+    const SERVER_KEY: [u8; KEY_LEN] = [9; KEY_LEN];
+
+    /// Addresses of a link's sockets on its memory network.
+    const SERVER_ADDR: &str = "192.0.2.1:2000";
+    const CLIENT_ADDR: &str = "192.0.2.2:2000";
+    const MIDDLE_ADDR: &str = "192.0.2.3:2000";
+    /// The host part of the address of the first socket bound after the link's own.
+    const FIRST_OTHER_HOST: u8 = 10;
+
+    /// A server and a client on a memory network. Every datagram between them goes through the
+    /// middle, where it can be looked at, dropped or sent again.
+    struct Link {
+        server: Net,
+        client: Net,
+        network: MemoryNetwork,
+        middle: MemorySocket,
+        server_addr: SocketAddr,
+        /// Where the client's datagrams came from, once one did.
+        client_addr: Option<SocketAddr>,
+        /// The host part of the address the next socket is bound to.
+        next_host: u8,
+    }
+
+    impl Link {
+        fn new(server_config: Config, client_config: Config) -> Link {
+            let network = MemoryNetwork::new();
+            let server_addr = SERVER_ADDR.parse().unwrap();
+            let server_socket = network.bind(server_addr).unwrap();
+            let server = Net::with_sockets(&server_config, vec![Box::new(server_socket)]).unwrap();
+            let middle = network.bind(middle_addr()).unwrap();
+            let client = client_of(&network, CLIENT_ADDR.parse().unwrap(), client_config);
+            Link {
+                server,
+                client,
+                network,
+                middle,
+                server_addr,
+                client_addr: None,
+                next_host: FIRST_OTHER_HOST,
+            }
+        }
+
+        /// Sets up a link between peers with the same password and completes the handshake.
+        fn connected(configure: impl Fn(&mut Config)) -> Link {
+            let (mut server_config, mut client_config) = (config(false), config(true));
+            configure(&mut server_config);
+            configure(&mut client_config);
+            let mut link = Link::new(server_config, client_config);
+            link.forward();
+            assert!(link.server.recv().unwrap().0.is_empty());
+            link.forward();
+            assert!(link.client.recv().unwrap().0.is_empty());
+            link
+        }
+
+        /// Returns an address on the link's network that nothing is bound to yet.
+        fn next_addr(&mut self) -> SocketAddr {
+            self.next_host += 1;
+            SocketAddr::from(([192, 0, 2, self.next_host - 1], 2000))
+        }
+
+        /// Binds a socket at an address of its own, such as another network a client moved to.
+        fn bind(&mut self) -> MemorySocket {
+            let addr = self.next_addr();
+            self.network.bind(addr).unwrap()
+        }
+
+        /// Makes another client of the middle, at an address of its own.
+        fn new_client(&mut self, config: Config) -> Net {
+            let addr = self.next_addr();
+            client_of(&self.network, addr, config)
+        }
+
+        /// Connects another client with the same password through the middle, and completes
+        /// its handshake.
+        fn add_client(&mut self) -> Net {
+            let mut client = self.new_client(config(true));
+            self.forward();
+            assert_eq!(recv(&mut self.server).unwrap(), b"");
+            self.forward();
+            assert_eq!(recv(&mut client).unwrap(), b"");
+            client
+        }
+
+        /// Takes the next datagram off the link without passing it on.
+        fn take(&mut self) -> (Vec<u8>, SocketAddr) {
+            let mut buf = [0; 4096];
+            let (len, from) = self.middle.recv_from(&mut buf).unwrap();
+            let from = from.as_socket().unwrap();
+            if from != self.server_addr {
+                self.client_addr = Some(from);
+            }
+            (buf[..len].to_vec(), from)
+        }
+
+        /// Passes the next datagram on to where it was going, and returns it.
+        fn forward(&mut self) -> Vec<u8> {
+            let (datagram, from) = self.take();
+            self.inject(&datagram, from == self.server_addr);
+            datagram
+        }
+
+        /// Returns whether no datagram is waiting on the link.
+        fn is_idle(&self) -> bool {
+            !is_readable(&self.middle)
+        }
+
+        /// Puts a datagram on the link as if the server, or else the client, sent it.
+        fn inject(&self, datagram: &[u8], from_server: bool) {
+            let to = match from_server {
+                true => self.client_addr.unwrap(),
+                false => self.server_addr,
+            };
+            self.middle.send_to(datagram, Some(&to.into())).unwrap();
+        }
+    }
+
+    fn middle_addr() -> SocketAddr {
+        MIDDLE_ADDR.parse().unwrap()
+    }
+
+    /// Makes a client of the middle with a socket bound to `addr`, connected to the middle
+    /// unless it talks to other clients directly.
+    fn client_of(network: &MemoryNetwork, addr: SocketAddr, config: Config) -> Net {
+        let mut socket = network.bind(addr).unwrap();
+        if !config.peer_to_peer {
+            socket.connect(middle_addr());
+        }
+        let config = Config {
+            remote_addr: MIDDLE_ADDR.to_owned(),
+            ..config
+        };
+        Net::with_sockets(&config, vec![Box::new(socket)]).unwrap()
+    }
+
+    /// Returns whether a descriptor has something to read, without waiting.
+    fn is_readable(fd: &impl AsRawFd) -> bool {
+        let fd = fd.as_raw_fd();
+        let mut pollfd = libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        unsafe { libc::poll(&mut pollfd, 1, 0) == 1 }
+    }
+
+    /// The server always has the same key, which its clients know.
+    fn config(is_client: bool) -> Config {
+        let server_key = *Identity::from_private_key(&SERVER_KEY).public_key();
+        Config {
+            is_client,
+            key: "password".to_owned(),
+            iterations: 1,
+            private_key: (!is_client).then_some(SERVER_KEY),
+            peer_key: is_client.then_some(server_key),
+            ..Default::default()
+        }
+    }
+
+    fn ipv4_packet(source: [u8; 4], destination: [u8; 4], payload: &[u8]) -> Vec<u8> {
+        let builder = PacketBuilder::ipv4(source, destination, 64).udp(1000, 2000);
+        let mut packet = Vec::with_capacity(builder.size(payload.len()));
+        builder.write(&mut packet, payload).unwrap();
+        packet
+    }
+
+    /// Sends a packet from a buffer with room for what sealing adds.
+    fn send(net: &mut Net, packet: &[u8]) -> usize {
+        let mut buf = [0; 4096];
+        buf[..packet.len()].copy_from_slice(packet);
+        net.send(&mut buf, packet.len())
+    }
+
+    fn recv(net: &mut Net) -> Result<Vec<u8>, tunerror::Error> {
+        net.recv().map(|(packet, _)| packet)
+    }
+
+    /// Copies the packets of a batch out of the buffers they were received into.
+    fn recv_batch(net: &mut Net) -> Vec<Result<Vec<u8>, tunerror::Error>> {
+        let mut results = vec![];
+        net.recv_batch(|result| results.push(result.map(<[u8]>::to_vec)))
+            .unwrap();
+        results
+    }
+
+    fn packet_bufs(packets: &[impl AsRef<[u8]>]) -> Vec<PacketBuf> {
+        packets
+            .iter()
+            .map(|packet| {
+                let packet = packet.as_ref();
+                let mut buf = PacketBuf::new();
+                buf.room_mut()[..packet.len()].copy_from_slice(packet);
+                buf.set_len(packet.len());
+                buf
+            })
+            .collect()
+    }
+
+    #[test]
+    fn handshake_then_packets_in_both_directions() {
+        let mut link = Link::connected(|_| {});
+        let request = ipv4_packet(CLIENT_IP, SERVER_IP, b"request");
+        assert!(send(&mut link.client, &request) > request.len());
+        link.forward();
+        assert_eq!(recv(&mut link.server).unwrap(), request);
+        // The server learned where the client's address is from its packet
+        let response = ipv4_packet(SERVER_IP, CLIENT_IP, b"response");
+        send(&mut link.server, &response);
+        link.forward();
+        assert_eq!(recv(&mut link.client).unwrap(), response);
+    }
+
+    #[test]
+    fn packets_wait_for_the_handshake() {
+        let mut link = Link::new(config(false), config(true));
+        let packet = ipv4_packet(CLIENT_IP, SERVER_IP, b"early");
+        assert_eq!(send(&mut link.client, &packet), 0);
+        link.forward();
+        recv(&mut link.server).unwrap();
+        link.forward();
+        // The queued packet goes out as soon as the response is in
+        recv(&mut link.client).unwrap();
+        link.forward();
+        assert_eq!(recv(&mut link.server).unwrap(), packet);
+    }
+
+    #[test]
+    fn every_packet_carries_the_next_counter() {
+        let mut link = Link::connected(|_| {});
+        let packet = ipv4_packet(CLIENT_IP, SERVER_IP, b"same payload");
+        send(&mut link.client, &packet);
+        send(&mut link.client, &packet);
+        let (first, second) = (link.forward(), link.forward());
+        let counter = |datagram: &[u8]| {
+            u64::from_be_bytes(datagram[datagram.len() - COUNTER_LEN..].try_into().unwrap())
+        };
+        assert_eq!((counter(&first), counter(&second)), (0, 1));
+        // Under another nonce, the same packet is sealed into something else
+        assert_ne!(first, second);
+        assert_eq!(recv(&mut link.server).unwrap(), packet);
+        assert_eq!(recv(&mut link.server).unwrap(), packet);
+    }
+
+    #[test]
+    fn replayed_packets_are_dropped_and_counted() {
+        let mut link = Link::connected(|_| {});
+        let packet = ipv4_packet(CLIENT_IP, SERVER_IP, b"once");
+        send(&mut link.client, &packet);
+        let datagram = link.forward();
+        assert_eq!(recv(&mut link.server).unwrap(), packet);
+        link.inject(&datagram, false);
+        let result = recv(&mut link.server);
+        assert!(matches!(result, Err(tunerror::Error::Replay(0))));
+        assert_eq!(link.server.replayed_packets(), 1);
+    }
+
+    #[test]
+    fn handshake_fails_without_a_common_cipher_suite() {
+        let mut server_config = config(false);
+        server_config.cipher_suites = vec![CipherSuite::ChaCha20Poly1305];
+        let mut client_config = config(true);
+        client_config.cipher_suites = vec![CipherSuite::Aes256Gcm];
+        let mut link = Link::new(server_config, client_config);
+        link.forward();
+        let result = recv(&mut link.server);
+        assert!(matches!(result, Err(tunerror::Error::NoCommonCipherSuite)));
+        assert!(link.is_idle());
+    }
+
+    #[test]
+    fn handshake_fails_with_another_server_key() {
+        let mut client_config = config(true);
+        client_config.peer_key = Some(*Identity::from_private_key(&[3; KEY_LEN]).public_key());
+        let mut link = Link::new(config(false), client_config);
+        link.forward();
+        // The initiation is encrypted to a key the server doesn't own
+        let result = recv(&mut link.server);
+        assert!(matches!(result, Err(tunerror::Error::Handshake(_))));
+    }
+
+    #[test]
+    fn clients_need_the_server_key() {
+        let mut client_config = config(true);
+        client_config.peer_key = None;
+        client_config.remote_addr = "127.0.0.1:9".to_owned();
+        let result = Net::new(&client_config);
+        assert!(matches!(result, Err(tunerror::Error::Message(_))));
+    }
+
+    #[test]
+    fn replayed_initiations_are_refused() {
+        let mut link = Link::new(config(false), config(true));
+        let initiation = link.forward();
+        recv(&mut link.server).unwrap();
+        link.inject(&initiation, false);
+        let result = recv(&mut link.server);
+        assert!(
+            matches!(&result, Err(tunerror::Error::Handshake(reason)) if reason == "replayed initiation"),
+            "{result:?}"
+        );
+    }
+
+    #[test]
+    fn forgotten_initiators_leave_a_floor_behind() {
+        let mut initiations = Initiations::default();
+        let timestamp = |n: u32| {
+            let mut timestamp = [0; 12];
+            timestamp[8..].copy_from_slice(&n.to_be_bytes());
+            timestamp
+        };
+        let key = |n: u32| {
+            let mut key = [0; KEY_LEN];
+            key[..4].copy_from_slice(&n.to_be_bytes());
+            key
+        };
+        for n in 0..MAX_REMEMBERED_INITIATORS as u32 {
+            initiations.accept(key(n), timestamp(n + 10));
+        }
+        assert!(initiations.is_replayed(&key(0), &timestamp(10)));
+        // The oldest key is forgotten to make room, but its initiations stay refused
+        initiations.accept(key(u32::MAX), timestamp(u32::MAX));
+        assert!(!initiations.newest.contains_key(&key(0)));
+        assert!(initiations.is_replayed(&key(0), &timestamp(10)));
+        assert!(!initiations.is_replayed(&key(0), &timestamp(11)));
+    }
+
+    #[test]
+    fn restarted_client_never_reuses_a_nonce() {
+        let client_config = || Config {
+            private_key: Some([1; KEY_LEN]),
+            ..config(true)
+        };
+        let mut link = Link::new(config(false), client_config());
+        link.forward();
+        recv(&mut link.server).unwrap();
+        link.forward();
+        recv(&mut link.client).unwrap();
+        let packet = ipv4_packet(CLIENT_IP, SERVER_IP, b"same packet");
+        send(&mut link.client, &packet);
+        let (before, _) = link.take();
+        // The same client starts over, with the same key, and its counters start at 0 again
+        let mut restarted = link.new_client(client_config());
+        link.forward();
+        recv(&mut link.server).unwrap();
+        link.forward();
+        recv(&mut restarted).unwrap();
+        send(&mut restarted, &packet);
+        let after = link.forward();
+        let counter = |datagram: &[u8]| datagram[datagram.len() - COUNTER_LEN..].to_vec();
+        assert_eq!(counter(&before), counter(&after));
+        assert_ne!(before, after);
+        assert_eq!(recv(&mut link.server).unwrap(), packet);
+    }
+
+    /// Makes every session of every peer look older by `age`.
+    fn age_sessions(net: &mut Net, age: Duration) {
+        for peer in net.peers.values_mut() {
+            let sessions = [&mut peer.session, &mut peer.previous_session];
+            for session in sessions.into_iter().flatten() {
+                session.created -= age;
+            }
+        }
+    }
+
+    /// Returns whether a datagram is a handshake initiation.
+    fn is_initiation(datagram: &[u8]) -> bool {
+        datagram[0] == handshake::HANDSHAKE_INITIATION
+            && datagram.len() == handshake::INITIATION_LEN
+    }
+
+    #[test]
+    fn clients_rekey_after_enough_packets() {
+        let mut link = Link::connected(|config| config.rekey_after_packets = 3);
+        for i in 0..3 {
+            send(&mut link.client, &ipv4_packet(CLIENT_IP, SERVER_IP, &[i]));
+        }
+        // The third packet used up the session, and a handshake follows it
+        for _ in 0..3 {
+            assert!(!is_initiation(&link.take().0));
+        }
+        assert!(is_initiation(&link.take().0));
+        assert!(link.is_idle());
+    }
+
+    #[test]
+    fn clients_rekey_sessions_that_are_old_enough() {
+        let mut link = Link::connected(|config| config.rekey_after_time = Duration::from_secs(60));
+        let packet = ipv4_packet(CLIENT_IP, SERVER_IP, b"old");
+        age_sessions(&mut link.client, Duration::from_secs(59));
+        send(&mut link.client, &packet);
+        link.forward();
+        assert_eq!(recv(&mut link.server).unwrap(), packet);
+        assert!(link.is_idle());
+        age_sessions(&mut link.client, Duration::from_secs(1));
+        send(&mut link.client, &packet);
+        assert!(!is_initiation(&link.take().0));
+        assert!(is_initiation(&link.take().0));
+        // Only the side that started the session replaces it
+        age_sessions(&mut link.server, Duration::from_secs(60));
+        let reply = ipv4_packet(SERVER_IP, CLIENT_IP, b"old");
+        send(&mut link.server, &reply);
+        link.forward();
+        assert_eq!(recv(&mut link.client).unwrap(), reply);
+        assert!(link.is_idle());
+    }
+
+    #[test]
+    fn packets_sealed_before_a_rekey_still_open_after_it() {
+        let mut link = Link::connected(|config| config.rekey_after_packets = 2);
+        let packets: Vec<Vec<u8>> = (0..2)
+            .map(|i| ipv4_packet(CLIENT_IP, SERVER_IP, &[i; 10]))
+            .collect();
+        for packet in &packets {
+            send(&mut link.client, packet);
+        }
+        // Held back until both sides switched to the new session
+        let in_flight = [link.take().0, link.take().0];
+        link.forward();
+        assert_eq!(recv(&mut link.server).unwrap(), b"");
+        link.forward();
+        assert_eq!(recv(&mut link.client).unwrap(), b"");
+        for (datagram, packet) in in_flight.iter().zip(&packets) {
+            link.inject(datagram, false);
+            assert_eq!(&recv(&mut link.server).unwrap(), packet);
+        }
+        let packet = ipv4_packet(CLIENT_IP, SERVER_IP, b"new session");
+        send(&mut link.client, &packet);
+        link.forward();
+        assert_eq!(recv(&mut link.server).unwrap(), packet);
+        let reply = ipv4_packet(SERVER_IP, CLIENT_IP, b"new session");
+        send(&mut link.server, &reply);
+        link.forward();
+        assert_eq!(recv(&mut link.client).unwrap(), reply);
+    }
+
+    #[test]
+    fn sessions_are_refused_at_one_and_a_half_times_the_rekey_time() {
+        let mut link = Link::connected(|config| config.rekey_after_time = Duration::from_secs(60));
+        let packet = ipv4_packet(CLIENT_IP, SERVER_IP, b"late");
+        age_sessions(&mut link.server, Duration::from_secs(89));
+        send(&mut link.client, &packet);
+        link.forward();
+        assert_eq!(recv(&mut link.server).unwrap(), packet);
+        age_sessions(&mut link.server, Duration::from_secs(1));
+        send(&mut link.client, &packet);
+        link.forward();
+        assert!(matches!(
+            recv(&mut link.server),
+            Err(tunerror::Error::UnknownPeer)
+        ));
+        // A client whose session expired holds its packets back for a new handshake
+        age_sessions(&mut link.client, Duration::from_secs(90));
+        assert_eq!(send(&mut link.client, &packet), 0);
+        assert!(is_initiation(&link.take().0));
+    }
+
+    #[test]
+    fn full_encapsulation_hides_the_inner_header() {
+        let mut link = Link::connected(|config| config.encapsulation = Encapsulation::Full);
+        let packet = ipv4_packet(CLIENT_IP, SERVER_IP, b"hidden");
+        send(&mut link.client, &packet);
+        let (datagram, _) = link.take();
+        assert_eq!(datagram[0], TRANSPORT_DATA);
+        assert_eq!(datagram.len(), DATA_HEADER_LEN + packet.len() + TAG_LEN);
+        for ip in [CLIENT_IP, SERVER_IP] {
+            assert!(!datagram.windows(4).any(|window| window == ip));
+        }
+        link.inject(&datagram, false);
+        assert_eq!(recv(&mut link.server).unwrap(), packet);
+        let reply = ipv4_packet(SERVER_IP, CLIENT_IP, b"hidden");
+        send(&mut link.server, &reply);
+        assert_eq!(link.forward()[0], TRANSPORT_DATA);
+        assert_eq!(recv(&mut link.client).unwrap(), reply);
+    }
+
+    #[test]
+    fn padded_packets_lose_their_padding_on_the_way_in() {
+        let mut link = Link::connected(|config| {
+            config.encapsulation = Encapsulation::Full;
+            config.padding = Padding::Mtu;
+        });
+        let packet = ipv4_packet(CLIENT_IP, SERVER_IP, b"short");
+        send(&mut link.client, &packet);
+        let datagram = link.forward();
+        assert_eq!(datagram.len(), DEFAULT_PATH_MTU - UDP_OVERHEAD);
+        assert_eq!(recv(&mut link.server).unwrap(), packet);
+        let reply = ipv4_packet(SERVER_IP, CLIENT_IP, &[1; 1000]);
+        send(&mut link.server, &reply);
+        assert_eq!(link.forward().len(), datagram.len());
+        assert_eq!(recv(&mut link.client).unwrap(), reply);
+    }
+
+    #[test]
+    fn packets_longer_than_their_header_says_are_only_padded() {
+        let mut link = Link::connected(|config| config.encapsulation = Encapsulation::Full);
+        let mut packet = ipv4_packet(CLIENT_IP, SERVER_IP, b"tail");
+        let len = packet.len();
+        // A header claiming more than was sealed is refused
+        packet[3] += 1;
+        packet::set_header_checksum(&mut packet[..20]);
+        send(&mut link.client, &packet);
+        link.forward();
+        assert!(recv(&mut link.server).is_err());
+        packet[3] -= 2;
+        packet::set_header_checksum(&mut packet[..20]);
+        send(&mut link.client, &packet);
+        link.forward();
+        assert_eq!(recv(&mut link.server).unwrap(), packet[..len - 1]);
+    }
+
+    #[test]
+    fn cover_packets_look_like_data_and_are_dropped() {
+        let mut link = Link::connected(|config| {
+            config.encapsulation = Encapsulation::Full;
+            config.padding = Padding::Multiple(256);
+            config.cover_rate = 2;
+        });
+        for _ in 0..2 {
+            pass_time(&mut link.client, TICK_INTERVAL / 2);
+            link.client.tick();
+            let datagram = link.forward();
+            assert_eq!(datagram[0], TRANSPORT_DATA);
+            let len = datagram.len() - DATA_HEADER_LEN - TAG_LEN;
+            assert!(
+                len.is_multiple_of(256) || len == link.client.max_padded_len,
+                "{len}"
+            );
+            assert_eq!(link.server.recv().unwrap().0, b"");
+            // The next one is due half a second later, not in the same burst
+            link.client.tick();
+            assert!(link.is_idle());
+            assert!(link.client.tick_timeout() > TICK_INTERVAL / 4);
+            assert!(link.client.tick_timeout() <= TICK_INTERVAL / 2);
+        }
+    }
+
+    #[test]
+    fn padding_and_cover_traffic_need_full_encapsulation() {
+        let padded = Config {
+            padding: Padding::Random(32),
+            ..config(false)
+        };
+        assert!(Net::new(&padded).is_err());
+        let covered = Config {
+            cover_rate: 1,
+            encapsulation: Encapsulation::Full,
+            key: String::new(),
+            private_key: None,
+            ..config(false)
+        };
+        assert!(Net::new(&covered).is_err());
+    }
+
+    #[test]
+    fn datagrams_longer_than_the_path_mtu_go_in_fragments() {
+        let mut link = Link::connected(|config| config.path_mtu = MIN_PATH_MTU);
+        let packet = ipv4_packet(CLIENT_IP, SERVER_IP, &[7; 1200]);
+        let sealed_len = send(&mut link.client, &packet);
+        let max_len = MIN_PATH_MTU - UDP_OVERHEAD;
+        let count = sealed_len.div_ceil(max_len - fragment::HEADER_LEN);
+        for _ in 1..count {
+            let datagram = link.forward();
+            assert_eq!((datagram[0], datagram.len()), (fragment::FRAGMENT, max_len));
+            assert_eq!(recv(&mut link.server).unwrap(), b"");
+        }
+        assert!(link.forward().len() <= max_len);
+        assert_eq!(recv(&mut link.server).unwrap(), packet);
+        // Short packets still go whole
+        let reply = ipv4_packet(SERVER_IP, CLIENT_IP, b"short");
+        send(&mut link.server, &reply);
+        assert_eq!(link.forward()[0] >> 4, 4);
+        assert_eq!(recv(&mut link.client).unwrap(), reply);
+    }
+
+    #[test]
+    fn datagrams_stay_within_the_receive_buffer_on_jumbo_paths() {
+        let mut link = Link::connected(|config| {
+            config.encapsulation = Encapsulation::Full;
+            config.padding = Padding::Mtu;
+            config.cover_rate = 1;
+            config.path_mtu = 9000;
+        });
+        let packet = ipv4_packet(CLIENT_IP, SERVER_IP, b"short");
+        send(&mut link.client, &packet);
+        assert_eq!(link.forward().len(), MAX_DATAGRAM_LEN);
+        assert_eq!(recv(&mut link.server).unwrap(), packet);
+        pass_time(&mut link.client, TICK_INTERVAL);
+        link.client.tick();
+        assert_eq!(link.forward().len(), MAX_DATAGRAM_LEN);
+        assert_eq!(recv(&mut link.server).unwrap(), b"");
+    }
+
+    #[test]
+    fn packets_without_room_to_be_sealed_are_dropped() {
+        let mut link = Link::connected(|_| {});
+        let mut packet = ipv4_packet(CLIENT_IP, SERVER_IP, b"no room");
+        let len = packet.len();
+        assert_eq!(link.client.send(&mut packet, len), 0);
+        assert!(link.is_idle());
+        let tiny = Config {
+            path_mtu: MIN_PATH_MTU - 1,
+            ..config(false)
+        };
+        assert!(Net::new(&tiny).is_err());
+    }
+
+    #[test]
+    fn batches_carry_packets_both_ways_in_order() {
+        let mut link = Link::connected(|_| {});
+        let packets: Vec<Vec<u8>> = (0..BATCH_SIZE as u8 + 5)
+            .map(|i| ipv4_packet(CLIENT_IP, SERVER_IP, &[i; 100]))
+            .collect();
+        let mut batch = packet_bufs(&packets);
+        let sent = link.client.send_batch(&mut batch);
+        assert_eq!(
+            sent,
+            packets.len() * (packets[0].len() + TAG_LEN + INDEX_LEN + COUNTER_LEN)
+        );
+        for _ in &packets {
+            link.forward();
+        }
+        let mut received = vec![];
+        while received.len() < packets.len() {
+            let results = recv_batch(&mut link.server);
+            assert!(!results.is_empty() && results.len() <= BATCH_SIZE);
+            received.extend(results.into_iter().map(Result::unwrap));
+        }
+        assert_eq!(received, packets);
+        // The server answers in a batch too
+        let reply = ipv4_packet(SERVER_IP, CLIENT_IP, b"reply");
+        link.server.send_batch(&mut packet_bufs(&[&reply]));
+        link.forward();
+        assert_eq!(recv_batch(&mut link.client).remove(0).unwrap(), reply);
+    }
+
+    #[test]
+    fn fully_encapsulated_packets_are_sealed_in_their_headroom() {
+        let mut link = Link::connected(|config| config.encapsulation = Encapsulation::Full);
+        let packet = ipv4_packet(CLIENT_IP, SERVER_IP, b"in place");
+        let mut batch = packet_bufs(&[&packet]);
+        let data = batch[0].packet().as_ptr();
+        link.client.send_batch(&mut batch);
+        // The header went in front of the packet, which didn't move
+        let (_, datagram) = batch[0].parts_mut();
+        assert_eq!(datagram.start, crate::pool::HEADROOM - DATA_HEADER_LEN);
+        assert_eq!(batch[0].packet()[DATA_HEADER_LEN..].as_ptr(), data);
+        assert_eq!(link.forward(), batch[0].packet());
+        assert_eq!(recv_batch(&mut link.server).remove(0).unwrap(), packet);
+    }
+
+    #[test]
+    fn equal_datagrams_go_together_with_gso_or_one_at_a_time() {
+        for gso in [true, false] {
+            let mut server_config = config(false);
+            server_config.port = 0;
+            let mut server = Net::new(&server_config).unwrap();
+            let server_addr = server.sockets[0].local_addr().unwrap();
+            let mut client_config = config(true);
+            client_config.remote_addr = server_addr.as_socket().unwrap().to_string();
+            let mut client = Net::new(&client_config).unwrap();
+            client.gso &= gso;
+            assert_eq!(recv_batch(&mut server).remove(0).unwrap(), b"");
+            assert_eq!(recv_batch(&mut client).remove(0).unwrap(), b"");
+            // The last packet is shorter, so it can end the same message
+            let mut packets: Vec<Vec<u8>> = (0..10)
+                .map(|i| ipv4_packet(CLIENT_IP, SERVER_IP, &[i; 100]))
+                .collect();
+            packets.push(ipv4_packet(CLIENT_IP, SERVER_IP, b"last"));
+            let mut batch = packet_bufs(&packets);
+            client.send_batch(&mut batch);
+            let received = recv_batch(&mut server);
+            // Put together by GRO, they all arrive with the first message
+            if client.gso && server.gro == Some(true) {
+                assert_eq!(received.len(), packets.len());
+            }
+            let mut received: Vec<Vec<u8>> = received.into_iter().map(Result::unwrap).collect();
+            while received.len() < packets.len() {
+                let results = recv_batch(&mut server);
+                received.extend(results.into_iter().map(Result::unwrap));
+            }
+            assert_eq!(received, packets);
+        }
+    }
+
+    #[test]
+    fn gso_runs_end_at_a_shorter_datagram_or_another_address() {
+        let (one, two): (SockAddr, SockAddr) = (
+            SocketAddr::from((Ipv4Addr::LOCALHOST, 1)).into(),
+            SocketAddr::from((Ipv4Addr::LOCALHOST, 2)).into(),
+        );
+        let (long, short) = ([0; 100], [0; 50]);
+        let slices = [&long, &long, &short[..], &short, &long, &short].map(IoSlice::new);
+        let addrs = [&one, &one, &one, &one, &one, &two];
+        let ends: Vec<usize> = [0, 3, 4, 5]
+            .into_iter()
+            .map(|start| gso_run_end(&slices, &addrs, start))
+            .collect();
+        assert_eq!(ends, [3, 4, 5, 6]);
+        let slices = [IoSlice::new(&long); mmsg::MAX_SEGMENTS + 1];
+        let addrs = [&one; mmsg::MAX_SEGMENTS + 1];
+        assert_eq!(gso_run_end(&slices, &addrs, 0), mmsg::MAX_SEGMENTS);
+    }
+
+    #[test]
+    fn peers_accept_both_layouts() {
+        let mut client_config = config(true);
+        client_config.encapsulation = Encapsulation::Full;
+        let mut link = Link::new(config(false), client_config);
+        link.forward();
+        recv(&mut link.server).unwrap();
+        link.forward();
+        recv(&mut link.client).unwrap();
+        let packet = ipv4_packet(CLIENT_IP, SERVER_IP, b"full");
+        send(&mut link.client, &packet);
+        assert_eq!(link.forward()[0], TRANSPORT_DATA);
+        assert_eq!(recv(&mut link.server).unwrap(), packet);
+        // The server keeps sending the inner header in cleartext
+        let reply = ipv4_packet(SERVER_IP, CLIENT_IP, b"header");
+        send(&mut link.server, &reply);
+        assert_eq!(link.forward()[12..20], reply[12..20]);
+        assert_eq!(recv(&mut link.client).unwrap(), reply);
+    }
+
+    #[test]
+    fn tampered_packets_fail_authentication() {
+        let mut link = Link::connected(|_| {});
+        send(
+            &mut link.client,
+            &ipv4_packet(CLIENT_IP, SERVER_IP, b"payload"),
+        );
+        let (mut datagram, _) = link.take();
+        datagram[30] ^= 1;
+        link.inject(&datagram, false);
+        let result = recv(&mut link.server);
+        assert!(matches!(result, Err(tunerror::Error::AuthenticationFailed)));
+    }
+
+    #[test]
+    fn rewritten_addresses_fail_authentication() {
+        let mut link = Link::connected(|_| {});
+        send(
+            &mut link.client,
+            &ipv4_packet(CLIENT_IP, SERVER_IP, b"payload"),
+        );
+        let (mut datagram, _) = link.take();
+        // Another client's address, so the server would send its traffic here
+        datagram[12..16].copy_from_slice(&[10, 0, 0, 3]);
+        link.inject(&datagram, false);
+        let result = recv(&mut link.server);
+        assert!(matches!(result, Err(tunerror::Error::AuthenticationFailed)));
+    }
+
+    #[test]
+    fn tampered_data_headers_fail_authentication() {
+        let mut link = Link::connected(|config| config.encapsulation = Encapsulation::Full);
+        send(
+            &mut link.client,
+            &ipv4_packet(CLIENT_IP, SERVER_IP, b"payload"),
+        );
+        let (mut datagram, _) = link.take();
+        datagram[1] = 1;
+        link.inject(&datagram, false);
+        let result = recv(&mut link.server);
+        assert!(matches!(result, Err(tunerror::Error::AuthenticationFailed)));
+    }
+
+    #[test]
+    fn malformed_datagrams_are_errors() {
+        let mut link = Link::connected(|_| {});
+        let packet = ipv4_packet(CLIENT_IP, SERVER_IP, b"genuine");
+        send(&mut link.client, &packet);
+        let (genuine, _) = link.take();
+        let trailer = &genuine[genuine.len() - INDEX_LEN - COUNTER_LEN..];
+        // A live session's trailer behind a header too short to be read
+        let short_ipv6 = [&[0x60; 20][..], trailer].concat();
+        link.inject(&short_ipv6, false);
+        let result = recv(&mut link.server);
+        assert!(matches!(result, Err(tunerror::Error::ShortPacket(32))));
+        let bad_ihl = [&[0x41; 20][..], trailer].concat();
+        link.inject(&bad_ihl, false);
+        let result = recv(&mut link.server);
+        assert!(matches!(result, Err(tunerror::Error::InvalidPacket)));
+        link.inject(&[TRANSPORT_DATA; 10], false);
+        let result = recv(&mut link.server);
+        assert!(matches!(result, Err(tunerror::Error::ShortPacket(10))));
+        link.inject(&genuine, false);
+        assert_eq!(recv(&mut link.server).unwrap(), packet);
+    }
+
+    #[test]
+    fn forged_packets_change_nothing() {
+        let mut link = Link::connected(|_| {});
+        let packet = ipv4_packet(CLIENT_IP, SERVER_IP, b"genuine");
+        send(&mut link.client, &packet);
+        let (genuine, _) = link.take();
+        // A forged packet far ahead in the session mustn't move the replay window
+        let mut forged = genuine.clone();
+        let counter = forged.len() - COUNTER_LEN;
+        forged[counter..].copy_from_slice(&(10 * replay::WINDOW_SIZE).to_be_bytes());
+        link.inject(&forged, false);
+        let result = recv(&mut link.server);
+        assert!(matches!(result, Err(tunerror::Error::AuthenticationFailed)));
+        // Nor may datagrams that aren't from any session add peers
+        for i in 0..100u8 {
+            link.inject(&[TRANSPORT_DATA, i].repeat(40), false);
+            assert!(recv(&mut link.server).is_err());
+        }
+        assert_eq!(link.server.peers.len(), 1);
+        link.inject(&genuine, false);
+        assert_eq!(recv(&mut link.server).unwrap(), packet);
+    }
+
+    #[test]
+    fn sources_outside_the_allowed_prefixes_are_dropped() {
+        let client_key = [1; KEY_LEN];
+        let client_public_key = *Identity::from_private_key(&client_key).public_key();
+        let mut client_config = config(true);
+        client_config.private_key = Some(client_key);
+        let mut server_config = config(false);
+        server_config.peers = vec![PeerConfig {
+            public_key: client_public_key,
+            allowed_ips: vec!["10.0.0.2/32".parse().unwrap()],
+        }];
+        let mut link = Link::new(server_config, client_config);
+        link.forward();
+        recv(&mut link.server).unwrap();
+        link.forward();
+        recv(&mut link.client).unwrap();
+        let allowed = ipv4_packet(CLIENT_IP, SERVER_IP, b"allowed");
+        send(&mut link.client, &allowed);
+        link.forward();
+        assert_eq!(recv(&mut link.server).unwrap(), allowed);
+        send(
+            &mut link.client,
+            &ipv4_packet([10, 0, 0, 3], SERVER_IP, b"spoofed"),
+        );
+        link.forward();
+        let result = recv(&mut link.server);
+        let spoofed = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 3));
+        assert!(matches!(result, Err(tunerror::Error::SourceNotAllowed(ip)) if ip == spoofed));
+    }
+
+    #[test]
+    fn servers_with_configured_peers_refuse_other_keys() {
+        let mut server_config = config(false);
+        server_config.peers = vec![PeerConfig {
+            public_key: [1; KEY_LEN],
+            allowed_ips: vec!["10.0.0.2/32".parse().unwrap()],
+        }];
+        let mut link = Link::new(server_config, config(true));
+        link.forward();
+        let result = recv(&mut link.server);
+        assert!(matches!(result, Err(tunerror::Error::Handshake(_))));
+        assert!(link.is_idle());
+    }
+
+    #[test]
+    fn learned_addresses_belong_to_their_first_client() {
+        let mut link = Link::connected(|_| {});
+        let packet = ipv4_packet(CLIENT_IP, SERVER_IP, b"first");
+        send(&mut link.client, &packet);
+        link.forward();
+        assert_eq!(recv(&mut link.server).unwrap(), packet);
+        let owner = |net: &Net| net.allowed_ips.lookup(IpAddr::from(CLIENT_IP)).copied();
+        let first = owner(&link.server);
+        // Another client with the password claims the same address
+        let mut other = link.add_client();
+        send(&mut other, &ipv4_packet(CLIENT_IP, SERVER_IP, b"taken"));
+        link.forward();
+        let result = recv(&mut link.server);
+        assert!(matches!(result, Err(tunerror::Error::SourceNotAllowed(_))));
+        assert_eq!(owner(&link.server), first);
+        // The first client keeps using it
+        send(&mut link.client, &packet);
+        link.forward();
+        assert_eq!(recv(&mut link.server).unwrap(), packet);
+    }
+
+    #[test]
+    fn loaded_servers_want_a_cookie_before_the_handshake() {
+        let mut server_config = config(false);
+        server_config.handshake_load_threshold = 0;
+        let mut link = Link::new(server_config, config(true));
+        // The first initiation only gets a cookie back
+        link.forward();
+        assert_eq!(recv(&mut link.server).unwrap(), b"");
+        assert!(link.server.peers.is_empty());
+        // The client retries with it in its MAC2 at once
+        link.forward();
+        assert_eq!(recv(&mut link.client).unwrap(), b"");
+        assert!(link.client.peers.values().all(|peer| peer.cookie.is_some()));
+        link.forward();
+        assert_eq!(recv(&mut link.server).unwrap(), b"");
+        assert_eq!(link.server.peers.len(), 1);
+        link.forward();
+        assert_eq!(recv(&mut link.client).unwrap(), b"");
+        let packet = ipv4_packet(CLIENT_IP, SERVER_IP, b"admitted");
+        send(&mut link.client, &packet);
+        link.forward();
+        assert_eq!(recv(&mut link.server).unwrap(), packet);
+    }
+
+    #[test]
+    fn servers_listen_on_ipv4_and_ipv6_and_reply_from_where_they_were_reached() {
+        let mut server_config = config(false);
+        server_config.listen_addrs =
+            vec!["127.0.0.1:0".parse().unwrap(), "[::1]:0".parse().unwrap()];
+        let mut server = Net::new(&server_config).unwrap();
+        let mut clients: Vec<(Net, [u8; 4])> = [(0, CLIENT_IP), (1, [10, 0, 0, 3])]
+            .into_iter()
+            .map(|(socket, ip)| {
+                let mut client_config = config(true);
+                client_config.remote_addr = server.sockets[socket]
+                    .local_addr()
+                    .unwrap()
+                    .as_socket()
+                    .unwrap()
+                    .to_string();
+                let mut client = Net::new(&client_config).unwrap();
+                assert_eq!(recv(&mut server).unwrap(), b"");
+                assert_eq!(recv(&mut client).unwrap(), b"");
+                (client, ip)
+            })
+            .collect();
+
+        for (client, ip) in &mut clients {
+            let request = ipv4_packet(*ip, SERVER_IP, b"request");
+            send(client, &request);
+            assert_eq!(recv(&mut server).unwrap(), request);
+            // A reply from the other socket would come from an address the client isn't
+            // connected to, or of the wrong family, and never reach it
+            let response = ipv4_packet(SERVER_IP, *ip, b"response");
+            send(&mut server, &response);
+            assert_eq!(recv(client).unwrap(), response);
+        }
+    }
+
+    #[test]
+    fn authenticated_packets_move_the_endpoint() {
+        let mut link = Link::connected(|_| {});
+        let client_key = *link.client.public_key().unwrap();
+        let middle = middle_addr();
+        assert_eq!(
+            link.server.next_event(),
+            Some(Event::EndpointChanged {
+                public_key: client_key,
+                old: None,
+                new: middle,
+            })
+        );
+        // The client's packets now come from another network
+        let roamed = link.bind();
+        let packet = ipv4_packet(CLIENT_IP, SERVER_IP, b"roaming");
+        send(&mut link.client, &packet);
+        let (datagram, _) = link.take();
+        roamed
+            .send_to(&datagram, Some(&link.server_addr.into()))
+            .unwrap();
+        assert_eq!(recv(&mut link.server).unwrap(), packet);
+        assert_eq!(
+            link.server.next_event(),
+            Some(Event::EndpointChanged {
+                public_key: client_key,
+                old: Some(middle),
+                new: roamed.local_addr().unwrap().as_socket().unwrap(),
+            })
+        );
+        let reply = ipv4_packet(SERVER_IP, CLIENT_IP, b"found you");
+        let len = send(&mut link.server, &reply);
+        let mut buf = [0; 4096];
+        assert_eq!(roamed.recv_from(&mut buf).unwrap().0, len);
+    }
+
+    #[test]
+    fn unauthenticated_packets_never_move_the_endpoint() {
+        let mut link = Link::connected(|_| {});
+        while link.server.next_event().is_some() {}
+        let packet = ipv4_packet(CLIENT_IP, SERVER_IP, b"genuine");
+        send(&mut link.client, &packet);
+        let datagram = link.forward();
+        assert_eq!(recv(&mut link.server).unwrap(), packet);
+        let attacker = link.bind();
+        attacker
+            .send_to(&datagram, Some(&link.server_addr.into()))
+            .unwrap();
+        assert!(matches!(
+            recv(&mut link.server),
+            Err(tunerror::Error::Replay(_))
+        ));
+        send(&mut link.client, &packet);
+        let (mut forged, _) = link.take();
+        forged[30] ^= 1;
+        attacker
+            .send_to(&forged, Some(&link.server_addr.into()))
+            .unwrap();
+        assert!(matches!(
+            recv(&mut link.server),
+            Err(tunerror::Error::AuthenticationFailed)
+        ));
+        assert_eq!(link.server.next_event(), None);
+        // Replies still go through the middle
+        let reply = ipv4_packet(SERVER_IP, CLIENT_IP, b"still here");
+        send(&mut link.server, &reply);
+        link.forward();
+        assert_eq!(recv(&mut link.client).unwrap(), reply);
+    }
+
+    #[test]
+    fn full_servers_refuse_new_peers() {
+        let mut link = Link::connected(|config| config.max_peers = 1);
+        let _other = link.new_client(config(true));
+        link.forward();
+        assert!(matches!(
+            recv(&mut link.server),
+            Err(tunerror::Error::TooManyPeers)
+        ));
+        assert_eq!(link.server.peers.len(), 1);
+        assert!(link.is_idle());
+        // The peer already there is kept
+        let packet = ipv4_packet(CLIENT_IP, SERVER_IP, b"still here");
+        send(&mut link.client, &packet);
+        link.forward();
+        assert_eq!(recv(&mut link.server).unwrap(), packet);
+    }
+
+    #[test]
+    fn full_servers_can_evict_the_idlest_peer_and_its_routes() {
+        let mut link = Link::connected(|config| {
+            config.max_peers = 2;
+            config.peer_limit_policy = PeerLimitPolicy::EvictIdlest;
+        });
+        let mut second = link.add_client();
+        let second_ip = [10, 0, 0, 3];
+        for (client, ip) in [(&mut link.client, CLIENT_IP), (&mut second, second_ip)] {
+            send(client, &ipv4_packet(ip, SERVER_IP, b"hello"));
+        }
+        for _ in 0..2 {
+            link.forward();
+            recv(&mut link.server).unwrap();
+        }
+        // The first client was heard from before the second
+        let first = *link.server.allowed_ips.lookup(CLIENT_IP.into()).unwrap();
+        let last_seen = &mut link.server.peers.get_mut(&first).unwrap().last_seen;
+        *last_seen = last_seen.map(|at| at - Duration::from_secs(1));
+
+        let _third = link.add_client();
+        assert_eq!(link.server.peers.len(), 2);
+        assert!(!link.server.peers.contains_key(&first));
+        assert!(link.server.allowed_ips.lookup(CLIENT_IP.into()).is_none());
+        assert!(link.server.allowed_ips.lookup(second_ip.into()).is_some());
+        let gone = ipv4_packet(SERVER_IP, CLIENT_IP, b"gone");
+        assert_eq!(send(&mut link.server, &gone), 0);
+    }
+
+    #[test]
+    fn idle_peers_lose_their_routes() {
+        let idle_timeout = Duration::from_secs(30);
+        let mut link = Link::connected(|config| config.idle_timeout = idle_timeout);
+        let packet = ipv4_packet(CLIENT_IP, SERVER_IP, b"hello");
+        send(&mut link.client, &packet);
+        link.forward();
+        assert_eq!(recv(&mut link.server).unwrap(), packet);
+        let now = Instant::now();
+        link.server
+            .expire_idle_peers(now + idle_timeout - Duration::from_secs(1));
+        assert!(link.server.allowed_ips.lookup(CLIENT_IP.into()).is_some());
+        link.server.expire_idle_peers(now + idle_timeout);
+        assert!(link.server.peers.is_empty());
+        assert!(link.server.allowed_ips.lookup(CLIENT_IP.into()).is_none());
+        let gone = ipv4_packet(SERVER_IP, CLIENT_IP, b"gone");
+        assert_eq!(send(&mut link.server, &gone), 0);
+    }
+
+    #[test]
+    fn learned_addresses_are_free_again_once_their_client_expired() {
+        let mut link = Link::connected(|_| {});
+        let packet = ipv4_packet(CLIENT_IP, SERVER_IP, b"first");
+        send(&mut link.client, &packet);
+        link.forward();
+        assert_eq!(recv(&mut link.server).unwrap(), packet);
+        let owner = |net: &Net| net.allowed_ips.lookup(IpAddr::from(CLIENT_IP)).copied();
+        let first = owner(&link.server);
+        link.server
+            .expire_idle_peers(Instant::now() + DEFAULT_IDLE_TIMEOUT + Duration::from_secs(1));
+        let mut other = link.add_client();
+        let packet = ipv4_packet(CLIENT_IP, SERVER_IP, b"free");
+        send(&mut other, &packet);
+        link.forward();
+        assert_eq!(recv(&mut link.server).unwrap(), packet);
+        assert_ne!(owner(&link.server), first);
+    }
+
+    /// Makes everything a peer remembers about time look `time` older, so that the next
+    /// [`Net::tick`] acts as if that much time passed.
+    fn pass_time(net: &mut Net, time: Duration) {
+        net.last_tick -= time.max(TICK_INTERVAL);
+        net.next_cover -= time;
+        for peer in net.peers.values_mut() {
+            for at in [&mut peer.last_sent, &mut peer.last_seen] {
+                *at = at.map(|at| at - time);
+            }
+        }
+    }
+
+    #[test]
+    fn idle_peers_send_keepalives() {
+        let interval = Duration::from_secs(10);
+        let mut link = Link::connected(|config| config.keepalive_interval = interval);
+        pass_time(&mut link.client, interval - Duration::from_secs(1));
+        link.client.tick();
+        assert!(link.is_idle());
+        pass_time(&mut link.client, Duration::from_secs(1));
+        link.client.tick();
+        let keepalive = link.forward();
+        assert_eq!(keepalive[0], KEEPALIVE);
+        assert_eq!(keepalive.len(), DATA_HEADER_LEN + TAG_LEN);
+        // The server takes it as a sign of life but has nothing to write to its tunnel
+        pass_time(&mut link.server, Duration::from_secs(60));
+        assert_eq!(recv(&mut link.server).unwrap(), b"");
+        let last_seen = link
+            .server
+            .peers
+            .values()
+            .next()
+            .unwrap()
+            .last_seen
+            .unwrap();
+        assert!(last_seen.elapsed() < Duration::from_secs(60));
+        // Anything sent counts, so a peer that sends packets sends no keepalives
+        pass_time(&mut link.client, interval);
+        send(
+            &mut link.client,
+            &ipv4_packet(CLIENT_IP, SERVER_IP, b"busy"),
+        );
+        link.forward();
+        pass_time(&mut link.client, Duration::ZERO);
+        link.client.tick();
+        assert!(link.is_idle());
+    }
+
+    #[test]
+    fn forged_keepalives_fail_authentication() {
+        let interval = Duration::from_secs(10);
+        let mut link = Link::connected(|config| config.keepalive_interval = interval);
+        pass_time(&mut link.client, interval);
+        link.client.tick();
+        let (mut keepalive, _) = link.take();
+        keepalive[DATA_HEADER_LEN] ^= 1;
+        link.inject(&keepalive, false);
+        let result = recv(&mut link.server);
+        assert!(matches!(result, Err(tunerror::Error::AuthenticationFailed)));
+    }
+
+    #[test]
+    fn clients_with_an_expired_session_do_a_handshake_instead_of_a_keepalive() {
+        let interval = Duration::from_secs(10);
+        let mut link = Link::connected(|config| config.keepalive_interval = interval);
+        age_sessions(&mut link.client, DEFAULT_REKEY_AFTER_TIME * 2);
+        pass_time(&mut link.client, interval);
+        link.client.tick();
+        assert!(is_initiation(&link.take().0));
+    }
+
+    #[test]
+    fn introduced_peers_fall_back_to_the_server_when_punching_fails() {
+        let mut link = Link::connected(|config| config.peer_to_peer = true);
+        let other = link.bind();
+        let public_key = *Identity::from_private_key(&[5; KEY_LEN]).public_key();
+        let other_ip = IpAddr::from([10, 0, 0, 3]);
+        link.client
+            .punch(
+                public_key,
+                other.local_addr().unwrap().as_socket().unwrap(),
+                other_ip,
+            )
+            .unwrap();
+        let mut buf = [0; 4096];
+        for _ in 0..rendezvous::PUNCH_ATTEMPTS {
+            let (len, _) = other.recv_from(&mut buf).unwrap();
+            assert!(is_initiation(&buf[..len]));
+            pass_time(&mut link.client, Duration::ZERO);
+            link.client.tick();
+        }
+        // Nobody answered, so packets to the other client go through the server again
+        assert!(link.client.allowed_ips.lookup(other_ip).is_none());
+        assert_eq!(link.client.peers.len(), 1);
+        let packet = ipv4_packet(CLIENT_IP, [10, 0, 0, 3], b"relayed");
+        send(&mut link.client, &packet);
+        link.forward();
+        assert_eq!(recv(&mut link.server).unwrap(), packet);
+    }
+
+    /// A server and clients connected over TCP on loopback, unless `configure` picks another
+    /// transport. Nothing has been exchanged yet.
+    fn tcp_peers(clients: usize, configure: impl Fn(&mut Config)) -> (Net, Vec<Net>) {
+        let mut server_config = Config {
+            transport: Transport::Tcp,
+            listen_addrs: vec![SocketAddr::from((Ipv4Addr::LOCALHOST, 0))],
+            ..config(false)
+        };
+        configure(&mut server_config);
+        let server = Net::new(&server_config).unwrap();
+        let server_addr = server.listeners[0]
+            .local_addr()
+            .unwrap()
+            .as_socket()
+            .unwrap();
+        let clients = (0..clients)
+            .map(|_| {
+                let mut client_config = Config {
+                    transport: Transport::Tcp,
+                    remote_addr: server_addr.to_string(),
+                    ..config(true)
+                };
+                configure(&mut client_config);
+                Net::new(&client_config).unwrap()
+            })
+            .collect();
+        (server, clients)
+    }
+
+    /// Has every peer receive until none of them has anything left to read for a while, and
+    /// returns the packets each one got.
+    fn settle(nets: &mut [&mut Net]) -> Vec<Vec<Vec<u8>>> {
+        let mut received = vec![vec![]; nets.len()];
+        loop {
+            let mut fds: Vec<libc::pollfd> = nets
+                .iter()
+                .map(|net| libc::pollfd {
+                    fd: net.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                })
+                .collect();
+            if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, 100) } <= 0 {
+                return received;
+            }
+            for (i, fd) in fds.iter().enumerate() {
+                if fd.revents & libc::POLLIN == 0 {
+                    continue;
+                }
+                if let Ok(packet) = recv(nets[i]) {
+                    if !packet.is_empty() {
+                        received[i].push(packet);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn tcp_servers_route_between_several_clients() {
+        let (mut server, mut clients) = tcp_peers(2, |_| {});
+        let [first, second] = &mut clients[..] else {
+            unreachable!()
+        };
+        settle(&mut [&mut server, first, second]);
+        let (first_ip, second_ip) = ([10, 0, 0, 2], [10, 0, 0, 3]);
+        let from_first = ipv4_packet(first_ip, SERVER_IP, b"first");
+        let from_second = ipv4_packet(second_ip, SERVER_IP, b"second");
+        assert!(send(first, &from_first) > from_first.len());
+        assert!(send(second, &from_second) > from_second.len());
+        let received = settle(&mut [&mut server, first, second]);
+        assert_eq!(received[0].len(), 2);
+        assert!(received[0].contains(&from_first) && received[0].contains(&from_second));
+        // The server learned which connection each address is behind
+        let to_second = ipv4_packet(SERVER_IP, second_ip, b"to second");
+        send(&mut server, &to_second);
+        let received = settle(&mut [&mut server, first, second]);
+        assert_eq!(received, [vec![], vec![], vec![to_second]]);
+    }
+
+    #[test]
+    fn tcp_clients_connect_again_after_losing_their_connection() {
+        let (mut server, mut clients) = tcp_peers(1, |_| {});
+        let client = &mut clients[0];
+        settle(&mut [&mut server, client]);
+        let tokens: Vec<usize> = server.connections.keys().copied().collect();
+        for token in tokens {
+            server.close_connection(token);
+        }
+        settle(&mut [&mut server, client]);
+        assert!(client.connections.is_empty());
+        let packet = ipv4_packet(CLIENT_IP, SERVER_IP, b"lost");
+        assert_eq!(send(client, &packet), 0);
+        // The client waits before connecting again, then does a new handshake
+        let reconnect_at = client.reconnect_at.unwrap();
+        assert!(reconnect_at > Instant::now());
+        client.reconnect_at = Some(Instant::now());
+        pass_time(client, Duration::ZERO);
+        client.tick();
+        settle(&mut [&mut server, client]);
+        let packet = ipv4_packet(CLIENT_IP, SERVER_IP, b"back");
+        assert!(send(client, &packet) > packet.len());
+        let received = settle(&mut [&mut server, client]);
+        assert_eq!(received[0], [packet]);
+        assert_eq!(client.reconnect_delay, MIN_RECONNECT_DELAY);
+    }
+
+    #[test]
+    fn tcp_servers_refuse_connections_beyond_max_peers() {
+        let (mut server, _clients) = tcp_peers(2, |_| {});
+        server.max_peers = 1;
+        recv(&mut server).unwrap();
+        let result = recv(&mut server);
+        assert!(matches!(result, Err(tunerror::Error::TooManyPeers)));
+        assert_eq!(server.connections.len(), 1);
+    }
+
+    /// Starts an HTTP proxy that opens a single tunnel with CONNECT, and returns its address.
+    fn connect_proxy() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut client, _) = listener.accept().unwrap();
+            let mut head = vec![];
+            while !head.ends_with(b"\r\n\r\n") {
+                let mut byte = [0];
+                client.read_exact(&mut byte).unwrap();
+                head.push(byte[0]);
+            }
+            let head = String::from_utf8(head).unwrap();
+            let target = head.strip_prefix("CONNECT ").unwrap().split(' ').next();
+            let mut server = TcpStream::connect(target.unwrap()).unwrap();
+            client
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                .unwrap();
+            let (mut from_client, mut to_server) =
+                (client.try_clone().unwrap(), server.try_clone().unwrap());
+            thread::spawn(move || io::copy(&mut from_client, &mut to_server));
+            let _ = io::copy(&mut server, &mut client);
+        });
+        addr
+    }
+
+    #[test]
+    fn websocket_clients_reach_their_server_through_a_proxy() {
+        let proxy = connect_proxy();
+        let (mut server, mut clients) = tcp_peers(1, |config| {
+            config.transport = Transport::WebSocket;
+            config.websocket_path = "/tunnel".to_owned();
+            if config.is_client {
+                config.proxy = Some(Proxy {
+                    addr: proxy,
+                    credentials: None,
+                });
+            }
+        });
+        let client = &mut clients[0];
+        settle(&mut [&mut server, client]);
+        let request = ipv4_packet(CLIENT_IP, SERVER_IP, b"request");
+        assert!(send(client, &request) > request.len());
+        let received = settle(&mut [&mut server, client]);
+        assert_eq!(received[0], [request]);
+        let response = ipv4_packet(SERVER_IP, CLIENT_IP, b"response");
+        send(&mut server, &response);
+        let received = settle(&mut [&mut server, client]);
+        assert_eq!(received[1], [response]);
+    }
+
+    #[test]
+    fn proxies_need_a_stream_transport() {
+        let result = Net::new(&Config {
+            remote_addr: "127.0.0.1:9".to_owned(),
+            proxy: Some("127.0.0.1:3128".parse().unwrap()),
+            ..config(true)
+        });
+        assert!(matches!(result, Err(tunerror::Error::Message(_))));
     }
 }
@@ -1,23 +1,9 @@
-use etherparse::{checksum, Ipv4HeaderSlice, PacketBuilder, TcpHeaderSlice};
+use std::net::IpAddr;
 
-const IPV4_HEADER_LEN: usize = 20;
-
-pub fn create_handshake_packet(ip_addr: &[u8; 4]) -> Vec<u8> {
-    let builder = PacketBuilder::ipv4(ip_addr.clone(), [0, 0, 0, 0], 10).udp(1, 1);
-    let payload = [1];
-    let mut result = Vec::<u8>::with_capacity(builder.size(payload.len()));
-    builder.write(&mut result, &payload).unwrap();
-    return result;
-}
+use etherparse::{checksum, Ipv4HeaderSlice, Ipv6HeaderSlice, TcpHeaderSlice};
 
-pub fn is_handshake_packet(buf: &[u8]) -> bool {
-    let slice = Ipv4HeaderSlice::from_slice(&buf);
-    if slice.is_err() {
-        println!("{:?}", slice.err().unwrap());
-        return false;
-    }
-    slice.unwrap().destination_addr().is_unspecified()
-}
+const IPV4_HEADER_LEN: usize = 20;
+const IPV6_HEADER_LEN: usize = 40;
 
 pub fn change_address_and_port(buf: &mut [u8], addr: &[u8], port: u16, is_source: bool) -> u16 {
     let mut offset = 0;
@@ -68,6 +54,47 @@ pub fn get_version(buf: &[u8]) -> u8 {
     buf[0] >> 4
 }
 
+/// Returns the source address of an IPv4 or IPv6 packet, or `None` if the header can't be parsed.
+pub fn get_source_addr(buf: &[u8]) -> Option<IpAddr> {
+    match get_version(buf) {
+        4 => Ipv4HeaderSlice::from_slice(buf)
+            .ok()
+            .map(|header| IpAddr::V4(header.source_addr())),
+        6 => Ipv6HeaderSlice::from_slice(buf)
+            .ok()
+            .map(|header| IpAddr::V6(header.source_addr())),
+        _ => None,
+    }
+}
+
+/// Returns the destination address of an IPv4 or IPv6 packet, or `None` if the header can't be
+/// parsed.
+pub fn get_destination_addr(buf: &[u8]) -> Option<IpAddr> {
+    match get_version(buf) {
+        4 => Ipv4HeaderSlice::from_slice(buf)
+            .ok()
+            .map(|header| IpAddr::V4(header.destination_addr())),
+        6 => Ipv6HeaderSlice::from_slice(buf)
+            .ok()
+            .map(|header| IpAddr::V6(header.destination_addr())),
+        _ => None,
+    }
+}
+
+/// Returns the length an IPv4 or IPv6 packet's header gives it, or `None` if the header can't be
+/// parsed.
+pub fn get_total_len(buf: &[u8]) -> Option<usize> {
+    match get_version(buf) {
+        4 => Ipv4HeaderSlice::from_slice(buf)
+            .ok()
+            .map(|header| header.total_len() as usize),
+        6 => Ipv6HeaderSlice::from_slice(buf)
+            .ok()
+            .map(|header| IPV6_HEADER_LEN + header.payload_length() as usize),
+        _ => None,
+    }
+}
+
 pub fn set_tcp_checksum(buf: &mut [u8], ip_header_length: usize) {
     let tcp_packet = &buf[ip_header_length..];
     let tcp_header = TcpHeaderSlice::from_slice(tcp_packet).unwrap();
@@ -0,0 +1,122 @@
+use std::str::FromStr;
+
+use ring::rand::SecureRandom;
+
+use crate::net::MAX_DATAGRAM_LEN;
+use crate::tunerror;
+
+/// How packets sealed behind the full encapsulation header are padded before they're
+/// encrypted, so their length on the network tells less about the packets inside. The padding
+/// is zeros after the inner packet, whose end the receiver finds from its IP header.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Padding {
+    #[default]
+    None,
+    /// Packets grow to the next multiple of this many bytes.
+    Multiple(usize),
+    /// Packets grow to the longest length that still fits in one datagram on the path.
+    Mtu,
+    /// Packets grow by a random number of bytes, up to this many.
+    Random(usize),
+}
+
+impl Padding {
+    /// Returns the length a packet of `len` bytes is padded to. It's never shorter than the
+    /// packet, and never longer than `max_len` unless the packet already is.
+    pub fn padded_len(self, len: usize, max_len: usize, rng: &dyn SecureRandom) -> usize {
+        let padded = match self {
+            Padding::None => len,
+            Padding::Multiple(multiple) => len
+                .checked_next_multiple_of(multiple.max(1))
+                .unwrap_or(max_len),
+            Padding::Mtu => max_len,
+            Padding::Random(max) => len.saturating_add(random_below(max.saturating_add(1), rng)),
+        };
+        padded.min(max_len).max(len)
+    }
+}
+
+impl FromStr for Padding {
+    type Err = tunerror::Error;
+
+    /// Parses `none`, `mtu`, `multiple:<bytes>` or `random:<bytes>`, with no more bytes than a
+    /// datagram can hold.
+    fn from_str(s: &str) -> Result<Padding, tunerror::Error> {
+        let invalid = || tunerror::Error::Message(format!("invalid padding {s}"));
+        let bytes = |n: &str| {
+            n.parse::<usize>()
+                .ok()
+                .filter(|n| (1..=MAX_DATAGRAM_LEN).contains(n))
+                .ok_or_else(invalid)
+        };
+        match s.split_once(':') {
+            None if s == "none" => Ok(Padding::None),
+            None if s == "mtu" => Ok(Padding::Mtu),
+            Some(("multiple", n)) => Ok(Padding::Multiple(bytes(n)?)),
+            Some(("random", n)) => Ok(Padding::Random(bytes(n)?)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Returns a random number below `bound`, which must not be 0. The bias is negligible for the
+/// small bounds used here.
+pub fn random_below(bound: usize, rng: &dyn SecureRandom) -> usize {
+    let mut bytes = [0; 8];
+    rng.fill(&mut bytes).unwrap();
+    (u64::from_le_bytes(bytes) % bound as u64) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use ring::rand::SystemRandom;
+
+    use super::*;
+
+    const MAX_LEN: usize = 1420;
+
+    #[test]
+    fn packets_grow_to_the_policy_without_passing_the_limit() {
+        let rng = SystemRandom::new();
+        assert_eq!(Padding::None.padded_len(100, MAX_LEN, &rng), 100);
+        assert_eq!(Padding::Multiple(64).padded_len(100, MAX_LEN, &rng), 128);
+        assert_eq!(Padding::Multiple(64).padded_len(128, MAX_LEN, &rng), 128);
+        assert_eq!(
+            Padding::Multiple(64).padded_len(1410, MAX_LEN, &rng),
+            MAX_LEN
+        );
+        assert_eq!(Padding::Mtu.padded_len(100, MAX_LEN, &rng), MAX_LEN);
+        assert_eq!(Padding::Mtu.padded_len(1500, MAX_LEN, &rng), 1500);
+        for _ in 0..100 {
+            let len = Padding::Random(16).padded_len(100, MAX_LEN, &rng);
+            assert!((100..=116).contains(&len));
+        }
+        // Policies made without parsing can't overflow either
+        for padding in [Padding::Multiple(usize::MAX), Padding::Random(usize::MAX)] {
+            assert!((100..=MAX_LEN).contains(&padding.padded_len(100, MAX_LEN, &rng)));
+        }
+    }
+
+    #[test]
+    fn policies_are_parsed_from_their_names() {
+        assert_eq!("none".parse::<Padding>().unwrap(), Padding::None);
+        assert_eq!("mtu".parse::<Padding>().unwrap(), Padding::Mtu);
+        assert_eq!(
+            "multiple:16".parse::<Padding>().unwrap(),
+            Padding::Multiple(16)
+        );
+        assert_eq!("random:64".parse::<Padding>().unwrap(), Padding::Random(64));
+        for invalid in [
+            "",
+            "multiple",
+            "multiple:0",
+            "random:-1",
+            "multiple:4097",
+            "random:18446744073709551615",
+            "mtu:1400",
+            "zeros",
+        ] {
+            assert!(invalid.parse::<Padding>().is_err(), "{invalid}");
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use std::ops::Range;
+
+/// Room kept in front of a packet, so that the header of fully encapsulated packets can be
+/// written there instead of moving the packet to make room for it.
+pub const HEADROOM: usize = 16;
+/// Room for a packet and what sealing adds after it, past the headroom.
+pub const CAPACITY: usize = 4096;
+
+/// A buffer holding one packet somewhere after its headroom. Packets are read into it and
+/// sealed or opened in place, so it can be written out without being copied.
+pub struct PacketBuf {
+    data: Box<[u8]>,
+    packet: Range<usize>,
+}
+
+impl PacketBuf {
+    pub fn new() -> PacketBuf {
+        PacketBuf::with_capacity(CAPACITY)
+    }
+
+    /// Makes a buffer with room for `capacity` bytes after its headroom.
+    pub fn with_capacity(capacity: usize) -> PacketBuf {
+        PacketBuf {
+            data: vec![0; HEADROOM + capacity].into_boxed_slice(),
+            packet: HEADROOM..HEADROOM,
+        }
+    }
+
+    pub fn packet(&self) -> &[u8] {
+        &self.data[self.packet.clone()]
+    }
+
+    pub fn packet_mut(&mut self) -> &mut [u8] {
+        &mut self.data[self.packet.clone()]
+    }
+
+    pub fn len(&self) -> usize {
+        self.packet.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.packet.is_empty()
+    }
+
+    /// Empties the buffer and gives back all the room after its headroom, for a packet to be
+    /// read into. [`PacketBuf::set_len`] then says how much of it the packet takes.
+    pub fn room_mut(&mut self) -> &mut [u8] {
+        self.packet = HEADROOM..HEADROOM;
+        &mut self.data[HEADROOM..]
+    }
+
+    pub fn set_len(&mut self, len: usize) {
+        assert!(HEADROOM + len <= self.data.len());
+        self.packet = HEADROOM..HEADROOM + len;
+    }
+
+    /// Gives the whole buffer, headroom included, and where the packet is in it.
+    pub(crate) fn parts_mut(&mut self) -> (&mut [u8], Range<usize>) {
+        (&mut self.data, self.packet.clone())
+    }
+
+    /// Moves the packet to another part of the buffer after it was sealed or opened in place.
+    pub(crate) fn set_packet(&mut self, packet: Range<usize>) {
+        assert!(packet.end <= self.data.len());
+        self.packet = packet;
+    }
+}
+
+/// The room after the headroom, for a packet to be read into without changing where the buffer
+/// says its packet is.
+impl AsMut<[u8]> for PacketBuf {
+    fn as_mut(&mut self) -> &mut [u8] {
+        &mut self.data[HEADROOM..]
+    }
+}
+
+impl Default for PacketBuf {
+    fn default() -> PacketBuf {
+        PacketBuf::new()
+    }
+}
+
+/// Buffers given back once their packet was written, to be taken again for the next ones.
+/// Only taking more than were ever given back allocates.
+pub struct BufferPool {
+    free: Vec<PacketBuf>,
+    capacity: usize,
+}
+
+impl BufferPool {
+    /// Makes a pool of buffers with room for `capacity` bytes, and `count` of them ready.
+    pub fn new(count: usize, capacity: usize) -> BufferPool {
+        BufferPool {
+            free: (0..count)
+                .map(|_| PacketBuf::with_capacity(capacity))
+                .collect(),
+            capacity,
+        }
+    }
+
+    pub fn take(&mut self) -> PacketBuf {
+        match self.free.pop() {
+            Some(mut buf) => {
+                buf.room_mut();
+                buf
+            }
+            None => PacketBuf::with_capacity(self.capacity),
+        }
+    }
+
+    pub fn give(&mut self, buf: PacketBuf) {
+        self.free.push(buf);
+    }
+
+    pub fn give_all(&mut self, bufs: impl IntoIterator<Item = PacketBuf>) {
+        self.free.extend(bufs);
+    }
+
+    /// Number of buffers ready to be taken.
+    pub fn len(&self) -> usize {
+        self.free.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.free.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buffers_keep_their_headroom_and_are_used_again() {
+        let mut pool = BufferPool::new(1, 100);
+        let mut buf = pool.take();
+        assert!(pool.is_empty());
+        buf.room_mut()[..3].copy_from_slice(b"abc");
+        buf.set_len(3);
+        assert_eq!(buf.packet(), b"abc");
+        let (data, packet) = buf.parts_mut();
+        assert_eq!(
+            (data.len(), packet),
+            (HEADROOM + 100, HEADROOM..HEADROOM + 3)
+        );
+        data[HEADROOM - 1] = b'>';
+        buf.set_packet(HEADROOM - 1..HEADROOM + 3);
+        assert_eq!(buf.packet(), b">abc");
+        let data = buf.packet().as_ptr();
+        pool.give(buf);
+        let buf = pool.take();
+        assert!(buf.is_empty());
+        assert_eq!(buf.packet().as_ptr(), data.wrapping_add(1));
+        // An empty pool makes new buffers
+        assert_eq!(pool.take().room_mut().len(), 100);
+    }
+}
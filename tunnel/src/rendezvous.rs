@@ -0,0 +1,162 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use crate::crypto::KEY_LEN;
+use crate::tunerror::Error;
+
+/// Message type of rendezvous messages, which are sealed like fully encapsulated data packets.
+/// See [`crate::handshake::HANDSHAKE_INITIATION`] for how message types are numbered.
+pub const RENDEZVOUS: u8 = 6;
+/// Number of initiations sent to punch a hole to a peer, one per tick, before its packets go
+/// through the server again.
+pub const PUNCH_ATTEMPTS: u32 = 5;
+/// How long a client waits before asking the server about the same address again.
+pub const REQUEST_INTERVAL: Duration = Duration::from_secs(60);
+/// Number of addresses a client remembers asking about.
+pub const MAX_REQUESTS: usize = 1024;
+const REQUEST: u8 = 1;
+const INTRODUCTION: u8 = 2;
+
+/// Messages clients and the server exchange to set up direct paths between clients.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Message {
+    /// A client asks the server to introduce it to the peer owning `destination`. `source` is
+    /// the client's own tunnel address, which the peer is told to send its replies to.
+    Request { source: IpAddr, destination: IpAddr },
+    /// The server tells a client the address a peer's packets come from. Both peers send
+    /// initiations to each other at once, which opens a path through both NATs.
+    Introduction {
+        public_key: [u8; KEY_LEN],
+        endpoint: SocketAddr,
+        tunnel_addr: IpAddr,
+    },
+}
+
+impl Message {
+    /// Addresses are written as their length followed by their bytes, and ports big endian.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        match self {
+            Message::Request {
+                source,
+                destination,
+            } => {
+                buf.push(REQUEST);
+                put_addr(&mut buf, *source);
+                put_addr(&mut buf, *destination);
+            }
+            Message::Introduction {
+                public_key,
+                endpoint,
+                tunnel_addr,
+            } => {
+                buf.push(INTRODUCTION);
+                buf.extend_from_slice(public_key);
+                put_addr(&mut buf, endpoint.ip());
+                buf.extend_from_slice(&endpoint.port().to_be_bytes());
+                put_addr(&mut buf, *tunnel_addr);
+            }
+        }
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Message, Error> {
+        Self::parse(buf).ok_or_else(|| Error::Message("malformed rendezvous message".to_owned()))
+    }
+
+    fn parse(buf: &[u8]) -> Option<Message> {
+        let (&kind, mut rest) = buf.split_first()?;
+        let message = match kind {
+            REQUEST => Message::Request {
+                source: take_addr(&mut rest)?,
+                destination: take_addr(&mut rest)?,
+            },
+            INTRODUCTION => {
+                let public_key = take(&mut rest, KEY_LEN)?.try_into().unwrap();
+                let ip = take_addr(&mut rest)?;
+                let port = u16::from_be_bytes(take(&mut rest, 2)?.try_into().unwrap());
+                Message::Introduction {
+                    public_key,
+                    endpoint: SocketAddr::new(ip, port),
+                    tunnel_addr: take_addr(&mut rest)?,
+                }
+            }
+            _ => return None,
+        };
+        rest.is_empty().then_some(message)
+    }
+}
+
+fn put_addr(buf: &mut Vec<u8>, addr: IpAddr) {
+    match addr {
+        IpAddr::V4(v4) => {
+            buf.push(4);
+            buf.extend_from_slice(&v4.octets());
+        }
+        IpAddr::V6(v6) => {
+            buf.push(16);
+            buf.extend_from_slice(&v6.octets());
+        }
+    }
+}
+
+/// Splits `len` bytes off the front of `buf`.
+fn take<'a>(buf: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if buf.len() < len {
+        return None;
+    }
+    let (head, rest) = buf.split_at(len);
+    *buf = rest;
+    Some(head)
+}
+
+fn take_addr(buf: &mut &[u8]) -> Option<IpAddr> {
+    let len = *take(buf, 1)?.first()?;
+    let bytes = take(buf, len as usize)?;
+    match len {
+        4 => Some(Ipv4Addr::from(<[u8; 4]>::try_from(bytes).unwrap()).into()),
+        16 => Some(Ipv6Addr::from(<[u8; 16]>::try_from(bytes).unwrap()).into()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_survive_a_round_trip() {
+        let messages = [
+            Message::Request {
+                source: Ipv4Addr::new(10, 0, 0, 2).into(),
+                destination: Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 3).into(),
+            },
+            Message::Introduction {
+                public_key: [7; KEY_LEN],
+                endpoint: "[2001:db8::1]:51820".parse().unwrap(),
+                tunnel_addr: Ipv4Addr::new(10, 0, 0, 3).into(),
+            },
+        ];
+        for message in messages {
+            assert_eq!(Message::from_bytes(&message.to_bytes()).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn malformed_messages_are_refused() {
+        let bytes = Message::Introduction {
+            public_key: [7; KEY_LEN],
+            endpoint: "192.0.2.1:51820".parse().unwrap(),
+            tunnel_addr: Ipv4Addr::new(10, 0, 0, 3).into(),
+        }
+        .to_bytes();
+        assert!(Message::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(Message::from_bytes(&[bytes.as_slice(), &[0]].concat()).is_err());
+        let mut unknown = bytes.clone();
+        unknown[0] = 9;
+        assert!(Message::from_bytes(&unknown).is_err());
+        // Addresses are either 4 or 16 bytes long
+        assert!(Message::from_bytes(&[REQUEST, 5, 0, 0, 0, 0, 0, 4, 10, 0, 0, 1]).is_err());
+        assert!(Message::from_bytes(&[]).is_err());
+    }
+}
@@ -0,0 +1,161 @@
+/// Number of bits held by a single block of the window.
+const BLOCK_BITS: u64 = u64::BITS as u64;
+/// Number of blocks in the ring. Must be a power of two.
+const BLOCKS: usize = 32;
+/// Counters this far behind the newest one accepted are always rejected. One block is kept
+/// out of the usable window so that moving the window forward never clears a live bit.
+pub const WINDOW_SIZE: u64 = (BLOCKS as u64 - 1) * BLOCK_BITS;
+
+/// Sliding anti-replay window keyed on packet counters, following the bitmap layout described
+/// in RFC 6479. Every received counter maps to one bit in a ring of blocks; advancing the window
+/// only clears the blocks it moves over instead of shifting the whole bitmap.
+pub struct ReplayWindow {
+    /// The highest counter accepted so far.
+    last: u64,
+    bitmap: [u64; BLOCKS],
+}
+
+impl Default for ReplayWindow {
+    fn default() -> Self {
+        ReplayWindow::new()
+    }
+}
+
+impl ReplayWindow {
+    pub fn new() -> ReplayWindow {
+        ReplayWindow {
+            last: 0,
+            bitmap: [0; BLOCKS],
+        }
+    }
+
+    /// Returns whether a packet with this counter could be accepted. This doesn't change the
+    /// window, so it can be called before the packet is authenticated.
+    pub fn check(&self, counter: u64) -> bool {
+        if counter > self.last {
+            return true;
+        }
+        if self.last - counter >= WINDOW_SIZE {
+            return false;
+        }
+        let (block, bit) = Self::position(counter);
+        self.bitmap[block] & (1 << bit) == 0
+    }
+
+    /// Marks the counter as received. Returns false if the counter is a duplicate or too old, in
+    /// which case the packet must be dropped. Only call this once the packet is authenticated.
+    pub fn update(&mut self, counter: u64) -> bool {
+        if !self.check(counter) {
+            return false;
+        }
+        if counter > self.last {
+            let current_block = self.last / BLOCK_BITS;
+            let new_block = counter / BLOCK_BITS;
+            let to_clear = (new_block - current_block).min(BLOCKS as u64);
+            for i in 1..=to_clear {
+                self.bitmap[((current_block + i) as usize) & (BLOCKS - 1)] = 0;
+            }
+            self.last = counter;
+        }
+        let (block, bit) = Self::position(counter);
+        self.bitmap[block] |= 1 << bit;
+        true
+    }
+
+    fn position(counter: u64) -> (usize, u64) {
+        let block = ((counter / BLOCK_BITS) as usize) & (BLOCKS - 1);
+        (block, counter % BLOCK_BITS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the window after accepting every counter given.
+    fn window(counters: impl IntoIterator<Item = u64>) -> ReplayWindow {
+        let mut window = ReplayWindow::new();
+        for counter in counters {
+            assert!(window.update(counter), "{counter} was rejected");
+        }
+        window
+    }
+
+    #[test]
+    fn duplicates_are_rejected() {
+        let mut window = window([0, 1, 2]);
+        for counter in [0, 1, 2] {
+            assert!(!window.check(counter));
+            assert!(!window.update(counter));
+        }
+        assert!(window.update(3));
+    }
+
+    #[test]
+    fn reordered_counters_inside_the_window_are_accepted_once() {
+        let mut window = window([10, 5, 8, 1, 9]);
+        for counter in [2, 3, 4, 6, 7] {
+            assert!(window.update(counter));
+        }
+        for counter in 1..=10 {
+            assert!(!window.update(counter));
+        }
+    }
+
+    #[test]
+    fn window_edge() {
+        let last = WINDOW_SIZE + 100;
+        let mut window = window([last]);
+        // The oldest counter still accepted is WINDOW_SIZE - 1 behind the newest
+        assert!(window.check(last - (WINDOW_SIZE - 1)));
+        assert!(!window.check(last - WINDOW_SIZE));
+        assert!(window.update(last - (WINDOW_SIZE - 1)));
+        assert!(!window.update(last - WINDOW_SIZE));
+    }
+
+    #[test]
+    fn counters_behind_the_window_are_rejected() {
+        let mut window = window([0, 1, 5 * WINDOW_SIZE]);
+        // Never received, but too old to tell
+        for counter in [2, 100, 4 * WINDOW_SIZE] {
+            assert!(!window.update(counter));
+        }
+    }
+
+    #[test]
+    fn large_jumps_clear_the_blocks_they_move_over() {
+        // Fill every bit of the ring, then move far ahead
+        let mut window = window(0..BLOCKS as u64 * BLOCK_BITS);
+        let last = 7 * BLOCKS as u64 * BLOCK_BITS + 3;
+        assert!(window.update(last));
+        // Counters whose bits were set before the jump map to the same bits again
+        for counter in last - (WINDOW_SIZE - 1)..last {
+            assert!(window.update(counter), "{counter} was rejected");
+        }
+        assert!(!window.update(last));
+    }
+
+    #[test]
+    fn counters_wrap_around_the_ring_at_block_boundaries() {
+        let ring = BLOCKS as u64 * BLOCK_BITS;
+        let mut window = window([BLOCK_BITS - 1, BLOCK_BITS, ring - 1]);
+        // The first block of the ring is reused for counters after it
+        assert!(window.update(ring));
+        assert!(window.update(ring + BLOCK_BITS - 1));
+        assert!(!window.update(ring - 1));
+        assert!(!window.update(ring));
+        // Moving into the next block dropped the oldest block out of the window
+        assert!(!window.update(BLOCK_BITS - 1));
+        assert!(!window.update(BLOCK_BITS));
+        assert!(window.update(ring + BLOCK_BITS));
+        assert!(window.update(3 * BLOCK_BITS));
+    }
+
+    #[test]
+    fn highest_counters_dont_overflow() {
+        let mut window = window([u64::MAX - 1]);
+        assert!(window.update(u64::MAX));
+        assert!(window.update(u64::MAX - 2));
+        assert!(!window.update(u64::MAX));
+    }
+}
@@ -1,4 +1,4 @@
-use std::{io, mem::MaybeUninit, os::fd::RawFd, ptr};
+use std::{io, mem::MaybeUninit, os::fd::RawFd, ptr, time::Duration};
 
 use libc::{c_int, fd_set, timeval, FD_CLR, FD_ISSET, FD_SET, FD_ZERO};
 
@@ -41,6 +41,14 @@ fn to_ptr<T>(opt: Option<&T>) -> *const T {
     }
 }
 
+/// Converts a duration to the timeout [`select`] takes.
+pub fn to_timeval(duration: Duration) -> timeval {
+    timeval {
+        tv_sec: duration.as_secs() as libc::time_t,
+        tv_usec: duration.subsec_micros() as libc::suseconds_t,
+    }
+}
+
 pub fn select(
     nfds: c_int,
     readfds: Option<&mut FdSet>,
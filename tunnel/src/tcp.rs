@@ -0,0 +1,657 @@
+use std::io::{self, Read, Write};
+use std::mem::{self, MaybeUninit};
+use std::net::SocketAddr;
+use std::os::fd::{AsRawFd, RawFd};
+use std::str::FromStr;
+use std::time::Instant;
+
+use ring::rand::{SecureRandom, SystemRandom};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+
+use crate::tunerror;
+use crate::websocket::{self, MASK_LEN};
+
+/// Size of the big endian length in front of every frame, without WebSocket.
+const LENGTH_LEN: usize = 2;
+/// Size of the longest frame header of either framing.
+const MAX_HEADER_LEN: usize = websocket::MAX_HEADER_LEN;
+/// Largest datagram a frame may carry, which is the size of the buffer [`crate::net::Net`]
+/// receives into.
+pub const MAX_FRAME_LEN: usize = 4096;
+/// Number of bytes that may wait to be written. Frames sent beyond that are dropped, as a full
+/// UDP send buffer would drop datagrams.
+const MAX_PENDING_LEN: usize = 256 * 1024;
+/// Longest HTTP request or response taken while a connection is set up.
+const MAX_HEAD_LEN: usize = 8192;
+
+/// An HTTP proxy a client reaches its server through, with a CONNECT request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Proxy {
+    pub addr: SocketAddr,
+    /// `user:password` for Basic authentication.
+    pub credentials: Option<String>,
+}
+
+impl FromStr for Proxy {
+    type Err = tunerror::Error;
+
+    /// Parses `address:port`, optionally behind `user:password@`.
+    fn from_str(s: &str) -> Result<Proxy, tunerror::Error> {
+        let (credentials, addr) = match s.rsplit_once('@') {
+            Some((credentials, addr)) => (Some(credentials.to_owned()), addr),
+            None => (None, s),
+        };
+        let addr = addr
+            .parse()
+            .map_err(|_| tunerror::Error::Message(format!("invalid proxy address {addr}")))?;
+        Ok(Proxy { addr, credentials })
+    }
+}
+
+/// How datagrams are carried over TCP connections.
+#[derive(Clone, Debug, Default)]
+pub struct StreamConfig {
+    /// Datagrams are sent as binary WebSocket messages, once the connection was upgraded with a
+    /// request for this path, instead of behind their length.
+    pub websocket_path: Option<String>,
+    /// Proxy a client connects through. Not used by servers.
+    pub proxy: Option<Proxy>,
+}
+
+/// What a connection waits for before frames can go through it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Stage {
+    /// The connection we opened isn't established yet.
+    Connecting,
+    /// The proxy's answer to our CONNECT request.
+    ProxyResponse,
+    /// The server's answer to our WebSocket upgrade request.
+    UpgradeResponse,
+    /// A client's WebSocket upgrade request.
+    UpgradeRequest,
+    Open,
+}
+
+/// A TCP connection carrying datagrams, for networks that drop UDP. Each datagram goes behind
+/// its length, or in a binary WebSocket message so that it passes through HTTP proxies and web
+/// servers. The socket is non-blocking: frames that can't be written yet wait in the
+/// connection, and a datagram is only handed out once all of it arrived.
+pub struct Connection {
+    socket: Socket,
+    /// Address of the other end, or of the server when connecting through a proxy.
+    peer_addr: SockAddr,
+    is_client: bool,
+    config: StreamConfig,
+    stage: Stage,
+    /// Key of our upgrade request, which the server's answer must be derived from.
+    websocket_key: String,
+    /// The part of an HTTP request or response that arrived.
+    head: Vec<u8>,
+    /// The frame being received, header included, and how much of it arrived.
+    incoming: Box<[u8; MAX_HEADER_LEN + MAX_FRAME_LEN]>,
+    received: usize,
+    /// The WebSocket message whose frames are being received, and whether more are coming.
+    message: Vec<u8>,
+    in_message: bool,
+    /// Frames sent before the connection was set up.
+    held: Vec<u8>,
+    /// Bytes the socket didn't take yet.
+    outgoing: Vec<u8>,
+    last_recv: Instant,
+    /// Whether epoll reports the socket when it's writable. [`crate::net::Net`] keeps this in
+    /// step with [`Connection::wants_write`].
+    pub watching_writes: bool,
+    rng: SystemRandom,
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.to_owned())
+}
+
+impl Connection {
+    /// Starts connecting to the server at `addr` without waiting for the connection to be
+    /// set up. Frames sent in the meantime are written once it is.
+    pub fn connect(addr: &SocketAddr, config: &StreamConfig) -> io::Result<Connection> {
+        let through = config.proxy.as_ref().map_or(*addr, |proxy| proxy.addr);
+        let socket = Socket::new(
+            Domain::for_address(through),
+            Type::STREAM,
+            Some(Protocol::TCP),
+        )?;
+        socket.set_nonblocking(true)?;
+        socket.set_nodelay(true)?;
+        let connected = match socket.connect(&through.into()) {
+            Ok(()) => true,
+            Err(e) if e.raw_os_error() == Some(libc::EINPROGRESS) => false,
+            Err(e) => return Err(e),
+        };
+        let mut connection = Connection::new(socket, (*addr).into(), true, config);
+        if connected {
+            connection.established();
+        }
+        Ok(connection)
+    }
+
+    /// Wraps a connection a server accepted on a listener.
+    pub fn accepted(
+        socket: Socket,
+        peer_addr: SockAddr,
+        config: &StreamConfig,
+    ) -> io::Result<Connection> {
+        socket.set_nonblocking(true)?;
+        socket.set_nodelay(true)?;
+        let mut connection = Connection::new(socket, peer_addr, false, config);
+        connection.stage = match config.websocket_path {
+            Some(_) => Stage::UpgradeRequest,
+            None => Stage::Open,
+        };
+        Ok(connection)
+    }
+
+    fn new(
+        socket: Socket,
+        peer_addr: SockAddr,
+        is_client: bool,
+        config: &StreamConfig,
+    ) -> Connection {
+        Connection {
+            socket,
+            peer_addr,
+            is_client,
+            config: config.clone(),
+            stage: Stage::Connecting,
+            websocket_key: String::new(),
+            head: vec![],
+            incoming: Box::new([0; MAX_HEADER_LEN + MAX_FRAME_LEN]),
+            received: 0,
+            message: vec![],
+            in_message: false,
+            held: vec![],
+            outgoing: vec![],
+            last_recv: Instant::now(),
+            watching_writes: false,
+            rng: SystemRandom::new(),
+        }
+    }
+
+    pub fn peer_addr(&self) -> &SockAddr {
+        &self.peer_addr
+    }
+
+    /// When the last whole frame arrived, or when the connection was set up if none did.
+    pub fn last_recv(&self) -> Instant {
+        self.last_recv
+    }
+
+    /// Returns whether the connection has to wait for the socket to be writable, to finish
+    /// connecting or to write what's pending.
+    pub fn wants_write(&self) -> bool {
+        self.stage == Stage::Connecting || !self.outgoing.is_empty()
+    }
+
+    /// Sends a datagram as a frame. It fails with [`io::ErrorKind::WouldBlock`] and drops the
+    /// datagram if too much is already waiting to be written.
+    pub fn send(&mut self, datagram: &[u8]) -> io::Result<usize> {
+        if datagram.is_empty() || datagram.len() > MAX_FRAME_LEN {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        let pending = self.outgoing.len() + self.held.len();
+        if pending + MAX_HEADER_LEN + datagram.len() > MAX_PENDING_LEN {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        self.queue_frame(websocket::OPCODE_BINARY, datagram);
+        self.flush()?;
+        Ok(datagram.len())
+    }
+
+    /// Frames a datagram, or a WebSocket control message, after what's waiting to be written.
+    /// Frames wait until the connection is set up.
+    fn queue_frame(&mut self, opcode: u8, payload: &[u8]) {
+        let websocket = self.config.websocket_path.is_some();
+        // Clients mask their WebSocket frames with a key the network can't predict
+        let mask = (websocket && self.is_client).then(|| {
+            let mut mask = [0; MASK_LEN];
+            self.rng.fill(&mut mask).unwrap();
+            mask
+        });
+        let out = match self.stage {
+            Stage::Open => &mut self.outgoing,
+            _ => &mut self.held,
+        };
+        if websocket {
+            websocket::write_frame(out, opcode, payload, mask);
+        } else {
+            out.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+            out.extend_from_slice(payload);
+        }
+    }
+
+    /// Writes as much of what's pending as the socket takes. Fails if connecting failed or the
+    /// connection broke.
+    pub fn flush(&mut self) -> io::Result<()> {
+        if self.stage == Stage::Connecting {
+            if let Some(e) = self.socket.take_error()? {
+                return Err(e);
+            }
+            match self.socket.peer_addr() {
+                Ok(_) => self.established(),
+                Err(e) if e.kind() == io::ErrorKind::NotConnected => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+        while !self.outgoing.is_empty() {
+            match (&self.socket).write(&self.outgoing) {
+                Ok(written) => {
+                    self.outgoing.drain(..written);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Starts setting up a connection we opened once it's established, by asking the proxy
+    /// to reach the server.
+    fn established(&mut self) {
+        let Some(proxy) = &self.config.proxy else {
+            return self.upgrade();
+        };
+        let target = self.target();
+        let request = websocket::connect_request(&target, proxy.credentials.as_deref());
+        self.outgoing.extend_from_slice(request.as_bytes());
+        self.stage = Stage::ProxyResponse;
+    }
+
+    /// Asks the server to upgrade the connection to WebSocket, once it reaches the server.
+    fn upgrade(&mut self) {
+        let Some(path) = &self.config.websocket_path else {
+            return self.open();
+        };
+        let mut key = [0; websocket::KEY_LEN];
+        self.rng.fill(&mut key).unwrap();
+        self.websocket_key = websocket::base64(&key);
+        let request = websocket::upgrade_request(&self.target(), path, &self.websocket_key);
+        self.outgoing.extend_from_slice(request.as_bytes());
+        self.stage = Stage::UpgradeResponse;
+    }
+
+    fn open(&mut self) {
+        self.stage = Stage::Open;
+        self.outgoing.append(&mut self.held);
+    }
+
+    /// Returns the address of the server a client connects to.
+    fn target(&self) -> String {
+        self.peer_addr
+            .as_socket()
+            .map(|addr| addr.to_string())
+            .unwrap_or_default()
+    }
+
+    /// Takes the HTTP request or response the connection waits for while it's set up.
+    fn handle_head(&mut self, head: &str) -> io::Result<()> {
+        match self.stage {
+            Stage::ProxyResponse => {
+                websocket::check_connect_response(head)?;
+                self.upgrade();
+            }
+            Stage::UpgradeResponse => {
+                websocket::check_upgrade_response(head, &self.websocket_key)?;
+                self.open();
+            }
+            Stage::UpgradeRequest => {
+                let path = self.config.websocket_path.as_deref().unwrap_or_default();
+                match websocket::accept_upgrade(head, path) {
+                    Ok(response) => {
+                        self.outgoing.extend_from_slice(response.as_bytes());
+                        self.open();
+                    }
+                    Err(e) => {
+                        // The connection is closed right after, so this is only a courtesy
+                        let _ = (&self.socket).write(e.response().as_bytes());
+                        return Err(invalid(&format!("refused upgrade: {}", e.status())));
+                    }
+                }
+            }
+            Stage::Connecting | Stage::Open => unreachable!(),
+        }
+        self.flush()
+    }
+
+    /// Reads an HTTP request or response up to the blank line ending its head. What's after it
+    /// is left in the socket: it's only looked at first, so that the socket stays readable
+    /// while frames are waiting.
+    fn read_head(&mut self) -> io::Result<Option<String>> {
+        let room = MAX_HEAD_LEN - self.head.len();
+        if room == 0 {
+            return Err(invalid("HTTP head too long"));
+        }
+        let mut peeked = [0; MAX_HEAD_LEN];
+        let peek_buf =
+            unsafe { &mut *(&mut peeked[..room] as *mut [u8] as *mut [MaybeUninit<u8>]) };
+        let available = match self.socket.peek(peek_buf) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(available) => available,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        // The blank line may have started in what was read before
+        let before = self.head.len();
+        let start = before.saturating_sub(3);
+        self.head.extend_from_slice(&peeked[..available]);
+        let end = self.head[start..]
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .map(|position| start + position + 4);
+        let taken = end.unwrap_or(self.head.len()) - before;
+        self.head.truncate(before + taken);
+        (&self.socket).read_exact(&mut peeked[..taken])?;
+        if end.is_none() {
+            return Ok(None);
+        }
+        String::from_utf8(mem::take(&mut self.head))
+            .map(Some)
+            .map_err(|_| invalid("HTTP head isn't UTF-8"))
+    }
+
+    /// Reads the next datagram into `buf` and returns its length, or `None` if it didn't fully
+    /// arrive yet. Nothing past the end of the frame is read, so the socket stays readable
+    /// while other frames are waiting. Fails once the other side closed the connection, if it
+    /// couldn't be set up, or if the other side sent a frame that's invalid or too long.
+    pub fn recv(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        loop {
+            match self.stage {
+                Stage::Open => {}
+                Stage::Connecting => {
+                    self.flush()?;
+                    if self.stage == Stage::Connecting {
+                        return Ok(None);
+                    }
+                    continue;
+                }
+                _ => {
+                    let Some(head) = self.read_head()? else {
+                        return Ok(None);
+                    };
+                    self.handle_head(&head)?;
+                    continue;
+                }
+            }
+            let header_len = match (self.received, &self.config.websocket_path) {
+                (0..LENGTH_LEN, _) | (_, None) => LENGTH_LEN,
+                (_, Some(_)) => websocket::header_len(&self.incoming[..2]),
+            };
+            let wanted = if self.received < header_len {
+                header_len
+            } else {
+                let total = header_len + self.payload_len(header_len, buf.len())?;
+                if self.received == total {
+                    self.received = 0;
+                    if let Some(len) = self.take_frame(header_len, total, buf)? {
+                        self.last_recv = Instant::now();
+                        return Ok(Some(len));
+                    }
+                    continue;
+                }
+                total
+            };
+            match (&self.socket).read(&mut self.incoming[self.received..wanted]) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(read) => self.received += read,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Returns the payload length of the frame whose header arrived.
+    fn payload_len(&self, header_len: usize, buf_len: usize) -> io::Result<usize> {
+        let len = match self.config.websocket_path {
+            Some(_) => websocket::parse_header(&self.incoming[..header_len])?.payload_len,
+            None => match u16::from_be_bytes([self.incoming[0], self.incoming[1]]) {
+                0 => return Err(invalid("invalid frame length 0")),
+                len => len as u64,
+            },
+        };
+        if len > MAX_FRAME_LEN.min(buf_len) as u64 {
+            return Err(invalid(&format!("invalid frame length {len}")));
+        }
+        Ok(len as usize)
+    }
+
+    /// Handles a frame that fully arrived, and returns the length of the datagram it completed
+    /// in `buf`, if any.
+    fn take_frame(
+        &mut self,
+        header_len: usize,
+        total: usize,
+        buf: &mut [u8],
+    ) -> io::Result<Option<usize>> {
+        if self.config.websocket_path.is_none() {
+            buf[..total - header_len].copy_from_slice(&self.incoming[header_len..total]);
+            return Ok(Some(total - header_len));
+        }
+        let header = websocket::parse_header(&self.incoming[..header_len])?;
+        // Clients mask their frames and servers don't
+        if header.mask.is_some() == self.is_client {
+            return Err(invalid("WebSocket frame masked by the wrong side"));
+        }
+        let payload = &mut self.incoming[header_len..total];
+        if let Some(mask) = header.mask {
+            websocket::apply_mask(payload, mask);
+        }
+        match header.opcode {
+            websocket::OPCODE_PING => {
+                let payload = payload.to_vec();
+                self.queue_frame(websocket::OPCODE_PONG, &payload);
+                self.flush()?;
+                Ok(None)
+            }
+            websocket::OPCODE_PONG => Ok(None),
+            websocket::OPCODE_CLOSE => Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "closed by the other side",
+            )),
+            opcode => {
+                // A message starts with a binary frame and goes on with continuations
+                if (opcode == websocket::OPCODE_BINARY) == self.in_message {
+                    return Err(invalid("unexpected WebSocket frame in a message"));
+                }
+                if self.message.len() + payload.len() > MAX_FRAME_LEN.min(buf.len()) {
+                    return Err(invalid("WebSocket message too long"));
+                }
+                self.message.extend_from_slice(payload);
+                self.in_message = !header.fin;
+                if self.in_message {
+                    return Ok(None);
+                }
+                let len = self.message.len();
+                buf[..len].copy_from_slice(&self.message);
+                self.message.clear();
+                Ok((len > 0).then_some(len))
+            }
+        }
+    }
+}
+
+impl AsRawFd for Connection {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    /// Returns a connection and the other end of it.
+    fn pair() -> (Connection, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connection = Connection::connect(&addr, &StreamConfig::default()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        (connection, stream)
+    }
+
+    /// Waits for the next frame, or for the connection to fail.
+    fn recv(connection: &mut Connection) -> io::Result<Vec<u8>> {
+        let mut buf = [0; MAX_FRAME_LEN];
+        for _ in 0..1000 {
+            if let Some(len) = connection.recv(&mut buf)? {
+                return Ok(buf[..len].to_vec());
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        panic!("no frame arrived");
+    }
+
+    #[test]
+    fn frames_carry_their_length() {
+        let (mut connection, mut stream) = pair();
+        for _ in 0..1000 {
+            connection.flush().unwrap();
+            if !connection.wants_write() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        connection.send(b"hello").unwrap();
+        let mut buf = [0; 7];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"\x00\x05hello");
+    }
+
+    #[test]
+    fn frames_are_handed_out_whole_and_one_at_a_time() {
+        let (mut connection, mut stream) = pair();
+        let mut buf = [0; MAX_FRAME_LEN];
+        stream.write_all(&[0, 3, b'o']).unwrap();
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(connection.recv(&mut buf).unwrap(), None);
+        stream.write_all(b"ne\x00\x03two").unwrap();
+        assert_eq!(recv(&mut connection).unwrap(), b"one");
+        assert_eq!(recv(&mut connection).unwrap(), b"two");
+    }
+
+    #[test]
+    fn invalid_lengths_fail_the_connection() {
+        for length in [0, MAX_FRAME_LEN as u16 + 1] {
+            let (mut connection, mut stream) = pair();
+            stream.write_all(&length.to_be_bytes()).unwrap();
+            let error = recv(&mut connection).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn closed_connections_fail() {
+        let (mut connection, stream) = pair();
+        drop(stream);
+        let error = recv(&mut connection).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    /// Returns a server's WebSocket connection for `path` and the client's end of it, which
+    /// hasn't sent its upgrade request yet.
+    fn websocket_pair(path: &str) -> (Connection, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (accepted, addr) = listener.accept().unwrap();
+        let config = StreamConfig {
+            websocket_path: Some(path.to_owned()),
+            proxy: None,
+        };
+        let connection = Connection::accepted(accepted.into(), addr.into(), &config).unwrap();
+        (connection, stream)
+    }
+
+    /// Reads an HTTP head off a stream.
+    fn read_head(stream: &mut TcpStream) -> String {
+        let mut head = vec![];
+        while !head.ends_with(b"\r\n\r\n") {
+            let mut byte = [0];
+            stream.read_exact(&mut byte).unwrap();
+            head.push(byte[0]);
+        }
+        String::from_utf8(head).unwrap()
+    }
+
+    /// Returns a frame as a client sends it. Only the last frame of a message has `fin`.
+    fn client_frame(opcode: u8, payload: &[u8], fin: bool) -> Vec<u8> {
+        let mut frame = vec![];
+        websocket::write_frame(&mut frame, opcode, payload, Some([1, 2, 3, 4]));
+        if !fin {
+            frame[0] &= 0x7f;
+        }
+        frame
+    }
+
+    #[test]
+    fn websocket_servers_put_messages_together_and_answer_pings() {
+        let (mut connection, mut stream) = websocket_pair("/tunnel");
+        let request =
+            websocket::upgrade_request("127.0.0.1", "/tunnel", "dGhlIHNhbXBsZSBub25jZQ==");
+        // Frames right behind the request must wait for it to be answered
+        let frames = [
+            client_frame(websocket::OPCODE_BINARY, b"fr", false),
+            client_frame(websocket::OPCODE_PING, b"p", true),
+            client_frame(websocket::OPCODE_CONTINUATION, b"ag", true),
+        ];
+        stream
+            .write_all(&[request.as_bytes(), &frames.concat()].concat())
+            .unwrap();
+        assert_eq!(recv(&mut connection).unwrap(), b"frag");
+        assert!(read_head(&mut stream).starts_with("HTTP/1.1 101 "));
+        let mut pong = [0; 3];
+        stream.read_exact(&mut pong).unwrap();
+        assert_eq!(pong, [0x80 | websocket::OPCODE_PONG, 1, b'p']);
+        // Servers don't mask what they send
+        connection.send(b"reply").unwrap();
+        let mut reply = [0; 7];
+        stream.read_exact(&mut reply).unwrap();
+        assert_eq!(reply, *b"\x82\x05reply");
+    }
+
+    #[test]
+    fn websocket_servers_refuse_other_paths_and_unmasked_frames() {
+        let (mut connection, mut stream) = websocket_pair("/tunnel");
+        let request = websocket::upgrade_request("127.0.0.1", "/other", "dGhlIHNhbXBsZSBub25jZQ==");
+        stream.write_all(request.as_bytes()).unwrap();
+        assert_eq!(
+            recv(&mut connection).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        assert!(read_head(&mut stream).starts_with("HTTP/1.1 404 "));
+
+        let (mut connection, mut stream) = websocket_pair("/tunnel");
+        let request =
+            websocket::upgrade_request("127.0.0.1", "/tunnel", "dGhlIHNhbXBsZSBub25jZQ==");
+        let mut unmasked = vec![];
+        websocket::write_frame(&mut unmasked, websocket::OPCODE_BINARY, b"data", None);
+        stream
+            .write_all(&[request.as_bytes(), &unmasked].concat())
+            .unwrap();
+        assert_eq!(
+            recv(&mut connection).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn proxies_are_parsed_with_optional_credentials() {
+        let proxy: Proxy = "user:p@ss@192.0.2.1:3128".parse().unwrap();
+        assert_eq!(proxy.addr, "192.0.2.1:3128".parse().unwrap());
+        assert_eq!(proxy.credentials.as_deref(), Some("user:p@ss"));
+        let proxy: Proxy = "[2001:db8::1]:8080".parse().unwrap();
+        assert_eq!(proxy.credentials, None);
+        assert!("proxy.example:8080".parse::<Proxy>().is_err());
+    }
+}
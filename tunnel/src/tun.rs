@@ -1,5 +1,9 @@
-use libc::{c_short, close, ifreq, ioctl, open, read, write, IFNAMSIZ, O_RDWR};
+use libc::{
+    c_short, close, fcntl, ifreq, ioctl, open, read, write, F_GETFL, F_SETFL, IFNAMSIZ, O_NONBLOCK,
+    O_RDWR,
+};
 
+use crate::pool::PacketBuf;
 use crate::tunerror::Error;
 use std::ffi::CString;
 use std::io;
@@ -73,6 +77,16 @@ impl TunSocket {
         Ok(TunSocket { fd, name })
     }
 
+    /// Makes reads fail with [`io::ErrorKind::WouldBlock`] instead of waiting when no packet is
+    /// waiting, so the device can be drained in a loop.
+    pub fn set_nonblocking(&self) -> Result<(), Error> {
+        let flags = unsafe { fcntl(self.fd, F_GETFL) };
+        if flags < 0 || unsafe { fcntl(self.fd, F_SETFL, flags | O_NONBLOCK) } < 0 {
+            return Err(Error::FCntl(io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
     pub fn read(&self, dst: &mut [u8]) -> Result<usize, Error> {
         match unsafe { read(self.fd, dst.as_mut_ptr() as _, dst.len()) } {
             -1 => Err(Error::IfaceRead(io::Error::last_os_error())),
@@ -80,6 +94,13 @@ impl TunSocket {
         }
     }
 
+    /// Reads a packet into the room after the buffer's headroom, where it can be sealed in place.
+    pub fn read_buf(&self, buf: &mut PacketBuf) -> Result<(), Error> {
+        let len = self.read(buf.room_mut())?;
+        buf.set_len(len);
+        Ok(())
+    }
+
     pub fn write(&self, buf: &[u8]) -> usize {
         match unsafe { write(self.fd, buf.as_ptr() as _, buf.len() as _) } {
             -1 => {
@@ -1,4 +1,5 @@
 use std::io;
+use std::net::IpAddr;
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -36,4 +37,24 @@ pub enum Error {
     ApiSocket(io::Error),
     #[error("{0}")]
     Message(String),
+    #[error("replayed packet with counter {0}")]
+    Replay(u64),
+    #[error("handshake: {0}")]
+    Handshake(String),
+    #[error("handshake: no cipher suite both peers allow")]
+    NoCommonCipherSuite,
+    #[error("packet for an unknown session")]
+    UnknownPeer,
+    #[error("packet failed authentication")]
+    AuthenticationFailed,
+    #[error("packet of {0} bytes is too short")]
+    ShortPacket(usize),
+    #[error("invalid packet")]
+    InvalidPacket,
+    #[error("source address {0} isn't allowed for the peer that sent it")]
+    SourceNotAllowed(IpAddr),
+    #[error("too many peers")]
+    TooManyPeers,
+    #[error("connection closed: {0}")]
+    ConnectionClosed(io::Error),
 }
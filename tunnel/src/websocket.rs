@@ -0,0 +1,360 @@
+use std::io;
+
+use ring::digest::{self, SHA1_FOR_LEGACY_USE_ONLY};
+
+/// Appended to a client's key to compute the server's answer to its upgrade request.
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+pub const OPCODE_CONTINUATION: u8 = 0x0;
+pub const OPCODE_BINARY: u8 = 0x2;
+pub const OPCODE_CLOSE: u8 = 0x8;
+pub const OPCODE_PING: u8 = 0x9;
+pub const OPCODE_PONG: u8 = 0xa;
+/// Size of the key a client masks its frames with.
+pub const MASK_LEN: usize = 4;
+/// Size of the longest frame header: 2 bytes, a 64 bit length and the mask.
+pub const MAX_HEADER_LEN: usize = 2 + 8 + MASK_LEN;
+/// Control frames carry at most this many bytes.
+const MAX_CONTROL_LEN: u64 = 125;
+/// Size of the random key of an upgrade request.
+pub const KEY_LEN: usize = 16;
+
+/// The parsed header of a WebSocket frame.
+#[derive(Debug, PartialEq, Eq)]
+pub struct FrameHeader {
+    /// Whether this is the last frame of its message.
+    pub fin: bool,
+    pub opcode: u8,
+    pub mask: Option<[u8; MASK_LEN]>,
+    pub payload_len: u64,
+}
+
+/// Returns the length of a frame header from its first 2 bytes.
+pub fn header_len(start: &[u8]) -> usize {
+    let length_len = match start[1] & 0x7f {
+        126 => 2,
+        127 => 8,
+        _ => 0,
+    };
+    let mask_len = if start[1] & 0x80 != 0 { MASK_LEN } else { 0 };
+    2 + length_len + mask_len
+}
+
+/// Parses a whole frame header, as long as [`header_len`] says. Fails on reserved bits, unknown
+/// opcodes and control frames that are fragmented or too long.
+pub fn parse_header(header: &[u8]) -> io::Result<FrameHeader> {
+    let invalid = |reason: &str| io::Error::new(io::ErrorKind::InvalidData, reason.to_owned());
+    if header[0] & 0x70 != 0 {
+        return Err(invalid("reserved WebSocket bits are set"));
+    }
+    let fin = header[0] & 0x80 != 0;
+    let opcode = header[0] & 0x0f;
+    let (payload_len, rest) = match header[1] & 0x7f {
+        126 => (
+            u16::from_be_bytes(header[2..4].try_into().unwrap()) as u64,
+            &header[4..],
+        ),
+        127 => (
+            u64::from_be_bytes(header[2..10].try_into().unwrap()),
+            &header[10..],
+        ),
+        len => (len as u64, &header[2..]),
+    };
+    let mask = (header[1] & 0x80 != 0).then(|| rest[..MASK_LEN].try_into().unwrap());
+    match opcode {
+        OPCODE_CONTINUATION | OPCODE_BINARY => {}
+        OPCODE_CLOSE | OPCODE_PING | OPCODE_PONG => {
+            if !fin || payload_len > MAX_CONTROL_LEN {
+                return Err(invalid("invalid WebSocket control frame"));
+            }
+        }
+        _ => return Err(invalid("unexpected WebSocket opcode")),
+    }
+    Ok(FrameHeader {
+        fin,
+        opcode,
+        mask,
+        payload_len,
+    })
+}
+
+/// Appends a single frame holding `payload` to `out`. Clients must give a fresh random mask.
+pub fn write_frame(out: &mut Vec<u8>, opcode: u8, payload: &[u8], mask: Option<[u8; MASK_LEN]>) {
+    out.push(0x80 | opcode);
+    let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+    match payload.len() {
+        len @ 0..=125 => out.push(mask_bit | len as u8),
+        len @ 126..=0xffff => {
+            out.push(mask_bit | 126);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            out.push(mask_bit | 127);
+            out.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    if let Some(mask) = mask {
+        out.extend_from_slice(&mask);
+    }
+    let start = out.len();
+    out.extend_from_slice(payload);
+    if let Some(mask) = mask {
+        apply_mask(&mut out[start..], mask);
+    }
+}
+
+/// Masks or unmasks a payload, which is the same operation.
+pub fn apply_mask(payload: &mut [u8], mask: [u8; MASK_LEN]) {
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % MASK_LEN];
+    }
+}
+
+/// Returns the request a client upgrades its connection to the server with. `host` is the
+/// server's address and `key` the base64 of [`KEY_LEN`] random bytes.
+pub fn upgrade_request(host: &str, path: &str, key: &str) -> String {
+    format!(
+        "GET {path} HTTP/1.1\r\nHost: {host}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Key: {key}\r\nSec-WebSocket-Version: 13\r\n\r\n"
+    )
+}
+
+/// Checks a client's upgrade request for `path` and returns the response accepting it.
+pub fn accept_upgrade(head: &str, path: &str) -> Result<String, HttpError> {
+    let (request_line, headers) = parse_head(head);
+    let parts: Vec<&str> = request_line.split(' ').collect();
+    let ["GET", target, "HTTP/1.1"] = parts[..] else {
+        return Err(HttpError::BadRequest);
+    };
+    // Web servers in front of us may pass the query string on
+    if target.split('?').next() != Some(path) {
+        return Err(HttpError::NotFound);
+    }
+    let has_token = |name: &str, token: &str| {
+        header(&headers, name).is_some_and(|value| {
+            value
+                .split(',')
+                .any(|part| part.trim().eq_ignore_ascii_case(token))
+        })
+    };
+    if !has_token("Upgrade", "websocket")
+        || !has_token("Connection", "upgrade")
+        || header(&headers, "Sec-WebSocket-Version") != Some("13")
+    {
+        return Err(HttpError::BadRequest);
+    }
+    let Some(key) = header(&headers, "Sec-WebSocket-Key") else {
+        return Err(HttpError::BadRequest);
+    };
+    Ok(format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(key)
+    ))
+}
+
+/// Checks the server's response to our upgrade request with `key`.
+pub fn check_upgrade_response(head: &str, key: &str) -> io::Result<()> {
+    let (status_line, headers) = parse_head(head);
+    if status(status_line) != Some(101) {
+        return Err(refused(format!("WebSocket upgrade refused: {status_line}")));
+    }
+    if header(&headers, "Sec-WebSocket-Accept") != Some(accept_key(key).as_str()) {
+        return Err(refused(
+            "WebSocket upgrade accepted for another key".to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+/// Returns the request asking an HTTP proxy to open a tunnel to `target`. `credentials` are
+/// `user:password` for Basic authentication.
+pub fn connect_request(target: &str, credentials: Option<&str>) -> String {
+    let authorization = credentials
+        .map(|credentials| {
+            format!(
+                "Proxy-Authorization: Basic {}\r\n",
+                base64(credentials.as_bytes())
+            )
+        })
+        .unwrap_or_default();
+    format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n{authorization}\r\n")
+}
+
+/// Checks that the proxy opened the tunnel.
+pub fn check_connect_response(head: &str) -> io::Result<()> {
+    let (status_line, _) = parse_head(head);
+    match status(status_line) {
+        Some(200..=299) => Ok(()),
+        _ => Err(refused(format!("proxy refused to connect: {status_line}"))),
+    }
+}
+
+/// Why a server refuses an upgrade request, which it answers with the matching status.
+#[derive(Debug, PartialEq, Eq)]
+pub enum HttpError {
+    BadRequest,
+    NotFound,
+}
+
+impl HttpError {
+    /// Returns the status code and reason of the response.
+    pub fn status(&self) -> &'static str {
+        match self {
+            HttpError::BadRequest => "400 Bad Request",
+            HttpError::NotFound => "404 Not Found",
+        }
+    }
+
+    pub fn response(&self) -> String {
+        format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", self.status())
+    }
+}
+
+fn refused(reason: String) -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionRefused, reason)
+}
+
+/// Splits an HTTP head into its first line and its headers.
+fn parse_head(head: &str) -> (&str, Vec<(&str, &str)>) {
+    let mut lines = head.split("\r\n");
+    let first_line = lines.next().unwrap_or_default();
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim(), value.trim()))
+        .collect();
+    (first_line, headers)
+}
+
+fn header<'a>(headers: &[(&str, &'a str)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(header, _)| header.eq_ignore_ascii_case(name))
+        .map(|(_, value)| *value)
+}
+
+/// Returns the status code of a response's status line.
+fn status(status_line: &str) -> Option<u16> {
+    let (version, rest) = status_line.split_once(' ')?;
+    if !version.starts_with("HTTP/1.") {
+        return None;
+    }
+    rest.split(' ').next()?.parse().ok()
+}
+
+/// Returns the key a server answers an upgrade request with `key` with.
+fn accept_key(key: &str) -> String {
+    let mut context = digest::Context::new(&SHA1_FOR_LEGACY_USE_ONLY);
+    context.update(key.as_bytes());
+    context.update(ACCEPT_GUID.as_bytes());
+    base64(context.finish().as_ref())
+}
+
+/// Encodes bytes in standard base64, with padding.
+pub fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, byte)| {
+            bits | (*byte as u32) << (16 - 8 * i)
+        });
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64_matches_rfc_4648() {
+        let vectors = [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ];
+        for (input, output) in vectors {
+            assert_eq!(base64(input.as_bytes()), output);
+        }
+    }
+
+    #[test]
+    fn accept_key_matches_rfc_6455() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn upgrades_are_accepted_on_their_path_only() {
+        let key = "dGhlIHNhbXBsZSBub25jZQ==";
+        let request = upgrade_request("192.0.2.1:443", "/tunnel", key);
+        let response = accept_upgrade(&request, "/tunnel").unwrap();
+        check_upgrade_response(&response, key).unwrap();
+        assert!(check_upgrade_response(&response, "AAAAAAAAAAAAAAAAAAAAAA==").is_err());
+        assert_eq!(accept_upgrade(&request, "/other"), Err(HttpError::NotFound));
+        let plain = "GET /tunnel HTTP/1.1\r\nHost: 192.0.2.1\r\n\r\n";
+        assert_eq!(accept_upgrade(plain, "/tunnel"), Err(HttpError::BadRequest));
+    }
+
+    #[test]
+    fn proxies_must_answer_with_success() {
+        let request = connect_request("192.0.2.1:443", Some("user:secret"));
+        assert!(request.starts_with("CONNECT 192.0.2.1:443 HTTP/1.1\r\n"));
+        assert!(request.contains("Proxy-Authorization: Basic dXNlcjpzZWNyZXQ=\r\n"));
+        check_connect_response("HTTP/1.1 200 Connection established\r\n\r\n").unwrap();
+        let refused = check_connect_response("HTTP/1.1 407 Proxy Authentication Required\r\n");
+        assert_eq!(
+            refused.unwrap_err().kind(),
+            io::ErrorKind::ConnectionRefused
+        );
+    }
+
+    #[test]
+    fn frames_survive_a_round_trip() {
+        for (len, mask) in [(5, None), (200, Some([1, 2, 3, 4])), (70000, Some([9; 4]))] {
+            let payload: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let mut frame = vec![];
+            write_frame(&mut frame, OPCODE_BINARY, &payload, mask);
+            let header_len = header_len(&frame[..2]);
+            let header = parse_header(&frame[..header_len]).unwrap();
+            assert_eq!(
+                header,
+                FrameHeader {
+                    fin: true,
+                    opcode: OPCODE_BINARY,
+                    mask,
+                    payload_len: len as u64,
+                }
+            );
+            let body = &mut frame[header_len..];
+            if let Some(mask) = mask {
+                assert_ne!(body, &payload[..]);
+                apply_mask(body, mask);
+            }
+            assert_eq!(body, &payload[..]);
+        }
+    }
+
+    #[test]
+    fn invalid_frames_are_refused() {
+        // Text frames, fragmented pings and reserved bits
+        for header in [[0x81, 0], [0x09, 0], [0xc2, 0]] {
+            assert!(parse_header(&header).is_err());
+        }
+        let mut long_ping = vec![];
+        write_frame(&mut long_ping, OPCODE_PING, &[0; 126], None);
+        assert!(parse_header(&long_ping[..header_len(&long_ping)]).is_err());
+    }
+}